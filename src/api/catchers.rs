@@ -0,0 +1,80 @@
+use rocket::{http::Header, serde::json::Json, Request};
+use serde::Serialize;
+
+use super::rate_limit::RetryAfter;
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub status: String,
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+pub struct Unauthorized {
+    body: Json<ErrorResponse>,
+    challenge: Header<'static>,
+}
+
+/// Ask for a bearer token, telling clients that sent one that it was rejected
+#[catch(401)]
+pub fn unauthorized(req: &Request<'_>) -> Unauthorized {
+    let sent_credential =
+        req.headers().contains("Authorization") || req.headers().contains("Authentication");
+    let challenge = if sent_credential {
+        r#"Bearer realm="vult", error="invalid_token""#
+    } else {
+        r#"Bearer realm="vult""#
+    };
+    Unauthorized {
+        body: Json(ErrorResponse {
+            status: "unauthorized".into(),
+        }),
+        challenge: Header::new("WWW-Authenticate", challenge),
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 403)]
+pub struct Forbidden {
+    body: Json<ErrorResponse>,
+    challenge: Header<'static>,
+}
+
+/// Tell clients that their credential does not allow the request
+#[catch(403)]
+pub fn forbidden() -> Forbidden {
+    Forbidden {
+        body: Json(ErrorResponse {
+            status: "forbidden".into(),
+        }),
+        challenge: Header::new(
+            "WWW-Authenticate",
+            r#"Bearer realm="vult", error="insufficient_scope""#,
+        ),
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    body: Json<ErrorResponse>,
+    retry_after: Header<'static>,
+}
+
+impl TooManyRequests {
+    pub fn new(retry_after: u64) -> Self {
+        TooManyRequests {
+            body: Json(ErrorResponse {
+                status: "rate_limited".into(),
+            }),
+            retry_after: Header::new("Retry-After", retry_after.to_string()),
+        }
+    }
+}
+
+/// Tell locked out clients when to try again
+#[catch(429)]
+pub fn too_many_requests(req: &Request<'_>) -> TooManyRequests {
+    let RetryAfter(seconds) = req.local_cache(|| RetryAfter(1));
+    TooManyRequests::new(*seconds)
+}
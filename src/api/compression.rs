@@ -0,0 +1,310 @@
+use std::io::{self, Cursor, Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Request, Response,
+};
+
+use crate::config::parse_config::CompressionConfig;
+
+/// Content codings supported for request and response bodies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Encoding of a request body, from its `Content-Encoding`
+    ///
+    /// Returns `Err` with the coding if it is not supported
+    pub fn of_request(req: &Request<'_>) -> Result<Option<Self>, String> {
+        match req.headers().get_one("Content-Encoding").map(str::trim) {
+            None | Some("") | Some("identity") => Ok(None),
+            Some(name) => Self::from_name(name)
+                .map(Some)
+                .ok_or_else(|| name.to_string()),
+        }
+    }
+
+    /// Preferred encoding of a response, from the request's `Accept-Encoding`
+    ///
+    /// zstd is preferred over gzip when the client weights them equally. `*` accepts gzip
+    /// unless gzip is listed itself.
+    pub fn accepted_by(req: &Request<'_>) -> Option<Self> {
+        let codings: Vec<(&str, f32)> = req
+            .headers()
+            .get("Accept-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(|coding| {
+                let mut parts = coding.split(';');
+                let name = parts.next().unwrap_or_default().trim();
+                let weight = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (name, weight)
+            })
+            .collect();
+        let weight = |encoding: Self| {
+            codings
+                .iter()
+                .find(|(name, _)| Self::from_name(name) == Some(encoding))
+                .or_else(|| {
+                    codings
+                        .iter()
+                        .find(|(name, _)| *name == "*" && encoding == Encoding::Gzip)
+                })
+                .map_or(0.0, |(_, weight)| *weight)
+        };
+        let (zstd, gzip) = (weight(Encoding::Zstd), weight(Encoding::Gzip));
+        if zstd > 0.0 && zstd >= gzip {
+            Some(Encoding::Zstd)
+        } else if gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(bytes, 0),
+        }
+    }
+
+    /// Decompress `bytes`, stopping once the result is larger than `limit` bytes
+    pub fn decompress(&self, bytes: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Encoding::Gzip => Box::new(GzDecoder::new(bytes)),
+            Encoding::Zstd => Box::new(zstd::Decoder::new(bytes)?),
+        };
+        let mut decompressed = Vec::new();
+        decoder.take(limit + 1).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// Compresses response bodies larger than the configured threshold
+///
+/// Streamed responses are left untouched since their size is not known up front.
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            min_size: config.min_size,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.headers().contains("Content-Encoding") {
+            return;
+        }
+        // Whether a response of this route is compressed depends on its size, so caches
+        // are told about every response that could have been
+        let size = match res.body().preset_size() {
+            Some(size) => size,
+            None => return,
+        };
+        res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        if size < self.min_size {
+            return;
+        }
+        let encoding = match Encoding::accepted_by(req) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to read response body for compression: {:?}", e);
+                return;
+            }
+        };
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                res.set_header(Header::new("Content-Encoding", encoding.name()));
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Err(e) => {
+                error!("Failed to compress response: {:?}", e);
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::{endpoints::export::ExportResponse, server::build_server},
+        config::parse_config::{CompressionConfig, Config, User},
+        util::key::hash_key,
+    };
+
+    use super::Encoding;
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("unit").unwrap()],
+            }],
+            compression: CompressionConfig {
+                enabled: true,
+                min_size: 256,
+            },
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn auth_header() -> Header<'static> {
+        Header::new("Authentication", "unit")
+    }
+
+    fn credentials(count: usize) -> Value {
+        (0..count)
+            .map(|i| json!({"id": format!("id{i}"), "value": "nothing"}))
+            .collect()
+    }
+
+    #[test]
+    fn compressed_response() {
+        let config = init_test_config("test/compression/response");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(credentials(50).to_string())
+            .dispatch();
+
+        for (accept, encoding) in [
+            ("gzip", Encoding::Gzip),
+            ("zstd", Encoding::Zstd),
+            ("gzip, zstd", Encoding::Zstd),
+            ("gzip;q=1.0, zstd;q=0.5", Encoding::Gzip),
+            ("*", Encoding::Gzip),
+            ("zstd;q=0.5, *", Encoding::Gzip),
+        ] {
+            let response = client
+                .get("/export")
+                .header(auth_header())
+                .header(Header::new("Accept-Encoding", accept))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(
+                response.headers().get_one("Content-Encoding"),
+                Some(encoding.name())
+            );
+            let body = response.into_bytes().unwrap();
+            let body = encoding.decompress(&body, 1 << 20).unwrap();
+            let body: ExportResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.credentials.unwrap().len(), 50);
+        }
+    }
+
+    #[test]
+    fn small_response_uncompressed() {
+        let config = init_test_config("test/compression/small_response");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .get("/export")
+            .header(auth_header())
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Content-Encoding").is_none());
+        assert!(response
+            .headers()
+            .get("Vary")
+            .any(|vary| vary == "Accept-Encoding"));
+    }
+
+    #[test]
+    fn compressed_request() {
+        let config = init_test_config("test/compression/request");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let body = Encoding::Zstd
+            .compress(credentials(10).to_string().as_bytes())
+            .unwrap();
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .header(Header::new("Content-Encoding", "zstd"))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let body = Encoding::Gzip
+            .compress(
+                json!({"state_id": "missing", "mutations": []})
+                    .to_string()
+                    .as_bytes(),
+            )
+            .unwrap();
+        let response = client
+            .post("/sync")
+            .header(auth_header())
+            .header(Header::new("Content-Encoding", "gzip"))
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = response.into_json().unwrap();
+        assert_eq!(body["store"].as_array().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn unsupported_request_encoding() {
+        let config = init_test_config("test/compression/unsupported");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .header(Header::new("Content-Encoding", "br"))
+            .body("[]")
+            .dispatch();
+        assert_eq!(response.status(), Status::UnsupportedMediaType);
+    }
+}
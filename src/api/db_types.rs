@@ -1,19 +1,26 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+/// Mutation as stored in the cache, with values always kept as strings
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename = "Mutation")]
-#[repr(C)]
 pub enum DbMutation {
-    Add { credential: Credential },
-    Delete { id: String },
-    Modify { credential: Credential },
+    Add {
+        #[serde(with = "StoredCredential")]
+        credential: Credential,
+    },
+    Delete {
+        id: String,
+    },
+    Modify {
+        #[serde(with = "StoredCredential")]
+        credential: Credential,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
-#[repr(C)]
 pub enum Mutation {
     #[serde(rename = "add")]
     Add { credential: Credential },
@@ -23,15 +30,46 @@ pub enum Mutation {
     Modify { credential: Credential },
 }
 
+impl Mutation {
+    pub fn credential(&self) -> &Credential {
+        match self {
+            Mutation::Add { credential } => credential,
+            Mutation::Delete { credential } => credential,
+            Mutation::Modify { credential } => credential,
+        }
+    }
+
+    pub fn credential_mut(&mut self) -> &mut Credential {
+        match self {
+            Mutation::Add { credential } => credential,
+            Mutation::Delete { credential } => credential,
+            Mutation::Modify { credential } => credential,
+        }
+    }
+}
+
 impl From<DbMutation> for Mutation {
     fn from(m: DbMutation) -> Self {
-        unsafe { std::mem::transmute::<DbMutation, Mutation>(m) }
+        match m {
+            DbMutation::Add { credential } => Mutation::Add { credential },
+            DbMutation::Delete { id } => Mutation::Delete {
+                credential: Credential {
+                    id,
+                    value: String::new(),
+                },
+            },
+            DbMutation::Modify { credential } => Mutation::Modify { credential },
+        }
     }
 }
 
 impl From<Mutation> for DbMutation {
     fn from(m: Mutation) -> Self {
-        unsafe { std::mem::transmute::<Mutation, DbMutation>(m) }
+        match m {
+            Mutation::Add { credential } => DbMutation::Add { credential },
+            Mutation::Delete { credential } => DbMutation::Delete { id: credential.id },
+            Mutation::Modify { credential } => DbMutation::Modify { credential },
+        }
     }
 }
 
@@ -45,12 +83,95 @@ impl Display for Mutation {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Credential {
     pub id: String,
+    /// Encrypted value, which CBOR carries as bytes if it is base64 and the client asked
+    /// for bytes
+    #[serde(with = "credential_value")]
     pub value: String,
 }
 
+/// [`Credential`] as bincode stores it in the cache, with values as plain strings since
+/// bincode cannot read a value that may be a string or bytes
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Credential")]
+struct StoredCredential {
+    id: String,
+    value: String,
+}
+
+/// Values in canonical base64 are sent as byte strings, a quarter smaller than the text,
+/// to clients that asked for them. Byte strings are accepted from any binary format.
+mod credential_value {
+    use std::fmt;
+
+    use serde::de::{self, Visitor};
+
+    use crate::api::wire::value_bytes;
+
+    use super::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() && value_bytes() {
+            if let Ok(bytes) = base64::decode(value) {
+                if base64::encode(&bytes) == value {
+                    return serializer.serialize_bytes(&bytes);
+                }
+            }
+        }
+        serializer.serialize_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        if deserializer.is_human_readable() {
+            return String::deserialize(deserializer);
+        }
+        deserializer.deserialize_any(ValueVisitor)
+    }
+
+    struct ValueVisitor;
+
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+            Ok(value.to_string())
+        }
+
+        fn visit_string<E: de::Error>(self, value: String) -> Result<String, E> {
+            Ok(value)
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<String, E> {
+            Ok(base64::encode(value))
+        }
+    }
+}
+
+/// Entire store that replaces the one of a vault
+#[derive(Debug, Clone, Copy)]
+pub enum NewStore<'a> {
+    /// Credentials sent with the request
+    Credentials(&'a [Credential]),
+    /// Credentials received by the chunked upload with this id
+    Upload(&'a str),
+}
+
+impl<'a> NewStore<'a> {
+    /// Store of a request, from the upload `upload_id` if given and `store` otherwise
+    pub fn of_request(store: &'a [Credential], upload_id: Option<&'a str>) -> Self {
+        match upload_id {
+            Some(upload_id) => NewStore::Upload(upload_id),
+            None => NewStore::Credentials(store),
+        }
+    }
+}
+
 impl Display for Credential {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -60,3 +181,211 @@ impl Display for Credential {
         )
     }
 }
+
+/// User that API keys can be issued to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Account {
+    pub alias: String,
+    /// Creation time in seconds since the Unix epoch
+    pub created: u64,
+    /// Number of API keys of the user
+    pub keys: u32,
+    /// Time of the most recent sync of any of the user's devices
+    pub last_sync: Option<u64>,
+}
+
+/// Size of a user's vault
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct VaultStats {
+    /// Number of credentials in the store
+    pub credentials: u64,
+    /// Number of states kept in the cache
+    pub states: u64,
+    /// Size of the vault's database file in bytes
+    pub size: u64,
+}
+
+/// Permission granted to an API key
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Scope {
+    /// Read the vault and pull remote mutations
+    #[serde(rename = "sync:read")]
+    SyncRead,
+    /// Push mutations and initialize or upload the vault
+    #[serde(rename = "sync:write")]
+    SyncWrite,
+    /// Manage the account, such as its devices and master password
+    #[serde(rename = "account:admin")]
+    AccountAdmin,
+}
+
+impl Scope {
+    /// Scopes of keys issued without explicit scopes
+    pub const ALL: [Scope; 3] = [Scope::SyncRead, Scope::SyncWrite, Scope::AccountAdmin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SyncRead => "sync:read",
+            Scope::SyncWrite => "sync:write",
+            Scope::AccountAdmin => "account:admin",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = crate::util::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| Self::Err::InvalidScope(s.to_string()))
+    }
+}
+
+/// API key of a user, without its hash
+///
+/// Each key is meant for a single device, so keys double as the device registry.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub alias: String,
+    pub name: String,
+    /// Creation time in seconds since the Unix epoch
+    pub created: u64,
+    /// Time of the last sync with this key in seconds since the Unix epoch
+    pub last_sync: Option<u64>,
+    /// State id returned by the last sync with this key
+    pub last_state: Option<String>,
+    pub scopes: Vec<Scope>,
+    /// Time after which the key is no longer accepted, in seconds since the Unix epoch
+    pub expires: Option<u64>,
+    /// Time the device passed a TOTP check, in seconds since the Unix epoch
+    pub totp_verified: Option<u64>,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/// TOTP setup of a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotpState {
+    /// Encrypted secret
+    pub secret: String,
+    /// Whether the setup was confirmed with a code and is enforced
+    pub enabled: bool,
+    /// Time step of the last code used
+    pub last_step: Option<u64>,
+}
+
+/// Recent failed authentication attempts of a client or user
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthFailures {
+    pub failures: u32,
+    /// Time of the last failure in seconds since the Unix epoch
+    pub last_failure: u64,
+    /// End of the lockout in seconds since the Unix epoch
+    pub locked_until: u64,
+}
+
+/// Kind of event recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Init,
+    Upload,
+    /// Fetch of the vault's salt from `/user/import`
+    Import,
+    /// Fetch of the salt with a challenge for proving the master password
+    SaltFetch,
+    Sync,
+    Rekey,
+    /// Replacement of the store, keeping the master password
+    ResetVault,
+    Reset,
+    RevokeDevice,
+    RotateKey,
+    /// Request to delete the account after the grace period
+    ScheduleDeletion,
+    CancelDeletion,
+    DeleteAccount,
+    /// Request with an unknown, revoked or expired credential
+    AuthFailure,
+    ProofFailure,
+    TotpFailure,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Init => "init",
+            AuditAction::Upload => "upload",
+            AuditAction::Import => "import",
+            AuditAction::SaltFetch => "salt_fetch",
+            AuditAction::Sync => "sync",
+            AuditAction::Rekey => "rekey",
+            AuditAction::ResetVault => "reset_vault",
+            AuditAction::Reset => "reset",
+            AuditAction::RevokeDevice => "revoke_device",
+            AuditAction::RotateKey => "rotate_key",
+            AuditAction::ScheduleDeletion => "schedule_deletion",
+            AuditAction::CancelDeletion => "cancel_deletion",
+            AuditAction::DeleteAccount => "delete_account",
+            AuditAction::AuthFailure => "auth_failure",
+            AuditAction::ProofFailure => "proof_failure",
+            AuditAction::TotpFailure => "totp_failure",
+        }
+    }
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Event to append to the audit log
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub alias: Option<String>,
+    /// Key id of the device making the request
+    pub device: Option<String>,
+    /// Path of the request, empty for events the server triggers itself
+    pub route: String,
+    /// Number of mutations or credentials the request carried
+    pub mutations: Option<u64>,
+    /// State id of the vault after the request
+    pub state_id: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Event of the audit log, chained to the previous one by its hash
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEvent {
+    /// Position in the log, without gaps
+    pub seq: u64,
+    /// Time in seconds since the Unix epoch
+    pub time: u64,
+    pub action: String,
+    pub alias: Option<String>,
+    pub device: Option<String>,
+    pub route: String,
+    pub mutations: Option<u64>,
+    pub state_id: Option<String>,
+    pub ip: Option<String>,
+    /// Hex encoded SHA-256 of the previous event's hash and this event
+    pub hash: String,
+}
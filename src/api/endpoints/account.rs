@@ -0,0 +1,349 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        catchers::TooManyRequests,
+        db_types::{AuditAction, AuditEntry},
+        endpoints::session::{check_proof, ProofCheck},
+        guards::{audit::Audit, user::AccountAdmin},
+    },
+    config::parse_config::Config,
+    database::traits::{Databases, UserDatabase},
+    util::{error::Error, proof::Challenges, session::now_secs},
+};
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct DeleteAccountRequest {
+    /// Challenge from `/auth/challenge`, not needed if the user has no master password
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub proof: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct DeleteAccountResponse {
+    pub status: String,
+    /// Time the account is deleted at in seconds since the Unix epoch, if it is not
+    /// deleted at once
+    pub due: Option<u64>,
+}
+
+/// Delete the account with its vault and devices, proving the master password
+///
+/// With `auth.deletion_grace` set, the deletion is scheduled with status `scheduled`
+/// and can be cancelled until it is due.
+#[post("/user/delete", data = "<data>")]
+pub fn delete_account(
+    admin: AccountAdmin,
+    audit: Audit,
+    config: &State<Config>,
+    db: &State<Databases>,
+    challenges: &State<Challenges>,
+    data: Json<DeleteAccountRequest>,
+) -> Result<status::Custom<Json<DeleteAccountResponse>>, TooManyRequests> {
+    let AccountAdmin(key) = admin;
+    let alias = &key.alias;
+    let response = |status: Status, message: &str, due: Option<u64>| {
+        Ok(status::Custom(
+            status,
+            Json(DeleteAccountResponse {
+                status: message.into(),
+                due,
+            }),
+        ))
+    };
+    match db.user.get_user(alias) {
+        Ok(_) => {
+            let (Some(challenge), Some(proof)) = (&data.challenge, &data.proof) else {
+                warn!(
+                    "Device {} tried to delete user {} without a proof",
+                    &key.id, alias
+                );
+                return response(Status::Forbidden, "invalid", None);
+            };
+            match check_proof(&key, &audit, config, db, challenges, challenge, proof) {
+                ProofCheck::Valid => {}
+                ProofCheck::Invalid => return response(Status::Forbidden, "invalid", None),
+                ProofCheck::LockedOut(retry_after) => {
+                    return Err(TooManyRequests::new(retry_after))
+                }
+                ProofCheck::Failed => return response(Status::InternalServerError, "failed", None),
+            }
+        }
+        // Without a master password there is nothing to prove
+        Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::UninitializedUser(_))) => {}
+        Err(e) => {
+            error!("Failed to look up user {}: {:?}", alias, e);
+            return response(Status::InternalServerError, "failed", None);
+        }
+    }
+
+    if config.auth.deletion_grace == 0 {
+        return match db.user.delete_account(alias) {
+            Ok(_) => {
+                info!("Deleted user {}", alias);
+                audit.record(audit.entry(AuditAction::DeleteAccount, &key));
+                response(Status::Ok, "success", None)
+            }
+            Err(e) => {
+                error!("Failed to delete user {}: {:?}", alias, e);
+                response(Status::InternalServerError, "failed", None)
+            }
+        };
+    }
+    let due = now_secs() + config.auth.deletion_grace;
+    match db.user.schedule_deletion(alias, due) {
+        Ok(_) => {
+            info!("Scheduled deletion of user {} at {}", alias, due);
+            audit.record(audit.entry(AuditAction::ScheduleDeletion, &key));
+            response(Status::Accepted, "scheduled", Some(due))
+        }
+        Err(Error::ExistingDeletion(_)) => {
+            let due = db.user.pending_deletion(alias).ok().flatten();
+            response(Status::Conflict, "existing", due)
+        }
+        Err(e) => {
+            error!("Failed to schedule deletion of user {}: {:?}", alias, e);
+            response(Status::InternalServerError, "failed", None)
+        }
+    }
+}
+
+/// Keep the account whose deletion is scheduled
+#[post("/user/delete/cancel")]
+pub fn cancel_deletion(
+    admin: AccountAdmin,
+    audit: Audit,
+    db: &State<Databases>,
+) -> status::Custom<Json<DeleteAccountResponse>> {
+    let AccountAdmin(key) = admin;
+    let (status, message) = match db.user.cancel_deletion(&key.alias) {
+        Ok(_) => {
+            info!("Cancelled deletion of user {}", &key.alias);
+            audit.record(audit.entry(AuditAction::CancelDeletion, &key));
+            (Status::Ok, "success")
+        }
+        Err(Error::MissingDeletion(_)) => (Status::NotFound, "missing"),
+        Err(e) => {
+            error!("Failed to cancel deletion of user {}: {:?}", &key.alias, e);
+            (Status::InternalServerError, "failed")
+        }
+    };
+    status::Custom(
+        status,
+        Json(DeleteAccountResponse {
+            status: message.into(),
+            due: None,
+        }),
+    )
+}
+
+/// Delete the accounts whose grace period is over at `now`
+pub fn delete_due_accounts(db: &dyn UserDatabase, now: u64) {
+    let aliases = match db.due_deletions(now) {
+        Ok(aliases) => aliases,
+        Err(e) => {
+            error!("Failed to look up scheduled deletions: {:?}", e);
+            return;
+        }
+    };
+    for alias in aliases {
+        if let Err(e) = db.delete_account(&alias) {
+            error!("Failed to delete user {}: {:?}", &alias, e);
+            continue;
+        }
+        info!("Deleted user {} after its grace period", &alias);
+        let entry = AuditEntry {
+            action: AuditAction::DeleteAccount,
+            alias: Some(alias),
+            device: None,
+            route: String::new(),
+            mutations: None,
+            state_id: None,
+            ip: None,
+        };
+        if let Err(e) = db.append_audit(&entry) {
+            error!("Failed to record deletion in the audit log: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::{endpoints::session::ChallengeResponse, server::build_server},
+        config::parse_config::{Config, User},
+        database::{sqlite::SqliteDatabase, traits::UserDatabase},
+        util::{key::hash_key, proof::proof},
+    };
+
+    use super::{delete_due_accounts, DeleteAccountResponse};
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![
+                User {
+                    alias: "unit".into(),
+                    keys: vec![hash_key("unit").unwrap()],
+                },
+                User {
+                    alias: "other".into(),
+                    keys: vec![hash_key("other").unwrap()],
+                },
+            ],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn auth_header(key: &str) -> Header<'static> {
+        Header::new("Authentication", key.to_string())
+    }
+
+    /// Set a master password with the hash `hash` and upload a credential
+    fn init_user(client: &Client, key: &str) {
+        let response = client
+            .post("/user/init")
+            .header(auth_header(key))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/init/upload")
+            .header(auth_header(key))
+            .body(json!([{"id": "first", "value": "value"}]).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn proven_request(client: &Client, hash: &str) -> Value {
+        let response = client
+            .post("/auth/challenge")
+            .header(auth_header("unit"))
+            .dispatch();
+        let body: ChallengeResponse = response.into_json().unwrap();
+        let challenge = body.challenge.unwrap();
+        json!({"proof": proof(hash, &challenge), "challenge": challenge})
+    }
+
+    fn delete(client: &Client, body: Value) -> (Status, DeleteAccountResponse) {
+        let response = client
+            .post(uri!(super::delete_account))
+            .header(auth_header("unit"))
+            .body(body.to_string())
+            .dispatch();
+        (response.status(), response.into_json().unwrap())
+    }
+
+    fn devices_status(client: &Client, key: &str) -> Status {
+        client
+            .get("/devices")
+            .header(auth_header(key))
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    fn deleted() {
+        let dir = "test/account/deleted";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        init_user(&client, "unit");
+        init_user(&client, "other");
+        let (status, body) = delete(&client, proven_request(&client, "hash"));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body.status, "success");
+
+        assert_eq!(devices_status(&client, "unit"), Status::Unauthorized);
+        assert!(!Path::new(dir).join("unit.sqlite").exists());
+        let db = SqliteDatabase::new(dir);
+        assert!(db.get_user("unit").is_err());
+        assert_eq!(
+            db.recent_audit("unit", 1).unwrap()[0].action,
+            "delete_account"
+        );
+
+        assert_eq!(devices_status(&client, "other"), Status::Ok);
+        let response = client
+            .get("/export")
+            .header(auth_header("other"))
+            .dispatch();
+        let body: Value = response.into_json().unwrap();
+        assert_eq!(body["credentials"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wrong_proof() {
+        let config = init_test_config("test/account/wrong_proof");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        init_user(&client, "unit");
+        let (status, body) = delete(&client, proven_request(&client, "wrong hash"));
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(body.status, "invalid");
+        let (status, _) = delete(&client, json!({}));
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(devices_status(&client, "unit"), Status::Ok);
+    }
+
+    #[test]
+    fn uninitialized() {
+        let config = init_test_config("test/account/uninitialized");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let (status, _) = delete(&client, json!({}));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(devices_status(&client, "unit"), Status::Unauthorized);
+    }
+
+    #[test]
+    fn grace_period() {
+        let dir = "test/account/grace_period";
+        let mut config = init_test_config(dir);
+        config.auth.deletion_grace = 3600;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        init_user(&client, "unit");
+        let (status, body) = delete(&client, proven_request(&client, "hash"));
+        assert_eq!(status, Status::Accepted);
+        assert_eq!(body.status, "scheduled");
+        let due = body.due.unwrap();
+        let (status, body) = delete(&client, proven_request(&client, "hash"));
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(body.due, Some(due));
+
+        // Cancelled deletions are not carried out
+        let response = client
+            .post(uri!(super::cancel_deletion))
+            .header(auth_header("unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(uri!(super::cancel_deletion))
+            .header(auth_header("unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let db = SqliteDatabase::new(dir);
+        delete_due_accounts(&db, due);
+        assert_eq!(devices_status(&client, "unit"), Status::Ok);
+
+        let (status, body) = delete(&client, proven_request(&client, "hash"));
+        assert_eq!(status, Status::Accepted);
+        let due = body.due.unwrap();
+        delete_due_accounts(&db, due - 1);
+        assert_eq!(devices_status(&client, "unit"), Status::Ok);
+        delete_due_accounts(&db, due);
+        assert_eq!(devices_status(&client, "unit"), Status::Unauthorized);
+        assert!(db.pending_deletion("unit").unwrap().is_none());
+        let events = db.recent_audit("unit", 1).unwrap();
+        assert_eq!(events[0].action, "delete_account");
+        assert!(events[0].device.is_none());
+    }
+}
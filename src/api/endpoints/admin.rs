@@ -0,0 +1,1071 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        db_types::{Account, ApiKey, Scope, VaultStats},
+        guards::admin::Admin,
+    },
+    config::parse_config::Config,
+    database::{accounts::issue_key, traits::Databases},
+    util::{
+        error::Error, id::random_hex, session::now_secs, totp::TotpCipher, types::GenericResult,
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct UsersResponse {
+    pub status: String,
+    pub users: Option<Vec<UserOverview>>,
+}
+
+/// User with the size of its vault
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserOverview {
+    #[serde(flatten)]
+    pub account: Account,
+    pub vault: VaultStats,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct ResyncResponse {
+    pub status: String,
+    /// The only state left, none if the vault has no state yet
+    pub state_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct PruneResponse {
+    pub status: String,
+    /// Number of states removed from all caches
+    pub pruned: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct BackupResponse {
+    pub status: String,
+    /// Directory the backup was written to
+    pub directory: Option<String>,
+    pub files: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct KeysResponse {
+    pub status: String,
+    pub keys: Option<Vec<ApiKey>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct IssueKeyResponse {
+    pub status: String,
+    pub id: Option<String>,
+    /// The new key, only ever returned here
+    pub key: Option<String>,
+    /// Expiry of the key in seconds since the Unix epoch
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddUserRequest {
+    pub alias: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IssueKeyRequest {
+    #[serde(default)]
+    pub name: String,
+    /// Scopes granted to the key, all of them if omitted
+    pub scopes: Option<Vec<Scope>>,
+    /// Seconds until the key expires, never if omitted
+    pub expires_in: Option<u64>,
+}
+
+/// Status code and message for a failed account operation
+fn error_status(e: &Error) -> (Status, &'static str) {
+    match e {
+        Error::ExistingUser(_) | Error::ExistingKey => (Status::Conflict, "existing"),
+        Error::MissingUser(_) | Error::MissingKey(_) => (Status::NotFound, "missing"),
+        Error::InvalidAlias(..) => (Status::UnprocessableEntity, "invalid"),
+        _ => (Status::InternalServerError, "failed"),
+    }
+}
+
+fn admin_status(result: Result<(), Error>) -> status::Custom<Json<AdminResponse>> {
+    let (status, message) = match &result {
+        Ok(_) => (Status::Ok, "success"),
+        Err(e) => error_status(e),
+    };
+    status::Custom(
+        status,
+        Json(AdminResponse {
+            status: message.into(),
+        }),
+    )
+}
+
+fn user_overviews(db: &Databases) -> GenericResult<Vec<UserOverview>> {
+    db.user
+        .list_accounts()?
+        .into_iter()
+        .map(|account| {
+            Ok(UserOverview {
+                vault: db.store.vault_stats(&account.alias)?,
+                account,
+            })
+        })
+        .collect()
+}
+
+/// List users with the size of their vault and their last sync
+#[get("/admin/users")]
+pub fn admin_list_users(
+    _admin: Admin,
+    db: &State<Databases>,
+) -> status::Custom<Json<UsersResponse>> {
+    match user_overviews(db) {
+        Ok(users) => status::Custom(
+            Status::Ok,
+            Json(UsersResponse {
+                status: "success".into(),
+                users: Some(users),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to list users: {:?}", e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(UsersResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+#[post("/admin/users", data = "<data>")]
+pub fn admin_add_user(
+    _admin: Admin,
+    db: &State<Databases>,
+    data: Json<AddUserRequest>,
+) -> status::Custom<Json<AdminResponse>> {
+    let result = db.user.add_account(&data.alias);
+    match &result {
+        Ok(_) => info!("Added user {}", &data.alias),
+        Err(e) => warn!("Failed to add user {}: {:?}", &data.alias, e),
+    }
+    admin_status(result)
+}
+
+/// Remove a user and revoke all of its keys
+///
+/// The user's vault file is kept, but a user added back with the same alias starts over.
+#[delete("/admin/users/<alias>")]
+pub fn admin_remove_user(
+    _admin: Admin,
+    db: &State<Databases>,
+    alias: &str,
+) -> status::Custom<Json<AdminResponse>> {
+    let result = db.user.remove_account(alias);
+    match &result {
+        Ok(_) => info!("Removed user {}", alias),
+        Err(e) => warn!("Failed to remove user {}: {:?}", alias, e),
+    }
+    admin_status(result)
+}
+
+/// Give a user a new alias, keeping its keys and vault
+#[post("/admin/users/<alias>/rename", data = "<data>")]
+pub fn admin_rename_user(
+    _admin: Admin,
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+    alias: &str,
+    data: Json<AddUserRequest>,
+) -> status::Custom<Json<AdminResponse>> {
+    let result = db.user.rename_account(alias, &data.alias, cipher);
+    match &result {
+        Ok(_) => info!("Renamed user {} to {}", alias, &data.alias),
+        Err(e) => warn!("Failed to rename user {}: {:?}", alias, e),
+    }
+    admin_status(result)
+}
+
+#[get("/admin/users/<alias>/keys")]
+pub fn admin_list_keys(
+    _admin: Admin,
+    db: &State<Databases>,
+    alias: &str,
+) -> status::Custom<Json<KeysResponse>> {
+    match db.user.list_keys(alias) {
+        Ok(keys) => status::Custom(
+            Status::Ok,
+            Json(KeysResponse {
+                status: "success".into(),
+                keys: Some(keys),
+            }),
+        ),
+        Err(e) => {
+            warn!("Failed to list keys of user {}: {:?}", alias, e);
+            let (status, message) = error_status(&e);
+            status::Custom(
+                status,
+                Json(KeysResponse {
+                    status: message.into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+/// Issue a new key to a user
+#[post("/admin/users/<alias>/keys", data = "<data>")]
+pub fn admin_issue_key(
+    _admin: Admin,
+    db: &State<Databases>,
+    alias: &str,
+    data: Json<IssueKeyRequest>,
+) -> status::Custom<Json<IssueKeyResponse>> {
+    let scopes = data.scopes.as_deref().unwrap_or(&Scope::ALL);
+    let expires = data.expires_in.map(|seconds| now_secs() + seconds);
+    match issue_key(db.user.as_ref(), alias, &data.name, scopes, expires) {
+        Ok((id, key)) => {
+            info!("Issued key {} to user {}", &id, alias);
+            status::Custom(
+                Status::Ok,
+                Json(IssueKeyResponse {
+                    status: "success".into(),
+                    id: Some(id),
+                    key: Some(key),
+                    expires,
+                }),
+            )
+        }
+        Err(e) => {
+            warn!("Failed to issue key to user {}: {:?}", alias, e);
+            let (status, message) = error_status(&e);
+            status::Custom(
+                status,
+                Json(IssueKeyResponse {
+                    status: message.into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+#[delete("/admin/keys/<id>")]
+pub fn admin_revoke_key(
+    _admin: Admin,
+    db: &State<Databases>,
+    id: &str,
+) -> status::Custom<Json<AdminResponse>> {
+    let result = db.user.remove_key(id);
+    match &result {
+        Ok(_) => info!("Revoked key {}", id),
+        Err(e) => warn!("Failed to revoke key {}: {:?}", id, e),
+    }
+    admin_status(result)
+}
+
+/// Forget every state of the user's vault, so all of its devices get the entire store on
+/// their next sync
+#[post("/admin/users/<alias>/resync")]
+pub fn admin_force_resync(
+    _admin: Admin,
+    db: &State<Databases>,
+    alias: &str,
+) -> status::Custom<Json<ResyncResponse>> {
+    match db.cache.force_resync(alias) {
+        Ok(state_id) => {
+            info!("Forced devices of user {} to resync", alias);
+            status::Custom(
+                Status::Ok,
+                Json(ResyncResponse {
+                    status: "success".into(),
+                    state_id,
+                }),
+            )
+        }
+        Err(e) => {
+            warn!("Failed to force resync of user {}: {:?}", alias, e);
+            let (status, message) = error_status(&e);
+            status::Custom(
+                status,
+                Json(ResyncResponse {
+                    status: message.into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+/// Remove all but the config's `cache_count` most recent states of every vault
+///
+/// Devices on a removed state get the entire store on their next sync.
+#[post("/admin/prune")]
+pub fn admin_prune_cache(
+    _admin: Admin,
+    config: &State<Config>,
+    db: &State<Databases>,
+) -> status::Custom<Json<PruneResponse>> {
+    let pruned = db.user.list_accounts().and_then(|accounts| {
+        accounts.iter().try_fold(0, |pruned, account| {
+            Ok(pruned + db.cache.prune_cache(&account.alias, config.cache_count)?)
+        })
+    });
+    match pruned {
+        Ok(pruned) => {
+            info!("Pruned {} states from the caches", pruned);
+            status::Custom(
+                Status::Ok,
+                Json(PruneResponse {
+                    status: "success".into(),
+                    pruned: Some(pruned),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to prune caches: {:?}", e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(PruneResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+/// Copy every database into a new directory named after the current time in
+/// `backup_directory`
+#[post("/admin/backup")]
+pub fn admin_backup(
+    _admin: Admin,
+    config: &State<Config>,
+    db: &State<Databases>,
+) -> status::Custom<Json<BackupResponse>> {
+    // The random suffix keeps backups started in the same second apart
+    let directory = config
+        .backup_path()
+        .join(format!("{}-{}", now_secs(), random_hex(4)));
+    match db.store.backup(&directory) {
+        Ok(files) => {
+            info!(
+                "Backed up {} databases to {}",
+                files.len(),
+                directory.display()
+            );
+            status::Custom(
+                Status::Ok,
+                Json(BackupResponse {
+                    status: "success".into(),
+                    directory: Some(directory.to_string_lossy().into_owned()),
+                    files: Some(files),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to back up to {}: {:?}", directory.display(), e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(BackupResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{
+            db_types::Scope,
+            endpoints::{
+                devices::RotateKeyResponse, init_upload::InitUploadResponse, sync::SyncResponse,
+            },
+            server::build_server,
+        },
+        config::parse_config::{Config, User},
+        database::{sqlite::SqliteDatabase, traits::StoreDatabase},
+        util::key::{generate_key, hash_key},
+    };
+
+    use super::{BackupResponse, IssueKeyResponse, KeysResponse, PruneResponse, UsersResponse};
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("unit").unwrap()],
+            }],
+            admin_keys: vec![hash_key("admin").unwrap()],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn admin_header() -> Header<'static> {
+        Header::new("Authentication", "admin")
+    }
+
+    #[test]
+    fn admin_key_required() {
+        let config = init_test_config("test/admin/admin_key_required");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client.get(uri!(super::admin_list_users)).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get(uri!(super::admin_list_users))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn config_users_imported() {
+        let config = init_test_config("test/admin/config_users_imported");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .get(uri!(super::admin_list_users))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: UsersResponse = response.into_json().unwrap();
+        let users = body.users.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].account.alias, "unit");
+        assert_eq!(users[0].account.keys, 1);
+
+        let response = client
+            .get(uri!(super::admin_list_keys("unit")))
+            .header(admin_header())
+            .dispatch();
+        let body: KeysResponse = response.into_json().unwrap();
+        assert_eq!(body.keys.unwrap()[0].name, "config");
+    }
+
+    #[test]
+    fn issue_and_revoke_key() {
+        let config = init_test_config("test/admin/issue_and_revoke_key");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::admin_add_user))
+            .header(admin_header())
+            .body(json!({"alias": "family"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(uri!(super::admin_issue_key("family")))
+            .header(admin_header())
+            .body(json!({"name": "phone"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: IssueKeyResponse = response.into_json().unwrap();
+        let key = body.key.unwrap();
+        // Keys start with their id
+        let (id, _) = key.split_once('.').unwrap();
+        assert_eq!(id, body.id.as_ref().unwrap());
+
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", key.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", format!("{}.guess", id)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .delete(uri!(super::admin_revoke_key(body.id.unwrap())))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", key))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn config_imported_once() {
+        let dir = "test/admin/config_imported_once";
+        Config::in_test_directory(dir);
+        let hashes = ["unit", "other", "admin"].map(|key| hash_key(key).unwrap());
+        let config = || Config {
+            users: vec![
+                User {
+                    alias: "unit".into(),
+                    keys: vec![hashes[0].clone()],
+                },
+                User {
+                    alias: "other".into(),
+                    keys: vec![hashes[1].clone()],
+                },
+            ],
+            admin_keys: vec![hashes[2].clone()],
+            db_directory: dir.into(),
+            ..Default::default()
+        };
+        {
+            let client = Client::tracked(build_server(config())).expect("Valid rocket instance");
+            let response = client
+                .get(uri!(super::admin_list_keys("unit")))
+                .header(admin_header())
+                .dispatch();
+            let keys = response.into_json::<KeysResponse>().unwrap().keys.unwrap();
+            let response = client
+                .delete(uri!(super::admin_revoke_key(&keys[0].id)))
+                .header(admin_header())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let response = client
+                .delete(uri!(super::admin_remove_user("other")))
+                .header(admin_header())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        // Restarting with the same config brings neither back
+        let client = Client::tracked(build_server(config())).expect("Valid rocket instance");
+        for key in ["unit", "other"] {
+            let response = client
+                .get("/export")
+                .header(Header::new("Authentication", key))
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+        let response = client
+            .get(uri!(super::admin_list_users))
+            .header(admin_header())
+            .dispatch();
+        let users = response
+            .into_json::<UsersResponse>()
+            .unwrap()
+            .users
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].account.keys, 0);
+    }
+
+    #[test]
+    fn config_key_with_id() {
+        let key = generate_key();
+        let mut config = init_test_config("test/admin/config_key_with_id");
+        config.users[0].keys.push(hash_key(&key).unwrap());
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        for key in [key.as_str(), "unit"] {
+            let response = client
+                .get("/export")
+                .header(Header::new("Authentication", key.to_string()))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let response = client
+            .get(uri!(super::admin_list_keys("unit")))
+            .header(admin_header())
+            .dispatch();
+        let keys = response.into_json::<KeysResponse>().unwrap().keys.unwrap();
+        assert!(keys
+            .iter()
+            .any(|listed| key.starts_with(&format!("{}.", listed.id))));
+    }
+
+    #[test]
+    fn add_and_remove_user() {
+        let config = init_test_config("test/admin/add_and_remove_user");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::admin_add_user))
+            .header(admin_header())
+            .body(json!({"alias": "unit"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = client
+            .delete(uri!(super::admin_remove_user("unit")))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .delete(uri!(super::admin_remove_user("unit")))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(uri!(super::admin_issue_key("unit")))
+            .header(admin_header())
+            .body(json!({}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn removed_user_added_back() {
+        let dir = "test/admin/removed_user_added_back";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/user/init")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        fill_vault(&client);
+        let response = client
+            .delete(uri!(super::admin_remove_user("unit")))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(uri!(super::admin_add_user))
+            .header(admin_header())
+            .body(json!({"alias": "unit"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(uri!(super::admin_issue_key("unit")))
+            .header(admin_header())
+            .body(json!({}).to_string())
+            .dispatch();
+        let key = response
+            .into_json::<IssueKeyResponse>()
+            .unwrap()
+            .key
+            .unwrap();
+
+        // Nothing of the removed user is handed to the new one
+        let response = client
+            .get("/user/import")
+            .header(Header::new("Authentication", key.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(export_count(&client, &key), 0);
+        let response = client
+            .post("/init/upload")
+            .header(Header::new("Authentication", key.clone()))
+            .body(json!([{"id": "one", "value": "secret"}]).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(export_count(&client, &key), 1);
+        // The old vault is kept next to the new one
+        assert_eq!(database_names(dir).len(), 3);
+    }
+
+    /// Names of the databases in `dir` without their extension
+    fn database_names(dir: &str) -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_suffix(".sqlite").map(String::from)
+            })
+            .collect()
+    }
+
+    fn export_count(client: &Client, key: &str) -> usize {
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", key.to_string()))
+            .dispatch();
+        let body: serde_json::Value = response.into_json().unwrap();
+        body["credentials"].as_array().unwrap().len()
+    }
+
+    #[test]
+    fn any_alias() {
+        let dir = "test/admin/any_alias";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        for name in ["../unit", "vult.internal", "a\\b"] {
+            let response = client
+                .post(uri!(super::admin_add_user))
+                .header(admin_header())
+                .body(json!({ "alias": name }).to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let response = client
+                .post(uri!(super::admin_issue_key(name)))
+                .header(admin_header())
+                .body(json!({}).to_string())
+                .dispatch();
+            let body: IssueKeyResponse = response.into_json().unwrap();
+            let key = body.key.unwrap();
+            let response = client
+                .post("/init/upload")
+                .header(Header::new("Authentication", key.clone()))
+                .body(json!([{"id": name, "value": "secret"}]).to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(export_count(&client, &key), 1);
+        }
+        let response = client
+            .post(uri!(super::admin_add_user))
+            .header(admin_header())
+            .body(json!({"alias": ""}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // Vaults are named by id and stay in the directory
+        assert!(!Path::new("test/admin/unit.sqlite").exists());
+        let names = database_names(dir);
+        assert_eq!(names.len(), 4);
+        for name in names {
+            assert!(
+                name == "vult.internal" || name.chars().all(|c| c.is_ascii_hexdigit()),
+                "Unexpected database {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rename_user() {
+        let dir = "test/admin/rename_user";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        fill_vault(&client);
+        let before = database_names(dir);
+        let rename = |name: &str, new_alias: &str| {
+            client
+                .post(uri!(super::admin_rename_user(name)))
+                .header(admin_header())
+                .body(json!({ "alias": new_alias }).to_string())
+                .dispatch()
+                .status()
+        };
+        assert_eq!(rename("unit", "team/unit"), Status::Ok);
+        assert_eq!(rename("unit", "other"), Status::NotFound);
+        assert_eq!(rename("team/unit", ""), Status::UnprocessableEntity);
+
+        // The key and the vault move with the user
+        assert_eq!(export_count(&client, "unit"), 3);
+        assert_eq!(database_names(dir), before);
+        let response = client
+            .get(uri!(super::admin_list_users))
+            .header(admin_header())
+            .dispatch();
+        let users = response
+            .into_json::<UsersResponse>()
+            .unwrap()
+            .users
+            .unwrap();
+        assert_eq!(users[0].account.alias, "team/unit");
+        assert_eq!(users[0].vault.credentials, 3);
+
+        let response = client
+            .post(uri!(super::admin_add_user))
+            .header(admin_header())
+            .body(json!({"alias": "other"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(rename("team/unit", "other"), Status::Conflict);
+    }
+
+    #[test]
+    fn legacy_vault_migrated() {
+        let dir = "test/admin/legacy_vault_migrated";
+        let config = init_test_config(dir);
+        // Vaults used to be named after their alias
+        let legacy = Path::new(dir).join("unit.sqlite");
+        let db = rusqlite::Connection::open(&legacy).unwrap();
+        db.execute("create table Store (id text primary key, value text)", [])
+            .unwrap();
+        db.execute("insert into Store values ('one', 'secret')", [])
+            .unwrap();
+        drop(db);
+
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        assert_eq!(export_count(&client, "unit"), 1);
+        assert!(!legacy.exists());
+        assert_eq!(database_names(dir).len(), 2);
+        assert_eq!(export_count(&client, "unit"), 1);
+    }
+
+    #[test]
+    fn read_only_key() {
+        let config = init_test_config("test/admin/read_only_key");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::admin_issue_key("unit")))
+            .header(admin_header())
+            .body(json!({"name": "backup", "scopes": ["sync:read"]}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let key = response
+            .into_json::<IssueKeyResponse>()
+            .unwrap()
+            .key
+            .unwrap();
+        let reader = || Header::new("Authentication", key.clone());
+
+        let response = client
+            .post("/user/init")
+            .header(reader())
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(r#"Bearer realm="vult", error="insufficient_scope""#)
+        );
+        let response = client
+            .post("/init/upload")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([{"id": "one", "value": "secret"}]).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/user/import").header(reader()).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        let response = client
+            .post("/sync")
+            .header(reader())
+            .body(json!({"state_id": "", "mutations": []}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/sync")
+            .header(reader())
+            .body(
+                json!({
+                    "state_id": "",
+                    "mutations": [{"type": "add", "credential": {"id": "two", "value": "secret"}}]
+                })
+                .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post("/init/upload/begin")
+            .header(reader())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .get(uri!(super::admin_list_keys("unit")))
+            .header(admin_header())
+            .dispatch();
+        let keys = response.into_json::<KeysResponse>().unwrap().keys.unwrap();
+        for key in keys {
+            match key.name.as_str() {
+                "backup" => assert_eq!(key.scopes, [Scope::SyncRead]),
+                _ => assert_eq!(key.scopes, Scope::ALL),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_scope() {
+        let config = init_test_config("test/admin/unknown_scope");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::admin_issue_key("unit")))
+            .header(admin_header())
+            .body(json!({"scopes": ["sync:everything"]}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn expiring_key() {
+        let config = init_test_config("test/admin/expiring_key");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let issue = |expires_in: u64| {
+            let response = client
+                .post(uri!(super::admin_issue_key("unit")))
+                .header(admin_header())
+                .body(json!({"expires_in": expires_in}).to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<IssueKeyResponse>().unwrap()
+        };
+        let sync = |key: &str| {
+            client
+                .post("/sync")
+                .header(Header::new("Authentication", key.to_string()))
+                .body(json!({"state_id": "", "mutations": []}).to_string())
+                .dispatch()
+        };
+
+        let expired = issue(0);
+        assert_eq!(sync(&expired.key.unwrap()).status(), Status::Unauthorized);
+
+        let expiring = issue(3600);
+        let response = sync(expiring.key.as_ref().unwrap());
+        assert_eq!(response.status(), Status::Ok);
+        let body: SyncResponse = response.into_json().unwrap();
+        assert_eq!(body.key_expires, expiring.expires);
+        let body: SyncResponse = sync("unit").into_json().unwrap();
+        assert!(body.key_expires.is_none());
+
+        // Rotating keeps the lifetime of the key
+        let response = client
+            .post("/devices/rotate")
+            .header(Header::new("Authentication", expiring.key.unwrap()))
+            .dispatch();
+        let rotated: RotateKeyResponse = response.into_json().unwrap();
+        assert!(rotated.expires.unwrap() >= expiring.expires.unwrap());
+    }
+
+    /// Upload two credentials and add one with a sync, returning the state ids
+    fn fill_vault(client: &Client) -> Vec<String> {
+        let response = client
+            .post("/init/upload")
+            .header(Header::new("Authentication", "unit"))
+            .body(
+                json!([{"id": "one", "value": "secret"}, {"id": "two", "value": "secret"}])
+                    .to_string(),
+            )
+            .dispatch();
+        let body: InitUploadResponse = response.into_json().unwrap();
+        let mut states = vec![body.state_id.unwrap()];
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "unit"))
+            .body(
+                json!({
+                    "state_id": &states[0],
+                    "mutations": [{"type": "add", "credential": {"id": "three", "value": "secret"}}]
+                })
+                .to_string(),
+            )
+            .dispatch();
+        let body: SyncResponse = response.into_json().unwrap();
+        states.push(body.state_id.unwrap());
+        states
+    }
+
+    fn full_resync(client: &Client, state_id: &str) -> bool {
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!({"state_id": state_id, "mutations": []}).to_string())
+            .dispatch();
+        let body: SyncResponse = response.into_json().unwrap();
+        body.store.is_some()
+    }
+
+    #[test]
+    fn vault_overview() {
+        let config = init_test_config("test/admin/vault_overview");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let users = |client: &Client| {
+            let response = client
+                .get(uri!(super::admin_list_users))
+                .header(admin_header())
+                .dispatch();
+            response
+                .into_json::<UsersResponse>()
+                .unwrap()
+                .users
+                .unwrap()
+        };
+        let before = users(&client);
+        assert_eq!(before[0].vault.credentials, 0);
+        assert!(before[0].account.last_sync.is_none());
+
+        fill_vault(&client);
+        let after = users(&client);
+        assert_eq!(after[0].vault.credentials, 3);
+        assert_eq!(after[0].vault.states, 2);
+        assert!(after[0].vault.size > 0);
+        assert!(after[0].account.last_sync.is_some());
+    }
+
+    #[test]
+    fn force_resync() {
+        let config = init_test_config("test/admin/force_resync");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let states = fill_vault(&client);
+        assert!(!full_resync(&client, &states[1]));
+        let response = client
+            .post(uri!(super::admin_force_resync("unit")))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(full_resync(&client, &states[1]));
+
+        let response = client
+            .post(uri!(super::admin_force_resync("nobody")))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn prune_cache() {
+        let mut config = init_test_config("test/admin/prune_cache");
+        config.cache_count = 1;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let states = fill_vault(&client);
+        let response = client
+            .post(uri!(super::admin_prune_cache))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: PruneResponse = response.into_json().unwrap();
+        assert_eq!(body.pruned, Some(1));
+        assert!(full_resync(&client, &states[0]));
+        assert!(!full_resync(&client, &states[1]));
+    }
+
+    #[test]
+    fn backup() {
+        let dir = "test/admin/backup";
+        let mut config = init_test_config(dir);
+        config.backup_directory = Some(format!("{}/copies", dir));
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        fill_vault(&client);
+        let response = client
+            .post(uri!(super::admin_backup))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: BackupResponse = response.into_json().unwrap();
+        let files = body.files.unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&"vult.internal.sqlite".to_string()));
+        let directory = body.directory.unwrap();
+        let copy = SqliteDatabase::new(&directory);
+        assert_eq!(copy.export_all("unit").unwrap().len(), 3);
+
+        // Another backup within the same second gets its own directory
+        let response = client
+            .post(uri!(super::admin_backup))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: BackupResponse = response.into_json().unwrap();
+        assert_ne!(body.directory.unwrap(), directory);
+    }
+}
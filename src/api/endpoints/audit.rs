@@ -0,0 +1,222 @@
+use log::error;
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{db_types::AuditEvent, guards::user::User},
+    database::traits::Databases,
+};
+
+/// Events returned by default
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct AuditResponse {
+    pub status: String,
+    pub events: Option<Vec<AuditEvent>>,
+}
+
+/// Recent activity on the user's account, newest first
+#[get("/user/audit?<limit>")]
+pub fn user_audit(
+    user: User,
+    db: &State<Databases>,
+    limit: Option<u32>,
+) -> status::Custom<Json<AuditResponse>> {
+    let User(alias) = user;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    match db.user.recent_audit(&alias, limit) {
+        Ok(events) => status::Custom(
+            Status::Ok,
+            Json(AuditResponse {
+                status: "success".into(),
+                events: Some(events),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to read audit log of user {}: {:?}", &alias, e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(AuditResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{
+            db_types::Scope,
+            endpoints::{init_upload::InitUploadResponse, sync::SyncResponse},
+            server::build_server,
+        },
+        config::parse_config::{Config, User},
+        database::{accounts::issue_key, sqlite::SqliteDatabase, traits::UserDatabase},
+        util::{
+            audit::{verify_chain, GENESIS_HASH},
+            key::hash_key,
+        },
+    };
+
+    use super::AuditResponse;
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![
+                User {
+                    alias: "unit".into(),
+                    keys: vec![hash_key("unit").unwrap()],
+                },
+                User {
+                    alias: "other".into(),
+                    keys: vec![hash_key("other").unwrap()],
+                },
+            ],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn audit(client: &Client, key: &str) -> AuditResponse {
+        let response = client
+            .get(uri!(super::user_audit(Option::<u32>::None)))
+            .header(Header::new("Authentication", key.to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    fn recorded() {
+        let dir = "test/audit/recorded";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/user/init")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/init/upload")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([]).to_string())
+            .dispatch();
+        let uploaded: InitUploadResponse = response.into_json().unwrap();
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "unit"))
+            .body(
+                json!({
+                    "state_id": uploaded.state_id,
+                    "mutations": [{"type": "add", "credential": {"id": "1", "value": "value"}}]
+                })
+                .to_string(),
+            )
+            .dispatch();
+        let synced: SyncResponse = response.into_json().unwrap();
+        let response = client
+            .get("/devices")
+            .header(Header::new("Authentication", "wrong"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let events = audit(&client, "unit").events.unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].action, "sync");
+        assert_eq!(events[0].route, "/sync");
+        assert_eq!(events[0].mutations, Some(1));
+        assert_eq!(events[0].state_id, synced.state_id);
+        assert!(events[0].device.is_some());
+        assert_eq!(events[1].action, "upload");
+        assert_eq!(events[1].mutations, Some(0));
+        assert_eq!(events[2].action, "init");
+        assert!(audit(&client, "other").events.unwrap().is_empty());
+
+        // Failures are logged without a user
+        let db = SqliteDatabase::new(dir);
+        let all = db.audit_events(0, 100).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3].action, "auth_failure");
+        assert!(all[3].alias.is_none());
+        assert_eq!(
+            verify_chain(0, GENESIS_HASH, &all),
+            Ok((4, all[3].hash.clone()))
+        );
+    }
+
+    #[test]
+    fn previous_account_hidden() {
+        let dir = "test/audit/previous_account_hidden";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        client
+            .post("/user/init")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(audit(&client, "unit").events.unwrap().len(), 1);
+
+        // The alias is given to a new account after the events of the old one
+        let db = SqliteDatabase::new(dir);
+        db.remove_account("unit").unwrap();
+        db.add_account("unit").unwrap();
+        let (_, key) = issue_key(&db, "unit", "phone", &Scope::ALL, None).unwrap();
+        let connection =
+            rusqlite::Connection::open(format!("{}/vult.internal.sqlite", dir)).unwrap();
+        connection
+            .execute("update AuditLog set time = time - 10", [])
+            .unwrap();
+        assert!(audit(&client, &key).events.unwrap().is_empty());
+        assert_eq!(db.audit_events(0, 100).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let dir = "test/audit/tampering_detected";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        client
+            .post("/user/init")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        for _ in 0..2 {
+            client
+                .get("/user/import")
+                .header(Header::new("Authentication", "unit"))
+                .dispatch();
+        }
+        let db = SqliteDatabase::new(dir);
+        assert!(verify_chain(0, GENESIS_HASH, &db.audit_events(0, 100).unwrap()).is_ok());
+
+        let connection =
+            rusqlite::Connection::open(format!("{}/vult.internal.sqlite", dir)).unwrap();
+        connection
+            .execute("update AuditLog set mutations = 5 where seq = 2", [])
+            .unwrap();
+        assert_eq!(
+            verify_chain(0, GENESIS_HASH, &db.audit_events(0, 100).unwrap()),
+            Err(2)
+        );
+        connection
+            .execute("delete from AuditLog where seq = 2", [])
+            .unwrap();
+        assert_eq!(
+            verify_chain(0, GENESIS_HASH, &db.audit_events(0, 100).unwrap()),
+            Err(3)
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        db_types::{ApiKey, AuditAction},
+        guards::{
+            audit::Audit,
+            user::{AccountAdmin, Device, LoginKey},
+        },
+    },
+    config::parse_config::Config,
+    database::{accounts::rotate_key, traits::Databases},
+    util::{error::Error, types::GenericResult},
+};
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct DevicesResponse {
+    pub status: String,
+    pub devices: Option<Vec<ApiKey>>,
+    /// Key id of the device making the request
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RevokeDeviceResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct RotateKeyResponse {
+    pub status: String,
+    pub id: Option<String>,
+    /// The new key, only ever returned here
+    pub key: Option<String>,
+    /// Expiry of the new key in seconds since the Unix epoch
+    pub expires: Option<u64>,
+    /// Time until which the old key keeps working, in seconds since the Unix epoch
+    pub previous_expires: Option<u64>,
+}
+
+/// List the devices of the user, one per key
+#[get("/devices")]
+pub fn list_devices(
+    device: Device,
+    db: &State<Databases>,
+) -> status::Custom<Json<DevicesResponse>> {
+    let Device(key) = device;
+    match db.user.list_keys(&key.alias) {
+        Ok(devices) => status::Custom(
+            Status::Ok,
+            Json(DevicesResponse {
+                status: "success".into(),
+                devices: Some(devices),
+                current: Some(key.id),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to list devices of user {}: {:?}", &key.alias, e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(DevicesResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+/// Revoke the key of one of the user's devices, such as a lost phone
+#[delete("/devices/<id>")]
+pub fn revoke_device(
+    admin: AccountAdmin,
+    audit: Audit,
+    db: &State<Databases>,
+    id: &str,
+) -> status::Custom<Json<RevokeDeviceResponse>> {
+    let AccountAdmin(key) = admin;
+    let (status, message) = match revoke_aux(db, &key.alias, id) {
+        Ok(_) => {
+            info!(
+                "Device {} revoked key {} of user {}",
+                &key.id, id, &key.alias
+            );
+            audit.record(audit.entry(AuditAction::RevokeDevice, &key));
+            (Status::Ok, "success")
+        }
+        Err(Error::MissingKey(_)) => {
+            warn!("User {} tried to revoke missing device {}", &key.alias, id);
+            (Status::NotFound, "missing")
+        }
+        Err(e) => {
+            error!("Failed to revoke device {}: {:?}", id, e);
+            (Status::InternalServerError, "failed")
+        }
+    };
+    status::Custom(
+        status,
+        Json(RevokeDeviceResponse {
+            status: message.into(),
+        }),
+    )
+}
+
+/// Replace the key of the requesting device with a new one
+///
+/// The new key keeps the name, scopes and lifetime of the old one, which stays valid for
+/// the configured grace period so the device can switch over.
+#[post("/devices/rotate")]
+pub fn rotate_device_key(
+    key: LoginKey,
+    audit: Audit,
+    config: &State<Config>,
+    db: &State<Databases>,
+) -> status::Custom<Json<RotateKeyResponse>> {
+    let LoginKey(old) = key;
+    match rotate_key(db.user.as_ref(), &old, config.auth.rotation_grace) {
+        Ok(rotated) => {
+            info!(
+                "Rotated key {} of user {} to {}",
+                &old.id, &old.alias, &rotated.id
+            );
+            audit.record(audit.entry(AuditAction::RotateKey, &old));
+            status::Custom(
+                Status::Ok,
+                Json(RotateKeyResponse {
+                    status: "success".into(),
+                    id: Some(rotated.id),
+                    key: Some(rotated.key),
+                    expires: rotated.expires,
+                    previous_expires: Some(rotated.old_expires),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to rotate key {}: {:?}", &old.id, e);
+            status::Custom(
+                Status::InternalServerError,
+                Json(RotateKeyResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+fn revoke_aux(db: &State<Databases>, alias: &str, id: &str) -> GenericResult<()> {
+    // Keys of other users are reported as missing
+    if db.user.get_key(id)?.alias != alias {
+        return Err(Error::MissingKey(id.to_string()));
+    }
+    db.user.remove_key(id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        path::Path,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{endpoints::sync::SyncResponse, server::build_server},
+        config::parse_config::{ClientCertificate, Config, TlsConfig, User},
+        database::{
+            accounts::{import_client_certificates, CERTIFICATE_KEY_NAME},
+            sqlite::SqliteDatabase,
+        },
+        util::{certificate::fingerprint, error::Error, key::hash_key},
+    };
+
+    use super::{DevicesResponse, RotateKeyResponse};
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![
+                User {
+                    alias: "unit".into(),
+                    keys: vec![hash_key("phone").unwrap(), hash_key("laptop").unwrap()],
+                },
+                User {
+                    alias: "other".into(),
+                    keys: vec![hash_key("other").unwrap()],
+                },
+            ],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn devices(client: &Client, key: &str) -> DevicesResponse {
+        let response = client
+            .get(uri!(super::list_devices))
+            .header(Header::new("Authentication", key.to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    fn sync_recorded() {
+        let config = init_test_config("test/devices/sync_recorded");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "phone"))
+            .body(json!({"state_id": "", "mutations": []}).to_string())
+            .dispatch();
+        let synced: SyncResponse = response.into_json().unwrap();
+
+        let phone = devices(&client, "phone").current.unwrap();
+        let body = devices(&client, "laptop");
+        let laptop = body.current.unwrap();
+        assert_ne!(phone, laptop);
+        let devices = body.devices.unwrap();
+        assert_eq!(devices.len(), 2);
+        for device in devices {
+            if device.id == phone {
+                assert!(device.last_sync.is_some());
+                assert_eq!(device.last_state, synced.state_id);
+            } else {
+                assert_eq!(device.id, laptop);
+                assert!(device.last_sync.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn revoke_one_device() {
+        let config = init_test_config("test/devices/revoke_one_device");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let phone = devices(&client, "phone").current.unwrap();
+        let laptop = devices(&client, "laptop").current.unwrap();
+
+        let response = client
+            .delete(uri!(super::revoke_device(&laptop)))
+            .header(Header::new("Authentication", "other"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .delete(uri!(super::revoke_device(&phone)))
+            .header(Header::new("Authentication", "laptop"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(uri!(super::list_devices))
+            .header(Header::new("Authentication", "phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let body = devices(&client, "laptop");
+        assert_eq!(body.devices.unwrap().len(), 1);
+    }
+
+    fn rotate(client: &Client, key: &str) -> RotateKeyResponse {
+        let response = client
+            .post(uri!(super::rotate_device_key))
+            .header(Header::new("Authentication", key.to_string()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    fn rotate_with_grace() {
+        let config = init_test_config("test/devices/rotate_with_grace");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "phone"))
+            .body(json!({"state_id": "", "mutations": []}).to_string())
+            .dispatch();
+        let synced: SyncResponse = response.into_json().unwrap();
+        let phone = devices(&client, "phone").current.unwrap();
+
+        let rotated = rotate(&client, "phone");
+        assert!(rotated.expires.is_none());
+        assert!(rotated.previous_expires.is_some());
+        let key = rotated.key.unwrap();
+        let body = devices(&client, &key);
+        assert_eq!(body.current, rotated.id);
+        for device in body.devices.unwrap() {
+            if Some(&device.id) == rotated.id.as_ref() {
+                assert_eq!(device.last_state, synced.state_id);
+            } else if device.id == phone {
+                assert_eq!(device.expires, rotated.previous_expires);
+            }
+        }
+        // The old key keeps working during the grace period
+        devices(&client, "phone");
+    }
+
+    #[test]
+    fn rotate_without_grace() {
+        let mut config = init_test_config("test/devices/rotate_without_grace");
+        config.auth.rotation_grace = 0;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let key = rotate(&client, "phone").key.unwrap();
+        devices(&client, &key);
+        let response = client
+            .get(uri!(super::list_devices))
+            .header(Header::new("Authentication", "phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    /// Certificate with common name `name`, signed by `ca` unless it is itself a CA
+    fn certificate(name: &str, ca: Option<&rcgen::Certificate>) -> (rcgen::Certificate, String) {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        if ca.is_none() {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        let certificate = rcgen::Certificate::from_params(params).unwrap();
+        let pem = match ca {
+            Some(ca) => certificate.serialize_pem_with_signer(ca).unwrap(),
+            None => certificate.serialize_pem().unwrap(),
+        };
+        (certificate, pem)
+    }
+
+    /// HTTPS request over a new connection, returning the status code and body
+    fn https_request(
+        port: u16,
+        ca: &rcgen::Certificate,
+        identity: Option<(&rcgen::Certificate, &rcgen::Certificate)>,
+        request: &str,
+    ) -> (u16, String) {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let tls_config = match identity {
+            Some((certificate, ca)) => builder
+                .with_single_cert(
+                    vec![rustls::Certificate(
+                        certificate.serialize_der_with_signer(ca).unwrap(),
+                    )],
+                    rustls::PrivateKey(certificate.serialize_private_key_der()),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connection =
+            rustls::ClientConnection::new(Arc::new(tls_config), "localhost".try_into().unwrap())
+                .unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, socket);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut response) {
+            // Closing the connection without a TLS alert is fine once the body is read
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        }
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn client_certificates() {
+        let dir = "test/devices/client_certificates";
+        let mut config = init_test_config(dir);
+        let (ca, ca_pem) = certificate("vult test CA", None);
+        let (server, server_pem) = certificate("localhost", Some(&ca));
+        let (phone, _) = certificate("phone", Some(&ca));
+        let (tablet, _) = certificate("tablet", Some(&ca));
+        let (stranger, _) = certificate("stranger", Some(&ca));
+        let path = |name: &str| format!("{}/{}", dir, name);
+        std::fs::write(path("ca.pem"), ca_pem).unwrap();
+        std::fs::write(path("server.pem"), server_pem).unwrap();
+        std::fs::write(path("server.key"), server.serialize_private_key_pem()).unwrap();
+        // Fingerprints are accepted as printed by openssl
+        let phone_fingerprint = fingerprint(&phone.get_key_pair().public_key_der())
+            .as_bytes()
+            .chunks(2)
+            .map(|byte| String::from_utf8_lossy(byte).to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        config.tls = Some(TlsConfig {
+            certs: path("server.pem"),
+            key: path("server.key"),
+            client_ca: Some(path("ca.pem")),
+            mandatory: false,
+            clients: vec![
+                ClientCertificate {
+                    alias: "unit".into(),
+                    fingerprint: Some(phone_fingerprint),
+                    subject: None,
+                },
+                ClientCertificate {
+                    alias: "other".into(),
+                    fingerprint: None,
+                    subject: Some("CN=tablet".into()),
+                },
+            ],
+        });
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        config.address = Some(Ipv4Addr::LOCALHOST.into());
+        config.port = Some(port);
+        let rocket = rocket::execute(build_server(config).ignite()).unwrap();
+        let shutdown = rocket.shutdown();
+        std::thread::spawn(move || {
+            let _rocket = rocket::execute(rocket.launch()).expect("Launch TLS server");
+        });
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let devices = "GET /devices HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let (status, body) = https_request(port, &ca, Some((&phone, &ca)), devices);
+        assert_eq!(status, 200);
+        let body: DevicesResponse = serde_json::from_str(&body).unwrap();
+        let devices_list = body.devices.unwrap();
+        assert_eq!(devices_list.len(), 3);
+        let current = devices_list
+            .iter()
+            .find(|device| Some(&device.id) == body.current.as_ref())
+            .unwrap();
+        assert_eq!(current.name, CERTIFICATE_KEY_NAME);
+
+        let (status, body) = https_request(port, &ca, Some((&tablet, &ca)), devices);
+        assert_eq!(status, 200);
+        let body: DevicesResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(body.devices.unwrap()[0].alias, "other");
+
+        let (status, _) = https_request(port, &ca, Some((&stranger, &ca)), devices);
+        assert_eq!(status, 401);
+        let (status, _) = https_request(port, &ca, None, devices);
+        assert_eq!(status, 401);
+        let with_key = "GET /devices HTTP/1.1\r\nHost: localhost\r\nAuthentication: phone\r\nConnection: close\r\n\r\n";
+        let (status, _) = https_request(port, &ca, None, with_key);
+        assert_eq!(status, 200);
+        // Certificates have no key to rotate
+        let rotate = "POST /devices/rotate HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (status, _) = https_request(port, &ca, Some((&phone, &ca)), rotate);
+        assert_eq!(status, 401);
+        shutdown.notify();
+    }
+
+    #[test]
+    fn moved_certificate_rejected() {
+        let dir = "test/devices/moved_certificate_rejected";
+        Config::in_test_directory(dir);
+        let db = SqliteDatabase::new(dir);
+        let client = |alias: &str| ClientCertificate {
+            alias: alias.into(),
+            fingerprint: None,
+            subject: Some("CN=tablet".into()),
+        };
+        import_client_certificates(&db, &[client("unit")]).unwrap();
+        import_client_certificates(&db, &[client("unit")]).unwrap();
+        assert!(matches!(
+            import_client_certificates(&db, &[client("other")]),
+            Err(Error::Config(_))
+        ));
+    }
+}
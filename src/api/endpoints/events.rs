@@ -0,0 +1,368 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{error, info};
+use rocket::{
+    http::Status,
+    response::{
+        status,
+        stream::{Event, EventStream},
+    },
+    tokio::{select, sync::broadcast::error::RecvError, time},
+    Shutdown, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{guards::user::User, wire::Wire},
+    config::parse_config::Config,
+    database::{notify::NotificationHub, traits::Databases},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateEvent {
+    pub state_id: String,
+}
+
+/// Stream of server-sent `state` events carrying each new state id of the user
+///
+/// Devices sync when they receive a state id other than their own.
+#[get("/sync/events")]
+pub fn sync_events(
+    user: User,
+    hub: &State<Arc<NotificationHub>>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let User(alias) = user;
+    info!("Streaming state changes to user {}", &alias);
+    let mut receiver = hub.subscribe(&alias);
+    EventStream! {
+        loop {
+            let state_id = select! {
+                received = receiver.recv() => match received {
+                    Ok(state_id) => state_id,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&StateEvent { state_id }).event("state");
+        }
+    }
+    .heartbeat(Duration::from_secs(15))
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct WaitResponse {
+    pub status: String,
+    pub state_id: Option<String>,
+}
+
+/// Long-poll for a state newer than `state_id`
+///
+/// Responds as soon as the most recent state differs from `state_id`, with status
+/// `changed` and the new state id, or with status `unchanged` once `timeout` seconds
+/// (capped by the server config) have passed.
+#[get("/sync/wait?<state_id>&<timeout>")]
+pub async fn wait_for_state(
+    user: User,
+    config: &State<Config>,
+    db: &State<Databases>,
+    hub: &State<Arc<NotificationHub>>,
+    mut shutdown: Shutdown,
+    state_id: &str,
+    timeout: Option<u64>,
+) -> status::Custom<Wire<WaitResponse>> {
+    let User(alias) = user;
+    let response = |status: &str, state_id: Option<String>| {
+        status::Custom(
+            Status::Ok,
+            Wire(WaitResponse {
+                status: status.into(),
+                state_id,
+            }),
+        )
+    };
+
+    // Subscribe before checking so that no state can slip in between
+    let mut receiver = hub.subscribe(&alias);
+    match db.cache.latest_state(&alias) {
+        Ok(Some(latest)) if latest != state_id => return response("changed", Some(latest)),
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to get latest state for user {}: {:?}", &alias, e);
+            return status::Custom(
+                Status::InternalServerError,
+                Wire(WaitResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            );
+        }
+    }
+
+    let duration = Duration::from_secs(
+        timeout
+            .unwrap_or(config.long_poll_timeout)
+            .min(config.long_poll_timeout),
+    );
+    let wait = async {
+        loop {
+            match receiver.recv().await {
+                Ok(latest) if latest != state_id => return Some(latest),
+                Ok(_) => continue,
+                // The state that was skipped may be the one waited for
+                Err(RecvError::Lagged(_)) => match db.cache.latest_state(&alias) {
+                    Ok(Some(latest)) if latest != state_id => return Some(latest),
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Failed to get latest state for user {}: {:?}", &alias, e);
+                        continue;
+                    }
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    };
+    select! {
+        latest = time::timeout(duration, wait) => match latest {
+            Ok(Some(latest)) => {
+                info!("Notifying waiting user {} of state {}", &alias, &latest);
+                response("changed", Some(latest))
+            }
+            _ => response("unchanged", Some(state_id.into())),
+        },
+        _ = &mut shutdown => response("unchanged", Some(state_id.into())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use rocket::{
+        http::{Header, Status},
+        local::asynchronous::Client,
+        tokio::{
+            io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines},
+            join,
+            time::{sleep, timeout},
+        },
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{
+            endpoints::{init_upload::InitUploadResponse, sync::SyncResponse},
+            server::build_server,
+        },
+        config::parse_config::{Config, User},
+        database::{notify::NotificationHub, sqlite::SqliteDatabase, traits::CacheDatabase},
+        util::key::hash_key,
+    };
+
+    use super::{StateEvent, WaitResponse};
+
+    /// Data of the next event, skipping comments and heartbeats
+    async fn next_data<R: AsyncBufRead + Unpin>(
+        lines: &mut Lines<R>,
+        duration: Duration,
+    ) -> Option<String> {
+        let read = async {
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Some(data) = line.strip_prefix("data:") {
+                    return Some(data.to_string());
+                }
+            }
+            None
+        };
+        timeout(duration, read).await.ok().flatten()
+    }
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![
+                User {
+                    alias: "unit".into(),
+                    keys: vec![hash_key("unit").unwrap()],
+                },
+                User {
+                    alias: "other".into(),
+                    keys: vec![hash_key("other").unwrap()],
+                },
+            ],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    #[rocket::async_test]
+    async fn notified_on_sync() {
+        let config = init_test_config("test/events/notified_on_sync");
+        let client = Client::tracked(build_server(config))
+            .await
+            .expect("Valid rocket instance");
+        let events = client
+            .get(uri!(super::sync_events))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch()
+            .await;
+        assert_eq!(events.status(), Status::Ok);
+        let other_events = client
+            .get(uri!(super::sync_events))
+            .header(Header::new("Authentication", "other"))
+            .dispatch()
+            .await;
+
+        let response = client
+            .post("/sync")
+            .header(Header::new("Authentication", "unit"))
+            .body(
+                json!({
+                    "state_id": "",
+                    "mutations": [
+                        {"type": "add", "credential": {"id": "random", "value": "nothing"}}
+                    ]
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await;
+        let body: SyncResponse = response.into_json().await.unwrap();
+        let state_id = body.state_id.unwrap();
+
+        let mut lines = BufReader::new(events).lines();
+        let event: StateEvent =
+            serde_json::from_str(&next_data(&mut lines, Duration::from_secs(5)).await.unwrap())
+                .unwrap();
+        assert_eq!(event.state_id, state_id);
+
+        // Other users are not told about the change
+        let mut other_lines = BufReader::new(other_events).lines();
+        assert!(next_data(&mut other_lines, Duration::from_millis(200))
+            .await
+            .is_none());
+    }
+
+    async fn initial_state(client: &Client) -> String {
+        let response = client
+            .post("/init/upload")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([]).to_string())
+            .dispatch()
+            .await;
+        let body: InitUploadResponse = response.into_json().await.unwrap();
+        body.state_id.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn wait_stale() {
+        let config = init_test_config("test/events/wait_stale");
+        let client = Client::tracked(build_server(config))
+            .await
+            .expect("Valid rocket instance");
+        let state_id = initial_state(&client).await;
+        let response = client
+            .get(uri!(super::wait_for_state("old", Option::<u64>::None)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch()
+            .await;
+        let body: WaitResponse = response.into_json().await.unwrap();
+        assert_eq!(body.status, "changed");
+        assert_eq!(body.state_id, Some(state_id));
+    }
+
+    #[rocket::async_test]
+    async fn wait_for_sync() {
+        let config = init_test_config("test/events/wait_for_sync");
+        let client = Client::tracked(build_server(config))
+            .await
+            .expect("Valid rocket instance");
+        let state_id = initial_state(&client).await;
+        let wait = async {
+            let response = client
+                .get(uri!(super::wait_for_state(&state_id, Option::<u64>::None)))
+                .header(Header::new("Authentication", "unit"))
+                .dispatch()
+                .await;
+            let body: WaitResponse = response.into_json().await.unwrap();
+            body
+        };
+        let sync = async {
+            sleep(Duration::from_millis(100)).await;
+            let response = client
+                .post("/sync")
+                .header(Header::new("Authentication", "unit"))
+                .body(
+                    json!({
+                        "state_id": &state_id,
+                        "mutations": [
+                            {"type": "add", "credential": {"id": "random", "value": "nothing"}}
+                        ]
+                    })
+                    .to_string(),
+                )
+                .dispatch()
+                .await;
+            let body: SyncResponse = response.into_json().await.unwrap();
+            body
+        };
+        let (waited, synced) = join!(wait, sync);
+        assert_eq!(waited.status, "changed");
+        assert_eq!(waited.state_id, synced.state_id);
+    }
+
+    #[rocket::async_test]
+    async fn wait_after_lag() {
+        let dir = "test/events/wait_after_lag";
+        let mut config = init_test_config(dir);
+        config.long_poll_timeout = 2;
+        let client = Client::tracked(build_server(config))
+            .await
+            .expect("Valid rocket instance");
+        let state_id = initial_state(&client).await;
+        let hub = client
+            .rocket()
+            .state::<Arc<NotificationHub>>()
+            .unwrap()
+            .clone();
+        let wait = async {
+            let response = client
+                .get(uri!(super::wait_for_state(&state_id, Option::<u64>::None)))
+                .header(Header::new("Authentication", "unit"))
+                .dispatch()
+                .await;
+            let body: WaitResponse = response.into_json().await.unwrap();
+            body
+        };
+        let change = async {
+            sleep(Duration::from_millis(100)).await;
+            // Change the state without a notification, then overflow the channel so the
+            // waiting request lags behind
+            let new_state_id = SqliteDatabase::new(dir).add_mutations("unit", &[]).unwrap();
+            for _ in 0..100 {
+                hub.publish("unit", &state_id);
+            }
+            new_state_id
+        };
+        let (waited, new_state_id) = join!(wait, change);
+        assert_eq!(waited.status, "changed");
+        assert_eq!(waited.state_id, Some(new_state_id));
+    }
+
+    #[rocket::async_test]
+    async fn wait_timeout() {
+        let mut config = init_test_config("test/events/wait_timeout");
+        config.long_poll_timeout = 1;
+        let client = Client::tracked(build_server(config))
+            .await
+            .expect("Valid rocket instance");
+        let state_id = initial_state(&client).await;
+        let response = client
+            .get(uri!(super::wait_for_state(&state_id, Some(60))))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch()
+            .await;
+        let body: WaitResponse = response.into_json().await.unwrap();
+        assert_eq!(body.status, "unchanged");
+        assert_eq!(body.state_id, Some(state_id));
+    }
+}
@@ -0,0 +1,170 @@
+use log::{error, info};
+use rocket::{http::Status, response::status, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{db_types::Credential, guards::user::User, wire::Wire},
+    config::parse_config::Config,
+    database::traits::Databases,
+};
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ExportResponse {
+    pub status: String,
+    pub credentials: Option<Vec<Credential>>,
+    /// Cursor of the next page, missing on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Export one page of the store
+///
+/// Pages are ordered by credential id. Credentials changed while paging may appear
+/// with either value, so clients sync from the state id that pointed them here afterwards.
+#[get("/export?<cursor>&<limit>")]
+pub fn export_store(
+    user: User,
+    config: &State<Config>,
+    db: &State<Databases>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+) -> status::Custom<Wire<ExportResponse>> {
+    let User(alias) = user;
+    let limit = limit
+        .unwrap_or(config.export_page_size)
+        .clamp(1, config.export_page_size);
+    match db.store.export_page(&alias, cursor.as_deref(), limit) {
+        Ok(credentials) => {
+            info!(
+                "Exported page of {} credentials for user {}",
+                credentials.len(),
+                &alias
+            );
+            let next_cursor = if credentials.len() < limit as usize {
+                None
+            } else {
+                credentials.last().map(|c| c.id.to_owned())
+            };
+            status::Custom(
+                Status::Ok,
+                Wire(ExportResponse {
+                    status: "success".into(),
+                    credentials: Some(credentials),
+                    next_cursor,
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to export store for user {}: {:?}", &alias, e);
+            status::Custom(
+                Status::InternalServerError,
+                Wire(ExportResponse {
+                    status: "failed".into(),
+                    ..Default::default()
+                }),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{db_types::Credential, server::build_server},
+        config::parse_config::{Config, User},
+        util::key::hash_key,
+    };
+
+    use super::ExportResponse;
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("unit").unwrap()],
+            }],
+            export_page_size: 2,
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn auth_header() -> Header<'static> {
+        Header::new("Authentication", "unit")
+    }
+
+    #[test]
+    fn paged() {
+        let config = init_test_config("test/export/paged");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(
+                json!([
+                    {"id": "c", "value": "3"},
+                    {"id": "a", "value": "1"},
+                    {"id": "e", "value": "5"},
+                    {"id": "b", "value": "2"},
+                    {"id": "d", "value": "4"},
+                ])
+                .to_string(),
+            )
+            .dispatch();
+
+        let mut exported: Vec<Credential> = Vec::new();
+        let mut cursor: Option<String> = None;
+        let mut pages = 0;
+        loop {
+            let response = client
+                .get(uri!(super::export_store(
+                    cursor.as_deref(),
+                    Option::<u32>::None
+                )))
+                .header(auth_header())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let body: ExportResponse = response.into_json().unwrap();
+            pages += 1;
+            exported.append(&mut body.credentials.unwrap());
+            cursor = body.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(pages, 3);
+        let ids: Vec<&str> = exported.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn limit_capped() {
+        let config = init_test_config("test/export/limit_capped");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(
+                json!([
+                    {"id": "a", "value": "1"},
+                    {"id": "b", "value": "2"},
+                    {"id": "c", "value": "3"},
+                ])
+                .to_string(),
+            )
+            .dispatch();
+        let response = client
+            .get(uri!(super::export_store(Option::<&str>::None, Some(100))))
+            .header(auth_header())
+            .dispatch();
+        let body: ExportResponse = response.into_json().unwrap();
+        assert_eq!(body.credentials.unwrap().len(), 2);
+        assert_eq!(body.next_cursor, Some("b".to_string()));
+    }
+}
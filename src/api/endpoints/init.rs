@@ -1,5 +1,9 @@
+use crate::api::{
+    db_types::AuditAction,
+    guards::{audit::Audit, user::Writer},
+};
+use crate::database::traits::Databases;
 use crate::util::error::Error;
-use crate::{api::guards::user::User, database::traits::Databases};
 use anyhow::Result;
 use log::{error, info, warn};
 use rocket::response::status;
@@ -20,16 +24,18 @@ pub struct InitResponse {
 
 #[post("/user/init", data = "<data>")]
 pub fn initialize_user(
-    user: User,
+    writer: Writer,
+    audit: Audit,
     db: &State<Databases>,
     data: Json<InitRequest>,
 ) -> status::Custom<Json<InitResponse>> {
-    let User(alias) = user;
-    info!("{:?}", &data);
-    let result = add_salt_aux(db, &alias, &data.salt, &data.hash);
+    let Writer(key) = writer;
+    let alias = &key.alias;
+    let result = add_salt_aux(db, alias, &data.salt, &data.hash);
     match result {
         Ok(true) => {
             info!("Initialized user {}", &alias);
+            audit.record(audit.entry(AuditAction::Init, &key));
             status::Custom(
                 Status::Ok,
                 Json(InitResponse {
@@ -88,21 +94,16 @@ mod test {
     use crate::{
         api::server::build_server,
         config::parse_config::{Config, User},
+        util::key::hash_key,
     };
 
     fn init_test_config(dir: &str) -> Config {
-        if Path::new(dir).exists() {
-            std::fs::remove_dir_all(dir).expect("Remove test data directory");
-        }
-        std::fs::create_dir_all(dir).expect("Create test data directory");
         Config {
             users: vec![User {
                 alias: "unit".into(),
-                keys: vec!["unit".into()],
+                keys: vec![hash_key("unit").unwrap()],
             }],
-            cache_count: 50,
-            db_directory: dir.into(),
-            enable_test_routes: false,
+            ..Config::in_test_directory(dir)
         }
     }
 
@@ -114,7 +115,7 @@ mod test {
             .post(uri!(super::initialize_user))
             .header(Header::new("Authentication", "random"))
             .dispatch();
-        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.status(), Status::Unauthorized);
     }
 
     #[test]
@@ -122,7 +123,11 @@ mod test {
         let config = init_test_config("test/init/missing_header");
         let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
         let response = client.post(uri!(super::initialize_user)).dispatch();
-        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(r#"Bearer realm="vult""#)
+        );
     }
 
     #[test]
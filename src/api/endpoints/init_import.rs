@@ -1,4 +1,14 @@
-use crate::{api::guards::user::User, database::traits::Databases, util::error::Error};
+use crate::{
+    api::{
+        db_types::AuditAction,
+        guards::{
+            audit::Audit,
+            user::{Device, Enrolled},
+        },
+    },
+    database::traits::Databases,
+    util::error::Error,
+};
 use log::{info, warn};
 use rocket::{http::Status, response::status, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
@@ -7,22 +17,32 @@ use serde::{Deserialize, Serialize};
 pub struct UserImportResponse {
     pub status: String,
     pub salt: Option<String>,
-    pub hash: Option<String>,
 }
 
+/// Salt of the master password, for a new device to set up the vault
+///
+/// The hash is not handed out, since it is what proofs of the password are checked
+/// against. Devices check the password they are given with `/auth/prove` instead.
+/// If the user set up TOTP, devices have to enroll with a code first.
 #[get("/user/import")]
-pub fn get_user(user: User, db: &State<Databases>) -> status::Custom<Json<UserImportResponse>> {
-    let User(alias) = user;
-    let result = db.user.get_user(&alias);
+pub fn get_user(
+    device: Device,
+    _enrolled: Enrolled,
+    audit: Audit,
+    db: &State<Databases>,
+) -> status::Custom<Json<UserImportResponse>> {
+    let Device(key) = device;
+    let alias = &key.alias;
+    let result = db.user.get_user(alias);
     match result {
         Ok(user) => {
             info!("Provided salt for user {}", &alias);
+            audit.record(audit.entry(AuditAction::Import, &key));
             status::Custom(
                 Status::Ok,
                 Json(UserImportResponse {
                     status: "success".into(),
                     salt: Some(user.0),
-                    hash: Some(user.1),
                 }),
             )
         }
@@ -32,7 +52,6 @@ pub fn get_user(user: User, db: &State<Databases>) -> status::Custom<Json<UserIm
                 Json(UserImportResponse {
                     status: "failed".into(),
                     salt: None,
-                    hash: None,
                 }),
             );
             if let Some(e) = e.downcast_ref::<Error>() {
@@ -44,7 +63,6 @@ pub fn get_user(user: User, db: &State<Databases>) -> status::Custom<Json<UserIm
                             Json(UserImportResponse {
                                 status: "uninitialized".into(),
                                 salt: None,
-                                hash: None,
                             }),
                         )
                     }
@@ -74,21 +92,16 @@ mod test {
     use crate::{
         api::server::build_server,
         config::parse_config::{Config, User},
+        util::key::hash_key,
     };
 
     fn init_test_config(dir: &str) -> Config {
-        if Path::new(dir).exists() {
-            std::fs::remove_dir_all(dir).expect("Remove test data directory");
-        }
-        std::fs::create_dir_all(dir).expect("Create test data directory");
         Config {
             users: vec![User {
                 alias: "unit".into(),
-                keys: vec!["unit".into()],
+                keys: vec![hash_key("unit").unwrap()],
             }],
-            cache_count: 50,
-            db_directory: dir.into(),
-            enable_test_routes: false,
+            ..Config::in_test_directory(dir)
         }
     }
 
@@ -111,10 +124,7 @@ mod test {
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
         let body: Value = response.into_json().unwrap();
-        assert_eq!(
-            body,
-            json!({"status":"success","salt":"somesalt", "hash": "somehash"})
-        );
+        assert_eq!(body, json!({"status":"success","salt":"somesalt"}));
     }
 
     #[test]
@@ -127,9 +137,6 @@ mod test {
             .dispatch();
         assert_eq!(response.status(), Status::Conflict);
         let body: Value = response.into_json().unwrap();
-        assert_eq!(
-            body,
-            json!({"status":"uninitialized","salt":null,"hash":null})
-        );
+        assert_eq!(body, json!({"status":"uninitialized","salt":null}));
     }
 }
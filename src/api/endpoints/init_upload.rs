@@ -1,12 +1,20 @@
 use anyhow::Result;
+use log::{info, warn};
 use rocket::response::status;
-use rocket::serde::json::Json;
 use rocket::{http::Status, State};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    api::{db_types::Credential, guards::user::User},
-    database::traits::Databases,
+    api::{
+        db_types::{AuditAction, AuditEntry, Credential},
+        guards::{
+            audit::Audit,
+            user::{AccountAdmin, Writer},
+        },
+        wire::Wire,
+    },
+    database::traits::{Databases, StoreDatabase, UserDatabase},
+    util::{error::Error, types::GenericResult},
 };
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -15,14 +23,25 @@ pub struct InitUploadResponse {
     pub status: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
+pub struct UploadSessionResponse {
+    pub status: String,
+    pub upload_id: Option<String>,
+    /// Number of credentials received so far
+    pub received: Option<u64>,
+}
+
 #[post("/init/upload", data = "<data>")]
 pub fn user_initial_upload(
-    user: User,
+    writer: Writer,
+    audit: Audit,
     db: &State<Databases>,
-    data: Json<Vec<Credential>>,
-) -> status::Custom<Json<InitUploadResponse>> {
-    let User(alias) = user;
-    match import(&alias, db, data) {
+    data: Wire<Vec<Credential>>,
+) -> status::Custom<Wire<InitUploadResponse>> {
+    let Writer(key) = writer;
+    let alias = &key.alias;
+    let count = data.len() as u64;
+    match import(alias, db, data) {
         Ok(None) => {
             error!(
                 "Conflict on initial import for user {}: user data already exists",
@@ -30,24 +49,31 @@ pub fn user_initial_upload(
             );
             status::Custom(
                 Status::Conflict,
-                Json(InitUploadResponse {
+                Wire(InitUploadResponse {
                     state_id: None,
                     status: "existing".to_string(),
                 }),
             )
         }
-        Ok(Some(state_id)) => status::Custom(
-            Status::Ok,
-            Json(InitUploadResponse {
-                state_id: Some(state_id),
-                status: "success".to_string(),
-            }),
-        ),
+        Ok(Some(state_id)) => {
+            audit.record(AuditEntry {
+                mutations: Some(count),
+                state_id: Some(state_id.to_owned()),
+                ..audit.entry(AuditAction::Upload, &key)
+            });
+            status::Custom(
+                Status::Ok,
+                Wire(InitUploadResponse {
+                    state_id: Some(state_id),
+                    status: "success".to_string(),
+                }),
+            )
+        }
         Err(_) => {
             error!("Failed to do initial import for user {}", &alias);
             status::Custom(
                 Status::InternalServerError,
-                Json(InitUploadResponse {
+                Wire(InitUploadResponse {
                     state_id: None,
                     status: "failed".to_string(),
                 }),
@@ -60,7 +86,7 @@ pub fn user_initial_upload(
 fn import(
     alias: &str,
     db: &State<Databases>,
-    data: Json<Vec<Credential>>,
+    data: Wire<Vec<Credential>>,
 ) -> Result<Option<String>> {
     let store_empty = db.store.is_empty(alias)?;
     let cache_empty = db.cache.is_empty(alias)?;
@@ -73,6 +99,220 @@ fn import(
     }
 }
 
+/// Start a resumable upload for stores too large for a single request
+#[post("/init/upload/begin")]
+pub fn begin_upload(
+    writer: Writer,
+    db: &State<Databases>,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    let Writer(key) = writer;
+    let alias = key.alias;
+    let result = is_uninitialized(&alias, db).and_then(|empty| match empty {
+        true => Ok(Some(db.store.begin_upload(&alias)?)),
+        false => Ok(None),
+    });
+    match result {
+        Ok(Some(upload_id)) => {
+            info!("Started upload {} for user {}", &upload_id, &alias);
+            status::Custom(
+                Status::Ok,
+                Wire(UploadSessionResponse {
+                    status: "success".into(),
+                    upload_id: Some(upload_id),
+                    received: Some(0),
+                }),
+            )
+        }
+        Ok(None) => {
+            error!(
+                "Conflict on initial upload for user {}: user data already exists",
+                &alias
+            );
+            upload_session_status(Status::Conflict, "existing")
+        }
+        Err(e) => {
+            error!("Failed to start upload for user {}: {:?}", &alias, e);
+            upload_session_status(Status::InternalServerError, "failed")
+        }
+    }
+}
+
+/// Start a resumable upload of a store that replaces the current one
+///
+/// The upload is passed as `upload_id` to `/user/rekey` or `/user/reset` once it has
+/// received every chunk, instead of sending the whole store with them.
+#[post("/user/upload/begin")]
+pub fn begin_replacement_upload(
+    admin: AccountAdmin,
+    db: &State<Databases>,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    let AccountAdmin(key) = admin;
+    let alias = key.alias;
+    match db.store.begin_upload(&alias) {
+        Ok(upload_id) => {
+            info!(
+                "Started replacement upload {} for user {}",
+                &upload_id, &alias
+            );
+            status::Custom(
+                Status::Ok,
+                Wire(UploadSessionResponse {
+                    status: "success".into(),
+                    upload_id: Some(upload_id),
+                    received: Some(0),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to start upload for user {}: {:?}", &alias, e);
+            upload_session_status(Status::InternalServerError, "failed")
+        }
+    }
+}
+
+/// Add a chunk of credentials to an upload
+///
+/// Chunks may be resent after a failure, credentials with the same id are replaced.
+#[put("/init/upload/<upload_id>", data = "<data>")]
+pub fn upload_chunk(
+    writer: Writer,
+    db: &State<Databases>,
+    upload_id: &str,
+    data: Wire<Vec<Credential>>,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    let Writer(key) = writer;
+    let alias = key.alias;
+    let result = db.store.stage_upload(&alias, upload_id, &data);
+    upload_session_response(&alias, upload_id, result)
+}
+
+/// Check how many credentials an upload has received, to resume after an interruption
+#[get("/init/upload/<upload_id>")]
+pub fn upload_progress(
+    writer: Writer,
+    db: &State<Databases>,
+    upload_id: &str,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    let Writer(key) = writer;
+    let alias = key.alias;
+    let result = db.store.upload_size(&alias, upload_id);
+    upload_session_response(&alias, upload_id, result)
+}
+
+/// Move everything received by an upload into the store
+#[post("/init/upload/<upload_id>/finish")]
+pub fn finish_upload(
+    writer: Writer,
+    audit: Audit,
+    db: &State<Databases>,
+    upload_id: &str,
+) -> status::Custom<Wire<InitUploadResponse>> {
+    let Writer(key) = writer;
+    let alias = &key.alias;
+    let result = db.cache.finish_upload(alias, upload_id);
+    let response = |status: Status, state_id: Option<String>, message: &str| {
+        status::Custom(
+            status,
+            Wire(InitUploadResponse {
+                state_id,
+                status: message.into(),
+            }),
+        )
+    };
+    match result {
+        Ok((state_id, count)) => {
+            info!("Finished upload {} for user {}", upload_id, &alias);
+            audit.record(AuditEntry {
+                mutations: Some(count),
+                state_id: Some(state_id.to_owned()),
+                ..audit.entry(AuditAction::Upload, &key)
+            });
+            response(Status::Ok, Some(state_id), "success")
+        }
+        Err(Error::MissingUpload(_)) => response(Status::NotFound, None, "missing"),
+        Err(Error::ExistingUser(_)) => {
+            error!(
+                "Conflict on initial upload for user {}: user data already exists",
+                &alias
+            );
+            response(Status::Conflict, None, "existing")
+        }
+        Err(e) => {
+            error!("Failed to finish upload for user {}: {:?}", &alias, e);
+            response(Status::InternalServerError, None, "failed")
+        }
+    }
+}
+
+/// Seconds after which an unfinished upload is forgotten
+pub const UPLOAD_LIFETIME: u64 = 24 * 3600;
+
+/// Forget the uploads of every user begun more than [`UPLOAD_LIFETIME`] before `now`
+pub fn expire_stale_uploads(store: &dyn StoreDatabase, user: &dyn UserDatabase, now: u64) {
+    let accounts = match user.list_accounts() {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            error!("Failed to list users to expire uploads: {:?}", e);
+            return;
+        }
+    };
+    for account in accounts {
+        match store.expire_uploads(&account.alias, now.saturating_sub(UPLOAD_LIFETIME)) {
+            Ok(0) => {}
+            Ok(expired) => info!(
+                "Expired {} unfinished uploads of user {}",
+                expired, &account.alias
+            ),
+            Err(e) => error!(
+                "Failed to expire uploads of user {}: {:?}",
+                &account.alias, e
+            ),
+        }
+    }
+}
+
+fn is_uninitialized(alias: &str, db: &State<Databases>) -> Result<bool> {
+    Ok(db.store.is_empty(alias)? && db.cache.is_empty(alias)?)
+}
+
+fn upload_session_status(
+    status: Status,
+    message: &str,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    status::Custom(
+        status,
+        Wire(UploadSessionResponse {
+            status: message.into(),
+            ..Default::default()
+        }),
+    )
+}
+
+fn upload_session_response(
+    alias: &str,
+    upload_id: &str,
+    result: GenericResult<u64>,
+) -> status::Custom<Wire<UploadSessionResponse>> {
+    match result {
+        Ok(received) => status::Custom(
+            Status::Ok,
+            Wire(UploadSessionResponse {
+                status: "success".into(),
+                upload_id: Some(upload_id.into()),
+                received: Some(received),
+            }),
+        ),
+        Err(Error::MissingUpload(_)) => {
+            warn!("Upload {} does not exist for user {}", upload_id, alias);
+            upload_session_status(Status::NotFound, "missing")
+        }
+        Err(e) => {
+            error!("Failed to upload chunk for user {}: {:?}", alias, e);
+            upload_session_status(Status::InternalServerError, "failed")
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
@@ -81,27 +321,28 @@ mod test {
         http::{Header, Status},
         local::blocking::Client,
     };
+    use serde_json::json;
 
     use crate::{
         api::{
-            db_types::Credential, endpoints::init_upload::InitUploadResponse, server::build_server,
+            db_types::Credential,
+            endpoints::init_upload::{InitUploadResponse, UploadSessionResponse},
+            server::build_server,
         },
         config::parse_config::{Config, User},
+        database::sqlite::SqliteDatabase,
+        util::{key::hash_key, session::now_secs},
     };
 
+    use super::{expire_stale_uploads, UPLOAD_LIFETIME};
+
     fn init_test_config(dir: &str) -> Config {
-        if Path::new(dir).exists() {
-            std::fs::remove_dir_all(dir).expect("Remove test data directory");
-        }
-        std::fs::create_dir_all(dir).expect("Create test data directory");
         Config {
             users: vec![User {
                 alias: "unit".into(),
-                keys: vec!["unit".into()],
+                keys: vec![hash_key("unit").unwrap()],
             }],
-            cache_count: 50,
-            db_directory: dir.into(),
-            enable_test_routes: false,
+            ..Config::in_test_directory(dir)
         }
     }
 
@@ -159,4 +400,109 @@ mod test {
         assert_eq!(body.status, "existing".to_string());
         assert!(body.state_id.is_none());
     }
+
+    #[test]
+    fn chunked_upload() {
+        let config = init_test_config("test/init_upload/chunked");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::begin_upload))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: UploadSessionResponse = response.into_json().unwrap();
+        let upload_id = body.upload_id.expect("Upload id");
+
+        let chunks = [
+            json!([{"id": "a", "value": "1"}, {"id": "b", "value": "2"}]),
+            json!([{"id": "c", "value": "3"}]),
+            // Resent after a dropped response
+            json!([{"id": "c", "value": "3"}]),
+        ];
+        for chunk in chunks {
+            let response = client
+                .put(uri!(super::upload_chunk(&upload_id)))
+                .header(Header::new("Authentication", "unit"))
+                .body(chunk.to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let response = client
+            .get(uri!(super::upload_progress(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        let body: UploadSessionResponse = response.into_json().unwrap();
+        assert_eq!(body.received, Some(3));
+
+        let response = client
+            .post(uri!(super::finish_upload(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: InitUploadResponse = response.into_json().unwrap();
+        assert_eq!(body.status, "success".to_string());
+        assert!(body.state_id.is_some());
+
+        let response = client
+            .post(uri!(super::begin_upload))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn missing_upload() {
+        let config = init_test_config("test/init_upload/missing_upload");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .put(uri!(super::upload_chunk("missing")))
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([{"id": "a", "value": "1"}]).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(uri!(super::finish_upload("missing")))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn stale_upload_expired() {
+        let dir = "test/init_upload/stale_upload";
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::begin_upload))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        let body: UploadSessionResponse = response.into_json().unwrap();
+        let upload_id = body.upload_id.expect("Upload id");
+        let _chunk = client
+            .put(uri!(super::upload_chunk(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([{"id": "a", "value": "1"}]).to_string())
+            .dispatch();
+
+        let db = SqliteDatabase::new(dir);
+        expire_stale_uploads(&db, &db, now_secs());
+        let response = client
+            .get(uri!(super::upload_progress(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        expire_stale_uploads(&db, &db, now_secs() + UPLOAD_LIFETIME + 1);
+        let response = client
+            .get(uri!(super::upload_progress(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(uri!(super::finish_upload(&upload_id)))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
 }
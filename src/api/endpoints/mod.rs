@@ -1,5 +1,15 @@
+pub mod account;
+pub mod admin;
+pub mod audit;
+pub mod devices;
+pub mod events;
+pub mod export;
 pub mod init;
 pub mod init_import;
 pub mod init_upload;
+pub mod rekey;
+pub mod reset;
+pub mod session;
 pub mod sync;
 pub mod test_reset;
+pub mod totp;
@@ -0,0 +1,310 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        catchers::TooManyRequests,
+        db_types::{AuditAction, AuditEntry, Credential, NewStore},
+        endpoints::session::{check_proof, ProofCheck},
+        guards::{audit::Audit, user::AccountAdmin},
+        wire::Wire,
+    },
+    config::parse_config::Config,
+    database::traits::Databases,
+    util::{error::Error, proof::Challenges},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct RekeyRequest {
+    /// Challenge from `/auth/challenge`, answered with the current master password
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub proof: Option<String>,
+    /// State the store was re-encrypted from
+    pub state_id: String,
+    pub salt: String,
+    pub hash: String,
+    /// Entire store encrypted with the new master password
+    #[serde(default)]
+    pub store: Vec<Credential>,
+    /// Upload from `/user/upload/begin` that received the store instead, for stores too
+    /// large for one request
+    #[serde(default)]
+    pub upload_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct RekeyResponse {
+    pub status: String,
+    pub state_id: Option<String>,
+}
+
+/// Change the master password, proving the current one, replacing the salt, hash and
+/// the whole store at once
+///
+/// Every previous state id is forgotten, so other devices get the entire store on their
+/// next sync. Rejected with status `stale` if the vault changed after `state_id`.
+#[post("/user/rekey", data = "<data>")]
+pub fn rekey_user(
+    admin: AccountAdmin,
+    audit: Audit,
+    config: &State<Config>,
+    db: &State<Databases>,
+    challenges: &State<Challenges>,
+    data: Wire<RekeyRequest>,
+) -> Result<status::Custom<Wire<RekeyResponse>>, TooManyRequests> {
+    let AccountAdmin(key) = admin;
+    let alias = &key.alias;
+    let response = |status: Status, message: &str, state_id: Option<String>| {
+        Ok(status::Custom(
+            status,
+            Wire(RekeyResponse {
+                status: message.into(),
+                state_id,
+            }),
+        ))
+    };
+    match db.user.get_user(alias) {
+        Ok(_) => {
+            let (Some(challenge), Some(proof)) = (&data.challenge, &data.proof) else {
+                warn!(
+                    "Device {} tried to rekey user {} without a proof",
+                    &key.id, alias
+                );
+                return response(Status::Forbidden, "invalid", None);
+            };
+            match check_proof(&key, &audit, config, db, challenges, challenge, proof) {
+                ProofCheck::Valid => {}
+                ProofCheck::Invalid => return response(Status::Forbidden, "invalid", None),
+                ProofCheck::LockedOut(retry_after) => {
+                    return Err(TooManyRequests::new(retry_after))
+                }
+                ProofCheck::Failed => return response(Status::InternalServerError, "failed", None),
+            }
+        }
+        Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::UninitializedUser(_))) => {
+            return response(Status::Conflict, "uninitialized", None)
+        }
+        Err(e) => {
+            error!("Failed to look up user {}: {:?}", alias, e);
+            return response(Status::InternalServerError, "failed", None);
+        }
+    }
+    let store = NewStore::of_request(&data.store, data.upload_id.as_deref());
+    match db
+        .cache
+        .replace_vault(alias, &data.state_id, &data.salt, &data.hash, store)
+    {
+        Ok((state_id, count)) => {
+            info!("Replaced master password and store of user {}", &alias);
+            audit.record(AuditEntry {
+                mutations: Some(count),
+                state_id: Some(state_id.to_owned()),
+                ..audit.entry(AuditAction::Rekey, &key)
+            });
+            response(Status::Ok, "success", Some(state_id))
+        }
+        Err(Error::StaleState(_)) => {
+            warn!(
+                "Rejected rekey of user {} from outdated state {}",
+                &alias, &data.state_id
+            );
+            response(Status::Conflict, "stale", None)
+        }
+        Err(Error::UninitializedUser(_)) => response(Status::Conflict, "uninitialized", None),
+        Err(Error::MissingUpload(_)) => response(Status::NotFound, "missing", None),
+        Err(e) => {
+            error!("Failed to rekey user {}: {:?}", &alias, e);
+            response(Status::InternalServerError, "failed", None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{
+            endpoints::{
+                export::ExportResponse, init_import::UserImportResponse,
+                init_upload::InitUploadResponse, session::ChallengeResponse, sync::SyncResponse,
+            },
+            server::build_server,
+        },
+        config::parse_config::{Config, User},
+        util::{key::hash_key, proof::proof},
+    };
+
+    use super::RekeyResponse;
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("unit").unwrap()],
+            }],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn auth_header() -> Header<'static> {
+        Header::new("Authentication", "unit")
+    }
+
+    /// Initialize the user with two credentials, returning the state id
+    fn init_user(client: &Client) -> String {
+        let _init = client
+            .post("/user/init")
+            .header(auth_header())
+            .body(json!({"salt": "old salt", "hash": "old hash"}).to_string())
+            .dispatch();
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(
+                json!([
+                    {"id": "first", "value": "old"},
+                    {"id": "second", "value": "old"}
+                ])
+                .to_string(),
+            )
+            .dispatch();
+        let body: InitUploadResponse = response.into_json().unwrap();
+        body.state_id.unwrap()
+    }
+
+    /// Rekey from `state_id`, proving the master password with `hash` if given
+    fn rekey(client: &Client, state_id: &str, hash: Option<&str>) -> (Status, RekeyResponse) {
+        let mut body = json!({
+            "state_id": state_id,
+            "salt": "new salt",
+            "hash": "new hash",
+            "store": [{"id": "first", "value": "new"}]
+        });
+        if let Some(hash) = hash {
+            let response = client
+                .post("/auth/challenge")
+                .header(auth_header())
+                .dispatch();
+            let challenge = response.into_json::<ChallengeResponse>().unwrap().challenge;
+            let challenge = challenge.unwrap();
+            body["proof"] = proof(hash, &challenge).into();
+            body["challenge"] = challenge.into();
+        }
+        let response = client
+            .post(uri!(super::rekey_user))
+            .header(auth_header())
+            .body(body.to_string())
+            .dispatch();
+        (response.status(), response.into_json().unwrap())
+    }
+
+    fn salt(client: &Client) -> String {
+        let response = client.get("/user/import").header(auth_header()).dispatch();
+        let body: UserImportResponse = response.into_json().unwrap();
+        body.salt.unwrap()
+    }
+
+    #[test]
+    fn rekeyed() {
+        let config = init_test_config("test/rekey/rekeyed");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let old_state = init_user(&client);
+        let (status, body) = rekey(&client, &old_state, Some("old hash"));
+        assert_eq!(status, Status::Ok);
+        let new_state = body.state_id.unwrap();
+        assert_eq!(salt(&client), "new salt");
+
+        // Devices on the old state get the whole new store
+        let response = client
+            .post("/sync")
+            .header(auth_header())
+            .body(json!({"state_id": &old_state, "mutations": []}).to_string())
+            .dispatch();
+        let body: SyncResponse = response.into_json().unwrap();
+        let store = body.store.unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store[0].value, "new");
+
+        // The new state is up to date
+        let response = client
+            .post("/sync")
+            .header(auth_header())
+            .body(json!({"state_id": &new_state, "mutations": []}).to_string())
+            .dispatch();
+        let body: SyncResponse = response.into_json().unwrap();
+        assert!(body.store.is_none());
+        assert!(body.mutations.unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn wrong_proof() {
+        let config = init_test_config("test/rekey/wrong_proof");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let state = init_user(&client);
+        for hash in [None, Some("wrong hash")] {
+            let (status, body) = rekey(&client, &state, hash);
+            assert_eq!(status, Status::Forbidden);
+            assert_eq!(body.status, "invalid");
+        }
+        assert_eq!(salt(&client), "old salt");
+        let response = client.get("/export").header(auth_header()).dispatch();
+        let body: ExportResponse = response.into_json().unwrap();
+        assert_eq!(body.credentials.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stale_state() {
+        let config = init_test_config("test/rekey/stale_state");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let old_state = init_user(&client);
+        let _sync = client
+            .post("/sync")
+            .header(auth_header())
+            .body(
+                json!({
+                    "state_id": &old_state,
+                    "mutations": [
+                        {"type": "add", "credential": {"id": "third", "value": "old"}}
+                    ]
+                })
+                .to_string(),
+            )
+            .dispatch();
+
+        let (status, body) = rekey(&client, &old_state, Some("old hash"));
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(body.status, "stale");
+        assert_eq!(salt(&client), "old salt");
+        let response = client.get("/export").header(auth_header()).dispatch();
+        let body: ExportResponse = response.into_json().unwrap();
+        assert_eq!(body.credentials.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn uninitialized() {
+        let config = init_test_config("test/rekey/uninitialized");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(json!([{"id": "first", "value": "old"}]).to_string())
+            .dispatch();
+        let body: InitUploadResponse = response.into_json().unwrap();
+        let (status, body) = rekey(&client, &body.state_id.unwrap(), None);
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(body.status, "uninitialized");
+        let response = client.get("/export").header(auth_header()).dispatch();
+        let body: ExportResponse = response.into_json().unwrap();
+        assert_eq!(body.credentials.unwrap()[0].value, "old");
+    }
+}
@@ -0,0 +1,262 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        db_types::{AuditAction, AuditEntry, Credential, NewStore},
+        guards::{audit::Audit, user::AccountAdmin},
+        wire::Wire,
+    },
+    database::traits::Databases,
+    util::error::Error,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequest {
+    /// State the device last synced to, if it still knows it
+    #[serde(default)]
+    pub state_id: Option<String>,
+    /// Entire store to replace the vault with
+    #[serde(default)]
+    pub store: Vec<Credential>,
+    /// Upload from `/user/upload/begin` that received the store instead, for stores too
+    /// large for one request
+    #[serde(default)]
+    pub upload_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct ResetResponse {
+    pub status: String,
+    pub state_id: Option<String>,
+}
+
+/// Replace the whole store, keeping the master password and devices
+///
+/// Meant for a device whose local state is broken to upload its store from scratch.
+/// Every previous state id is forgotten, so other devices get the entire store on their
+/// next sync. Rejected with status `stale` if a `state_id` is given and the vault
+/// changed after it.
+#[post("/user/reset", data = "<data>")]
+pub fn reset_vault(
+    admin: AccountAdmin,
+    audit: Audit,
+    db: &State<Databases>,
+    data: Wire<ResetRequest>,
+) -> status::Custom<Wire<ResetResponse>> {
+    let AccountAdmin(key) = admin;
+    let alias = &key.alias;
+    let response = |status: Status, message: &str, state_id: Option<String>| {
+        status::Custom(
+            status,
+            Wire(ResetResponse {
+                status: message.into(),
+                state_id,
+            }),
+        )
+    };
+    let store = NewStore::of_request(&data.store, data.upload_id.as_deref());
+    match db.cache.reset_vault(alias, data.state_id.as_deref(), store) {
+        Ok((state_id, count)) => {
+            info!("Reset store of user {}", &alias);
+            audit.record(AuditEntry {
+                mutations: Some(count),
+                state_id: Some(state_id.to_owned()),
+                ..audit.entry(AuditAction::ResetVault, &key)
+            });
+            response(Status::Ok, "success", Some(state_id))
+        }
+        Err(Error::StaleState(state_id)) => {
+            warn!(
+                "Rejected reset of user {} from outdated state {}",
+                &alias, &state_id
+            );
+            response(Status::Conflict, "stale", None)
+        }
+        Err(Error::MissingUpload(_)) => response(Status::NotFound, "missing", None),
+        Err(e) => {
+            error!("Failed to reset store of user {}: {:?}", &alias, e);
+            response(Status::InternalServerError, "failed", None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::{
+            endpoints::{
+                devices::DevicesResponse,
+                init_import::UserImportResponse,
+                init_upload::{InitUploadResponse, UploadSessionResponse},
+                sync::SyncResponse,
+            },
+            server::build_server,
+        },
+        config::parse_config::{Config, User},
+        util::key::hash_key,
+    };
+
+    use super::ResetResponse;
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("phone").unwrap(), hash_key("laptop").unwrap()],
+            }],
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn auth_header(key: &str) -> Header<'static> {
+        Header::new("Authentication", key.to_string())
+    }
+
+    /// Initialize the user with two credentials, returning the state id
+    fn init_user(client: &Client) -> String {
+        let _init = client
+            .post("/user/init")
+            .header(auth_header("phone"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        let response = client
+            .post("/init/upload")
+            .header(auth_header("phone"))
+            .body(
+                json!([
+                    {"id": "first", "value": "old"},
+                    {"id": "second", "value": "old"}
+                ])
+                .to_string(),
+            )
+            .dispatch();
+        let body: InitUploadResponse = response.into_json().unwrap();
+        body.state_id.unwrap()
+    }
+
+    fn reset(client: &Client, body: Value) -> (Status, ResetResponse) {
+        let response = client
+            .post(uri!(super::reset_vault))
+            .header(auth_header("phone"))
+            .body(body.to_string())
+            .dispatch();
+        (response.status(), response.into_json().unwrap())
+    }
+
+    #[test]
+    fn replaced() {
+        let config = init_test_config("test/reset/replaced");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let old_state = init_user(&client);
+        let (status, body) = reset(&client, json!({"store": [{"id": "first", "value": "new"}]}));
+        assert_eq!(status, Status::Ok);
+        assert!(body.state_id.is_some());
+
+        // Other devices on the old state get the whole new store
+        let response = client
+            .post("/sync")
+            .header(auth_header("laptop"))
+            .body(json!({"state_id": &old_state, "mutations": []}).to_string())
+            .dispatch();
+        let body: SyncResponse = response.into_json().unwrap();
+        let store = body.store.unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store[0].value, "new");
+
+        // The master password and devices are kept
+        let response = client
+            .get("/user/import")
+            .header(auth_header("laptop"))
+            .dispatch();
+        let body: UserImportResponse = response.into_json().unwrap();
+        assert_eq!(body.salt.unwrap(), "salt");
+        let response = client
+            .get("/devices")
+            .header(auth_header("phone"))
+            .dispatch();
+        let body: DevicesResponse = response.into_json().unwrap();
+        assert_eq!(body.devices.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stale_state() {
+        let config = init_test_config("test/reset/stale_state");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let old_state = init_user(&client);
+        let _sync = client
+            .post("/sync")
+            .header(auth_header("laptop"))
+            .body(
+                json!({
+                    "state_id": &old_state,
+                    "mutations": [
+                        {"type": "add", "credential": {"id": "third", "value": "old"}}
+                    ]
+                })
+                .to_string(),
+            )
+            .dispatch();
+
+        let (status, body) = reset(&client, json!({"state_id": &old_state, "store": []}));
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(body.status, "stale");
+        let response = client
+            .get("/export")
+            .header(auth_header("phone"))
+            .dispatch();
+        let body: Value = response.into_json().unwrap();
+        assert_eq!(body["credentials"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn replaced_by_upload() {
+        let config = init_test_config("test/reset/replaced_by_upload");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        init_user(&client);
+        let response = client
+            .post("/user/upload/begin")
+            .header(auth_header("phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: UploadSessionResponse = response.into_json().unwrap();
+        let upload_id = body.upload_id.expect("Upload id");
+        for chunk in [
+            json!([{"id": "a", "value": "new"}, {"id": "b", "value": "new"}]),
+            json!([{"id": "c", "value": "new"}]),
+        ] {
+            let response = client
+                .put(format!("/init/upload/{}", &upload_id))
+                .header(auth_header("phone"))
+                .body(chunk.to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let (status, body) = reset(&client, json!({"upload_id": &upload_id}));
+        assert_eq!(status, Status::Ok);
+        assert!(body.state_id.is_some());
+        let response = client
+            .get("/export")
+            .header(auth_header("laptop"))
+            .dispatch();
+        let body: Value = response.into_json().unwrap();
+        let credentials = body["credentials"].as_array().unwrap();
+        assert_eq!(credentials.len(), 3);
+        assert!(credentials.iter().all(|c| c["value"] == "new"));
+
+        // The upload is used up by the reset
+        let (status, body) = reset(&client, json!({"upload_id": &upload_id}));
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(body.status, "missing");
+    }
+}
@@ -0,0 +1,626 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        catchers::TooManyRequests,
+        db_types::{ApiKey, AuditAction},
+        guards::{
+            audit::Audit,
+            user::{DeviceKey, Enrolled, LoginKey},
+        },
+        rate_limit::{user_subject, RateLimiter},
+    },
+    config::parse_config::Config,
+    database::traits::Databases,
+    util::{
+        error::Error,
+        proof::{verify_proof, Challenges},
+        session::SessionSigner,
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct LoginResponse {
+    pub status: String,
+    pub token: Option<String>,
+    /// Expiry of the token in seconds since the Unix epoch
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct ChallengeResponse {
+    pub status: String,
+    pub challenge: Option<String>,
+    /// Salt the client derives the hash of the master password with
+    pub salt: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProveRequest {
+    pub challenge: String,
+    pub proof: String,
+}
+
+/// Exchange a device key for a short-lived session token
+///
+/// The token is used as a bearer token in place of the key until it expires, and stops
+/// working when the key is revoked.
+#[post("/auth/login")]
+pub fn login(
+    key: LoginKey,
+    config: &State<Config>,
+    signer: &State<SessionSigner>,
+) -> status::Custom<Json<LoginResponse>> {
+    let LoginKey(key) = key;
+    let (token, expires) = signer.issue(&key.id, config.auth.session_lifetime, false);
+    info!(
+        "Started session for device {} of user {}",
+        &key.id, &key.alias
+    );
+    status::Custom(
+        Status::Ok,
+        Json(LoginResponse {
+            status: "success".into(),
+            token: Some(token),
+            expires: Some(expires),
+        }),
+    )
+}
+
+/// Start proving knowledge of the master password
+///
+/// Responds with a single-use challenge valid for a minute and the user's salt. Devices
+/// of users that set up TOTP have to be enrolled, as the salt is revealed.
+#[post("/auth/challenge")]
+pub fn challenge(
+    key: DeviceKey,
+    _enrolled: Enrolled,
+    audit: Audit,
+    db: &State<Databases>,
+    challenges: &State<Challenges>,
+) -> status::Custom<Json<ChallengeResponse>> {
+    let DeviceKey(key) = key;
+    let response = |status: Status, message: &str| {
+        status::Custom(
+            status,
+            Json(ChallengeResponse {
+                status: message.into(),
+                ..Default::default()
+            }),
+        )
+    };
+    match db.user.get_user(&key.alias) {
+        Ok((salt, _)) => {
+            audit.record(audit.entry(AuditAction::SaltFetch, &key));
+            status::Custom(
+                Status::Ok,
+                Json(ChallengeResponse {
+                    status: "success".into(),
+                    challenge: Some(challenges.issue(&key.id)),
+                    salt: Some(salt),
+                }),
+            )
+        }
+        Err(e) => match e.downcast_ref::<Error>() {
+            Some(Error::UninitializedUser(_)) => response(Status::Conflict, "uninitialized"),
+            _ => {
+                error!("Failed to look up user {}: {:?}", &key.alias, e);
+                response(Status::InternalServerError, "failed")
+            }
+        },
+    }
+}
+
+/// Outcome of checking a proof of the master password
+pub enum ProofCheck {
+    Valid,
+    /// Wrong proof, or a challenge that is unknown, expired or issued to another device
+    Invalid,
+    /// The user is locked out for this many seconds
+    LockedOut(u64),
+    Failed,
+}
+
+/// Check a proof of the master password of the user of `key` answering `challenge`
+///
+/// Wrong proofs count against the user rather than the device, so guessing the
+/// password from several devices still gets the user locked out.
+pub fn check_proof(
+    key: &ApiKey,
+    audit: &Audit,
+    config: &Config,
+    db: &Databases,
+    challenges: &Challenges,
+    challenge: &str,
+    proof: &str,
+) -> ProofCheck {
+    let limiter = RateLimiter::new(&config.rate_limit, db.user.as_ref());
+    let subject = user_subject(&key.alias);
+    match limiter.locked_out(&subject) {
+        Ok(Some(retry_after)) => {
+            warn!(
+                "Device {} tried to prove while user {} is locked out",
+                &key.id, &key.alias
+            );
+            return ProofCheck::LockedOut(retry_after);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to check rate limit of {}: {:?}", &subject, e);
+            return ProofCheck::Failed;
+        }
+    }
+    if !challenges.take(challenge, &key.id) {
+        warn!(
+            "Device {} answered an unknown or expired challenge",
+            &key.id
+        );
+        return ProofCheck::Invalid;
+    }
+    let hash = match db.user.get_user(&key.alias) {
+        Ok((_, hash)) => hash,
+        Err(e) => {
+            error!("Failed to look up user {}: {:?}", &key.alias, e);
+            return ProofCheck::Failed;
+        }
+    };
+    if !verify_proof(&hash, challenge, proof) {
+        warn!(
+            "Device {} sent a wrong proof for user {}",
+            &key.id, &key.alias
+        );
+        if let Err(e) = limiter.fail(&subject) {
+            error!("Failed to record authentication failure: {:?}", e);
+        }
+        audit.record(audit.entry(AuditAction::ProofFailure, key));
+        return ProofCheck::Invalid;
+    }
+    if let Err(e) = limiter.succeed(&subject) {
+        error!("Failed to clear authentication failures: {:?}", e);
+    }
+    ProofCheck::Valid
+}
+
+/// Answer a challenge with HMAC-SHA256 of it keyed with the hash of the master password
+///
+/// A correct proof starts a session that can access the vault when the config requires
+/// proof. The server only ever sees the hash, never the password.
+#[post("/auth/prove", data = "<data>")]
+pub fn prove(
+    key: DeviceKey,
+    audit: Audit,
+    config: &State<Config>,
+    db: &State<Databases>,
+    signer: &State<SessionSigner>,
+    challenges: &State<Challenges>,
+    data: Json<ProveRequest>,
+) -> Result<status::Custom<Json<LoginResponse>>, TooManyRequests> {
+    let DeviceKey(key) = key;
+    let response = |status: Status, message: &str| {
+        Ok(status::Custom(
+            status,
+            Json(LoginResponse {
+                status: message.into(),
+                ..Default::default()
+            }),
+        ))
+    };
+    match check_proof(
+        &key,
+        &audit,
+        config,
+        db,
+        challenges,
+        &data.challenge,
+        &data.proof,
+    ) {
+        ProofCheck::Valid => {}
+        ProofCheck::Invalid => return response(Status::Forbidden, "invalid"),
+        ProofCheck::LockedOut(retry_after) => return Err(TooManyRequests::new(retry_after)),
+        ProofCheck::Failed => return response(Status::InternalServerError, "failed"),
+    }
+    let (token, expires) = signer.issue(&key.id, config.auth.session_lifetime, true);
+    info!(
+        "Started proven session for device {} of user {}",
+        &key.id, &key.alias
+    );
+    Ok(status::Custom(
+        Status::Ok,
+        Json(LoginResponse {
+            status: "success".into(),
+            token: Some(token),
+            expires: Some(expires),
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+
+    use crate::{
+        api::{
+            endpoints::devices::DevicesResponse,
+            server::{build_server, build_server_with_state, ServerState},
+        },
+        config::parse_config::{Config, User},
+        util::{
+            key::{generate_key, hash_key},
+            proof::proof,
+        },
+    };
+
+    use super::{ChallengeResponse, LoginResponse};
+
+    fn users() -> Vec<User> {
+        vec![User {
+            alias: "unit".into(),
+            keys: vec![hash_key("phone").unwrap(), hash_key("laptop").unwrap()],
+        }]
+    }
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: users(),
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    /// Config using the data directory as it is, to restart a server on it
+    fn test_config(dir: &str) -> Config {
+        Config {
+            users: users(),
+            db_directory: dir.into(),
+            ..Default::default()
+        }
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    fn login(client: &Client, key: &str) -> LoginResponse {
+        let response = client
+            .post(uri!(super::login))
+            .header(bearer(key))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    fn session_token() {
+        let config = init_test_config("test/session/session_token");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let session = login(&client, "phone");
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Sessions cannot be extended by logging in with them
+        let response = client
+            .post(uri!(super::login))
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn revoked_key_ends_session() {
+        let config = init_test_config("test/session/revoked_key_ends_session");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let session = login(&client, "phone");
+        let response = client
+            .get("/devices")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        let phone = response
+            .into_json::<DevicesResponse>()
+            .unwrap()
+            .current
+            .unwrap();
+        let response = client
+            .delete(format!("/devices/{}", phone))
+            .header(bearer("laptop"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(r#"Bearer realm="vult", error="invalid_token""#)
+        );
+    }
+
+    #[test]
+    fn expired_session() {
+        let mut config = init_test_config("test/session/expired_session");
+        config.auth.session_lifetime = 0;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let session = login(&client, "phone");
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn legacy_header_disabled() {
+        let mut config = init_test_config("test/session/legacy_header_disabled");
+        config.auth.legacy_header = false;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", "phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client.get("/export").header(bearer("phone")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    fn challenge(client: &Client, key: &str) -> ChallengeResponse {
+        let response = client
+            .post(uri!(super::challenge))
+            .header(bearer(key))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    fn proof_required() {
+        let mut config = init_test_config("test/session/proof_required");
+        config.auth.require_proof = true;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        // Nothing to prove before the master password is set
+        let response = client
+            .post("/user/init")
+            .header(bearer("phone"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/export").header(bearer("phone")).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let session = login(&client, "phone");
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let body = challenge(&client, "phone");
+        assert_eq!(body.salt.as_deref(), Some("salt"));
+        let challenge_id = body.challenge.unwrap();
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("phone"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("hash", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let session: LoginResponse = response.into_json().unwrap();
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Challenges can only be answered once
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("phone"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("hash", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn wrong_proof() {
+        let mut config = init_test_config("test/session/wrong_proof");
+        config.auth.require_proof = true;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/user/init")
+            .header(bearer("phone"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+
+        let challenge_id = challenge(&client, "phone").challenge.unwrap();
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("phone"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("guess", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // Challenges are bound to the device they were issued to
+        let challenge_id = challenge(&client, "phone").challenge.unwrap();
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("laptop"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("hash", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn survives_relaunch() {
+        let dir = "test/session/survives_relaunch";
+        let config = init_test_config(dir);
+        let state = ServerState::new();
+        let (session, challenge_id) = {
+            let client = Client::tracked(build_server_with_state(config, state.clone()))
+                .expect("Valid rocket instance");
+            let response = client
+                .post("/user/init")
+                .header(bearer("phone"))
+                .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let session = login(&client, "phone");
+            (session, challenge(&client, "phone").challenge.unwrap())
+        };
+
+        // A reload builds the server again with the same state
+        let client = Client::tracked(build_server_with_state(test_config(dir), state))
+            .expect("Valid rocket instance");
+        let response = client
+            .get("/export")
+            .header(bearer(session.token.as_ref().unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("phone"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("hash", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn key_lockout() {
+        let dir = "test/session/key_lockout";
+        let mut config = init_test_config(dir);
+        config.rate_limit.max_failures = 3;
+        {
+            let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+            for _ in 0..3 {
+                let response = client.get("/export").header(bearer("guess")).dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+            }
+            // Even a valid key is refused while locked out
+            let response = client.get("/export").header(bearer("phone")).dispatch();
+            assert_eq!(response.status(), Status::TooManyRequests);
+            let retry_after: u64 = response
+                .headers()
+                .get_one("Retry-After")
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(retry_after > 0 && retry_after <= 30);
+        }
+
+        // Lockouts survive a restart
+        let client =
+            Client::tracked(build_server(test_config(dir))).expect("Valid rocket instance");
+        let response = client.get("/export").header(bearer("phone")).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn key_lockout_by_user() {
+        let mut config = init_test_config("test/session/key_lockout_by_user");
+        config.rate_limit.max_failures = 3;
+        let key = generate_key();
+        config.users[0].keys.push(hash_key(&key).unwrap());
+        let (id, _) = key.split_once('.').unwrap();
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let remote = |ip: &str| format!("{}:8000", ip).parse().unwrap();
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            let response = client
+                .get("/export")
+                .header(bearer(&format!("{}.guess", id)))
+                .remote(remote(ip))
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        // Guesses at the key of the user from any address lock out its key
+        let response = client
+            .get("/export")
+            .header(bearer(&key))
+            .remote(remote("10.0.0.4"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client
+            .get("/export")
+            .header(bearer("phone"))
+            .remote(remote("10.0.0.1"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn rate_limit_disabled() {
+        let mut config = init_test_config("test/session/rate_limit_disabled");
+        config.rate_limit.enabled = false;
+        config.rate_limit.max_failures = 1;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        for _ in 0..3 {
+            let response = client.get("/export").header(bearer("guess")).dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+        let response = client.get("/export").header(bearer("phone")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn proof_lockout() {
+        let mut config = init_test_config("test/session/proof_lockout");
+        config.rate_limit.max_failures = 2;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/user/init")
+            .header(bearer("phone"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        for _ in 0..2 {
+            let challenge_id = challenge(&client, "phone").challenge.unwrap();
+            let response = client
+                .post(uri!(super::prove))
+                .header(bearer("phone"))
+                .body(
+                    json!({"challenge": &challenge_id, "proof": proof("guess", &challenge_id)})
+                        .to_string(),
+                )
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+
+        // The user is locked out on every device, even with the right proof
+        let challenge_id = challenge(&client, "laptop").challenge.unwrap();
+        let response = client
+            .post(uri!(super::prove))
+            .header(bearer("laptop"))
+            .body(
+                json!({"challenge": &challenge_id, "proof": proof("hash", &challenge_id)})
+                    .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert!(response.headers().get_one("Retry-After").is_some());
+        // Keys still work for everything else
+        let response = client.get("/export").header(bearer("laptop")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
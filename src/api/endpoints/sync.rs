@@ -1,17 +1,20 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{Context, Result};
 use log::{error, info, trace, warn};
-use rocket::{http::Status, response::status, serde::json::Json, State};
+use rocket::{http::Status, response::status, State};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     api::{
-        db_types::{Credential, Mutation},
-        guards::user::User,
+        db_types::{AuditAction, AuditEntry, Credential, Mutation, Scope},
+        endpoints::export::rocket_uri_macro_export_store,
+        guards::{audit::Audit, user::Device},
+        wire::Wire,
     },
+    config::parse_config::Config,
     database::traits::Databases,
-    util::error::Error,
+    util::{error::Error, session::now_secs},
 };
 
 #[derive(Debug, Deserialize)]
@@ -26,7 +29,12 @@ pub struct SyncResponse {
     pub state_id: Option<String>,
     pub mutations: Option<Vec<Mutation>>,
     pub store: Option<Vec<Credential>>,
+    /// Location of the paged export when the store is too large to include
+    pub export: Option<String>,
     pub id_changes: Option<Vec<(String, String)>>,
+    /// Expiry of the key of the request in seconds since the Unix epoch, sent when it is
+    /// close so the device can rotate it in time
+    pub key_expires: Option<u64>,
 }
 
 impl SyncResponse {
@@ -42,20 +50,54 @@ impl SyncResponse {
 
 #[post("/sync", data = "<data>")]
 pub fn sync_user(
-    user: User,
+    device: Device,
+    audit: Audit,
+    config: &State<Config>,
     db: &State<Databases>,
-    data: Json<SyncRequest>,
-) -> status::Custom<Json<SyncResponse>> {
-    let User(alias) = user;
-    info!("Syncing user {}", &alias);
+    data: Wire<SyncRequest>,
+) -> status::Custom<Wire<SyncResponse>> {
+    let Device(key) = device;
+    let alias = &key.alias;
+    info!("Syncing user {} from device {}", alias, &key.id);
+    if !data.mutations.is_empty() && !key.has_scope(Scope::SyncWrite) {
+        warn!(
+            "Refused mutations from device {} of user {} without the {} scope",
+            &key.id,
+            alias,
+            Scope::SyncWrite
+        );
+        return status::Custom(
+            Status::Forbidden,
+            Wire(SyncResponse {
+                status: "read_only".into(),
+                ..Default::default()
+            }),
+        );
+    }
 
-    match sync_aux(&alias, db, data) {
-        Ok(response) => status::Custom(Status::Ok, Json(response)),
+    let mutations = data.mutations.len() as u64;
+    match sync_aux(alias, config, db, data) {
+        Ok(mut response) => {
+            audit.record(AuditEntry {
+                mutations: Some(mutations),
+                state_id: response.state_id.to_owned(),
+                ..audit.entry(AuditAction::Sync, &key)
+            });
+            response.key_expires = key
+                .expires
+                .filter(|expires| *expires <= now_secs() + config.auth.expiry_warning);
+            if let Some(state_id) = &response.state_id {
+                if let Err(e) = db.user.record_sync(&key.id, state_id) {
+                    warn!("Failed to record sync of device {}: {:?}", &key.id, e);
+                }
+            }
+            status::Custom(Status::Ok, Wire(response))
+        }
         Err(e) => {
             error!("Failed to sync user\n{:?}", e);
             status::Custom(
                 Status::InternalServerError,
-                Json(SyncResponse {
+                Wire(SyncResponse {
                     status: "failed".into(),
                     ..Default::default()
                 }),
@@ -66,27 +108,27 @@ pub fn sync_user(
 
 fn sync_aux(
     alias: &str,
+    config: &State<Config>,
     db: &State<Databases>,
-    mut data: Json<SyncRequest>,
+    mut data: Wire<SyncRequest>,
 ) -> Result<SyncResponse> {
     let mut response = SyncResponse::default();
 
-    if data.mutations.is_empty() && !data.state_id.is_empty() {
-        info!("No new mutations");
-        response.state_id = Some(data.state_id.to_string());
-        response.status = "success".to_string();
-        return Ok(response);
-    }
-
     // Applying mutations
     trace!("Applying mutations");
-    data.mutations
-        .retain_mut(|mutation| match db.store.apply_mutation(alias, mutation) {
+    let mut renamed_ids: HashMap<String, String> = HashMap::new();
+    data.mutations.retain_mut(|mutation| {
+        // Later mutations in the same batch refer to the id the client knows about
+        if let Some(new_id) = renamed_ids.get(&mutation.credential().id) {
+            mutation.credential_mut().id = new_id.to_owned();
+        }
+        match db.store.apply_mutation(alias, mutation) {
             Ok(None) => true,
             Ok(Some(id)) => {
                 if let Mutation::Add { credential } = mutation {
                     trace!("Replaced id {} with {}", &credential.id, &id);
                     response.add_id_change(&credential.id, &id);
+                    renamed_ids.insert(credential.id.to_owned(), id.to_owned());
                     credential.id = id;
                 }
                 true
@@ -106,7 +148,8 @@ fn sync_aux(
                 };
                 false
             }
-        });
+        }
+    });
 
     // Check state
     trace!("Checking state");
@@ -115,15 +158,21 @@ fn sync_aux(
         .has_state(alias, &data.state_id)
         .context(format!("Failed to check user state for user {}", alias))?;
 
-    // Return whole store
+    // Return whole store, or point to the paged export if it does not fit in a page
     if !state_exists {
         info!("State id not found, exporting entire store");
         let store = db
             .store
-            .export_all(alias)
+            .export_page(alias, None, config.export_page_size + 1)
             .with_context(|| format!("Failed to export store for user {}", alias))?;
-        info!("Exported store for user {}", &alias);
-        response.store = Some(store);
+        if store.len() > config.export_page_size as usize {
+            info!("Store too large, pointing user {} to paged export", &alias);
+            response.export =
+                Some(uri!(export_store(Option::<&str>::None, Option::<u32>::None)).to_string());
+        } else {
+            info!("Exported store for user {}", &alias);
+            response.store = Some(store);
+        }
     } else {
         info!("State id found, getting remote mutations");
         let mut remote_mutations = db
@@ -134,32 +183,20 @@ fn sync_aux(
         if !remote_mutations.is_empty() {
             info!("Found new remote state, filtering new mutations");
 
-            // Otherwise perform filters
-            // Filter self-overriding local mutations
-            let mut overriden_ids: HashSet<String> = HashSet::new();
-            data.mutations.retain(|m| {
-                overriden_ids.insert(match m {
-                    Mutation::Add { credential } => credential.id.to_owned(),
-                    Mutation::Delete { credential } => credential.id.to_owned(),
-                    Mutation::Modify { credential } => credential.id.to_owned(),
-                })
-            });
-
-            // Filter out remote mutations for return
-            remote_mutations.retain(|m| match m {
-                    Mutation::Add { credential } => {
-                        if overriden_ids.insert(credential.id.to_owned()) {
-                            warn!("Impossible state: credential modified/deleted locally without knowing about remote credential with same id");
-                            true
-                        } else {
-                            false
-                        }
-                    },
-                    Mutation::Modify { credential } => overriden_ids.insert(credential.id.to_owned()),
-                    Mutation::Delete { credential } => overriden_ids.insert(credential.id.to_owned())
-                });
+            // Local mutations override anything remote affecting the same credential
+            let overriden_ids: HashSet<String> = data
+                .mutations
+                .iter()
+                .map(|m| m.credential().id.to_owned())
+                .collect();
 
-            response.mutations = Some(remote_mutations);
+            remote_mutations.retain(|m| !overriden_ids.contains(&m.credential().id));
+            response.mutations = Some(collapse_mutations(remote_mutations));
+        } else if data.mutations.is_empty() {
+            info!("No new mutations");
+            response.state_id = Some(data.state_id.to_string());
+            response.status = "success".to_string();
+            return Ok(response);
         } else {
             info!("Already have most recent state");
         }
@@ -174,37 +211,73 @@ fn sync_aux(
     Ok(response)
 }
 
+/// Collapse the mutations of each credential into one taking the client from before the
+/// first to after the last, ordered by the last
+///
+/// Adds are only recorded for free ids, so a credential first added here is one the
+/// client never had: it is added with its final value, or left out if it was deleted.
+fn collapse_mutations(mutations: Vec<Mutation>) -> Vec<Mutation> {
+    let mut existed: HashMap<String, bool> = HashMap::new();
+    for mutation in &mutations {
+        existed
+            .entry(mutation.credential().id.to_owned())
+            .or_insert(!matches!(mutation, Mutation::Add { .. }));
+    }
+    let mut returned_ids: HashSet<String> = HashSet::new();
+    let mut collapsed: Vec<Mutation> = mutations
+        .into_iter()
+        .rev()
+        .filter(|m| returned_ids.insert(m.credential().id.to_owned()))
+        .filter_map(|mutation| {
+            let existed = existed[&mutation.credential().id];
+            match mutation {
+                Mutation::Delete { .. } if !existed => None,
+                Mutation::Add { credential } | Mutation::Modify { credential } => {
+                    Some(if existed {
+                        Mutation::Modify { credential }
+                    } else {
+                        Mutation::Add { credential }
+                    })
+                }
+                deletion => Some(deletion),
+            }
+        })
+        .collect();
+    collapsed.reverse();
+    collapsed
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
 
-    use rocket::{http::Header, local::blocking::Client};
-    use serde_json::json;
+    use ciborium::value::Value as CborValue;
+    use rocket::{
+        http::{Accept, ContentType, Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::{json, Value};
 
     use crate::{
         api::{
             db_types::{Credential, Mutation},
             endpoints::init_upload::InitUploadResponse,
             server::build_server,
+            wire::Format,
         },
         config::parse_config::{Config, User},
+        util::key::hash_key,
     };
 
     use super::SyncResponse;
 
     fn init_test_config(dir: &str) -> Config {
-        if Path::new(dir).exists() {
-            std::fs::remove_dir_all(dir).expect("Remove test data directory");
-        }
-        std::fs::create_dir_all(dir).expect("Create test data directory");
         Config {
             users: vec![User {
                 alias: "unit".into(),
-                keys: vec!["unit".into()],
+                keys: vec![hash_key("unit").unwrap()],
             }],
-            cache_count: 50,
-            db_directory: dir.into(),
-            enable_test_routes: false,
+            ..Config::in_test_directory(dir)
         }
     }
 
@@ -242,9 +315,7 @@ mod test {
                 .to_string(),
             )
             .dispatch();
-        let body_str = &response.into_string().unwrap();
-        println!("{}", &body_str);
-        let body: SyncResponse = serde_json::from_str(body_str).unwrap();
+        let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
         assert!(!body.state_id.unwrap().is_empty());
         assert!(body.mutations.is_none());
         assert!(body.store.is_none());
@@ -357,7 +428,6 @@ mod test {
             )
             .dispatch();
         let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-        println!("{:?}", &body);
         assert!(!body.state_id.unwrap().is_empty());
         assert_eq!(
             body.mutations,
@@ -412,7 +482,6 @@ mod test {
             )
             .dispatch();
         let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-        println!("{:?}", &body);
         assert!(!body.state_id.unwrap().is_empty());
         assert!(body.mutations.is_none());
         assert_eq!(
@@ -472,7 +541,6 @@ mod test {
             )
             .dispatch();
         let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-        println!("{:?}", &body);
         assert!(!body.state_id.unwrap().is_empty());
         assert!(body.mutations.is_none());
         assert!(body.store.is_none());
@@ -527,13 +595,66 @@ mod test {
             )
             .dispatch();
         let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-        println!("{:?}", &body);
         assert!(!body.state_id.unwrap().is_empty());
         assert!(body.mutations.is_none());
         assert!(body.store.is_none());
         assert!(body.id_changes.is_none());
     }
 
+    #[test]
+    fn collapsed_for_client() {
+        let config = init_test_config("test/sync/collapsed_for_client");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(json!([{"id": "kept", "value": "first"}]).to_string())
+            .dispatch();
+        let init_body: InitUploadResponse = response.into_json().unwrap();
+        let init_state_id = init_body.state_id.unwrap();
+        let sync = |state_id: &str, mutations: Value| -> SyncResponse {
+            client
+                .post(uri!(super::sync_user))
+                .header(auth_header())
+                .body(json!({"state_id": state_id, "mutations": mutations}).to_string())
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+        let credential = |id: &str, value: &str| json!({"id": id, "value": value});
+        let mut state_id = init_state_id.clone();
+        for mutations in [
+            json!([{"type": "add", "credential": credential("new", "first")}]),
+            json!([{"type": "modify", "credential": credential("new", "second")}]),
+            json!([{"type": "add", "credential": credential("gone", "first")}]),
+            json!([{"type": "delete", "credential": credential("gone", "")}]),
+            json!([{"type": "modify", "credential": credential("kept", "second")}]),
+        ] {
+            state_id = sync(&state_id, mutations).state_id.unwrap();
+        }
+
+        // A client at the initial state never had `new` or `gone`, so it could not apply
+        // a modification or deletion of them
+        let body = sync(&init_state_id, json!([]));
+        assert_eq!(
+            body.mutations.unwrap(),
+            vec![
+                Mutation::Add {
+                    credential: Credential {
+                        id: "new".into(),
+                        value: "second".into()
+                    }
+                },
+                Mutation::Modify {
+                    credential: Credential {
+                        id: "kept".into(),
+                        value: "second".into()
+                    }
+                },
+            ]
+        );
+    }
+
     #[test]
     fn remote_overriden() {
         let config = init_test_config("test/sync/remote_overriden");
@@ -594,10 +715,193 @@ mod test {
             )
             .dispatch();
         let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-        println!("{:?}", &body);
         assert!(!body.state_id.unwrap().is_empty());
         assert!(body.mutations.is_none());
         assert!(body.store.is_none());
         assert!(body.id_changes.is_none());
     }
+
+    #[test]
+    fn missing_state_paged() {
+        let mut config = init_test_config("test/sync/missing_state_paged");
+        config.export_page_size = 1;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let _init = client
+            .post("/init/upload")
+            .header(auth_header())
+            .body(
+                json!([
+                    {
+                        "id": "random",
+                        "value": "nothing"
+                    },
+                    {
+                        "id": "something",
+                        "value": "nothing"
+                    }
+                ])
+                .to_string(),
+            )
+            .dispatch();
+        let response = client
+            .post(uri!(super::sync_user))
+            .header(auth_header())
+            .body(json!({"state_id": "asdfasdf", "mutations": []}).to_string())
+            .dispatch();
+        let body: SyncResponse = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(!body.state_id.unwrap().is_empty());
+        assert!(body.store.is_none());
+        assert_eq!(body.export, Some("/export".to_string()));
+    }
+
+    /// Runs a sync with a remote change and a clashing id in `format`
+    fn encoded_sync(dir: &str, format: Format) -> SyncResponse {
+        let config = init_test_config(dir);
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let send = |uri: &str, body: Value| {
+            let response = client
+                .post(uri.to_string())
+                .header(auth_header())
+                .header(ContentType(format.media_type()))
+                .header(Accept::from(format.media_type()))
+                .body(format.encode(&body).unwrap())
+                .dispatch();
+            assert_eq!(
+                response.content_type(),
+                Some(ContentType(format.media_type()))
+            );
+            response.into_bytes().unwrap()
+        };
+        let init: InitUploadResponse = format
+            .decode(&send(
+                "/init/upload",
+                json!([{"id": "random", "value": "nothing"}]),
+            ))
+            .unwrap();
+        let init_state_id = init.state_id.expect("Init body state id");
+        send(
+            "/sync",
+            json!({
+                "state_id": &init_state_id,
+                "mutations": [
+                    {"type": "modify", "credential": {"id": "random", "value": "changed"}},
+                    {"type": "add", "credential": {"id": "other", "value": "nothing"}}
+                ]
+            }),
+        );
+        format
+            .decode(&send(
+                "/sync",
+                json!({
+                    "state_id": &init_state_id,
+                    "mutations": [
+                        {"type": "add", "credential": {"id": "random", "value": "clash"}},
+                        {"type": "delete", "credential": {"id": "missing", "value": ""}}
+                    ]
+                }),
+            ))
+            .unwrap()
+    }
+
+    #[test]
+    fn cbor_matches_json() {
+        let json_body = encoded_sync("test/sync/encoding_json", Format::Json);
+        let cbor_body = encoded_sync("test/sync/encoding_cbor", Format::Cbor);
+        assert_eq!(cbor_body.status, json_body.status);
+        assert_eq!(cbor_body.mutations, json_body.mutations);
+        assert_eq!(cbor_body.store, json_body.store);
+        assert_eq!(cbor_body.export, json_body.export);
+        // New ids are random, only the replaced ids can match
+        let replaced = |body: &SyncResponse| -> Vec<String> {
+            body.id_changes
+                .iter()
+                .flatten()
+                .map(|(id, _)| id.to_owned())
+                .collect()
+        };
+        assert_eq!(replaced(&cbor_body), vec!["random".to_string()]);
+        assert_eq!(replaced(&cbor_body), replaced(&json_body));
+    }
+
+    /// Field `name` of the CBOR map `value`
+    fn cbor_field<'a>(value: &'a CborValue, name: &str) -> &'a CborValue {
+        value
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key.as_text() == Some(name))
+            .map(|(_, value)| value)
+            .unwrap()
+    }
+
+    #[test]
+    fn cbor_values_as_bytes() {
+        let config = init_test_config("test/sync/cbor_values_as_bytes");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let credential = |id: &str, value: CborValue| {
+            CborValue::Map(vec![
+                (CborValue::Text("id".into()), CborValue::Text(id.into())),
+                (CborValue::Text("value".into()), value),
+            ])
+        };
+        let upload = CborValue::Array(vec![
+            credential("binary", CborValue::Bytes(b"secret".to_vec())),
+            credential("text", CborValue::Text("not base64".into())),
+        ]);
+        let response = client
+            .post("/init/upload")
+            .header(auth_header())
+            .header(ContentType(Format::Cbor.media_type()))
+            .body(Format::Cbor.encode(&upload).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // JSON carries the bytes as base64
+        let response = client.get("/export").header(auth_header()).dispatch();
+        assert!(response.headers().get("Vary").any(|vary| vary == "Accept"));
+        let body: Value = response.into_json().unwrap();
+        let values: Vec<_> = body["credentials"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|credential| credential["value"].clone())
+            .collect();
+        assert!(values.contains(&json!(base64::encode(b"secret"))));
+        assert!(values.contains(&json!("not base64")));
+
+        // CBOR keeps strings unless the client asks for bytes
+        let export = |accept: &str| {
+            let response = client
+                .get("/export")
+                .header(auth_header())
+                .header(Header::new("Accept", accept.to_string()))
+                .dispatch();
+            assert!(response.headers().get("Vary").any(|vary| vary == "Accept"));
+            let content_type = response.content_type().unwrap().to_string();
+            let body: CborValue = Format::Cbor
+                .decode(&response.into_bytes().unwrap())
+                .unwrap();
+            let credentials = cbor_field(&body, "credentials").as_array().unwrap().clone();
+            assert_eq!(credentials.len(), 2);
+            (content_type, credentials)
+        };
+        let (content_type, credentials) = export("application/cbor");
+        assert_eq!(content_type, "application/cbor");
+        for credential in &credentials {
+            let value = cbor_field(credential, "value");
+            match cbor_field(credential, "id").as_text().unwrap() {
+                "binary" => assert_eq!(value.as_text(), Some(base64::encode(b"secret").as_str())),
+                _ => assert_eq!(value.as_text(), Some("not base64")),
+            }
+        }
+        let (content_type, credentials) = export("application/cbor; values=bytes");
+        assert_eq!(content_type, "application/cbor; values=bytes");
+        for credential in &credentials {
+            let value = cbor_field(credential, "value");
+            match cbor_field(credential, "id").as_text().unwrap() {
+                "binary" => assert_eq!(value.as_bytes(), Some(&b"secret".to_vec())),
+                _ => assert_eq!(value.as_text(), Some("not base64")),
+            }
+        }
+    }
 }
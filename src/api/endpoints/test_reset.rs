@@ -1,15 +1,23 @@
-use std::path::Path;
-
 use anyhow::Result;
 use rocket::{http::Status, State};
 
-use crate::{api::guards::user::User, config::parse_config::Config, database::traits::Databases};
+use crate::{
+    api::{
+        db_types::AuditAction,
+        guards::{audit::Audit, user::Writer},
+    },
+    database::traits::Databases,
+};
 
 #[post("/test/reset")]
-pub fn reset_databases(user: User, config: &State<Config>, db: &State<Databases>) -> Status {
-    let User(alias) = user;
-    match clear_database(&alias, &config.db_directory, db) {
-        Ok(_) => Status::Ok,
+pub fn reset_databases(writer: Writer, audit: Audit, db: &State<Databases>) -> Status {
+    let Writer(key) = writer;
+    let alias = &key.alias;
+    match clear_database(alias, db) {
+        Ok(_) => {
+            audit.record(audit.entry(AuditAction::Reset, &key));
+            Status::Ok
+        }
         Err(_) => {
             error!("Failed to reset for user {}", &alias);
             Status::InternalServerError
@@ -17,8 +25,66 @@ pub fn reset_databases(user: User, config: &State<Config>, db: &State<Databases>
     }
 }
 
-fn clear_database(alias: &str, db_directory: &str, db: &State<Databases>) -> Result<()> {
+fn clear_database(alias: &str, db: &State<Databases>) -> Result<()> {
     db.user.remove_salt(alias)?;
-    std::fs::remove_file(Path::new(db_directory).join(format!("{alias}.sqlite")))?;
+    db.store.clear_vault(alias)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::server::build_server,
+        config::parse_config::{Config, User},
+        util::key::hash_key,
+    };
+
+    fn init_test_config(dir: &str) -> Config {
+        Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: vec![hash_key("unit").unwrap()],
+            }],
+            enable_test_routes: true,
+            ..Config::in_test_directory(dir)
+        }
+    }
+
+    fn upload(client: &Client) -> Status {
+        client
+            .post("/init/upload")
+            .header(Header::new("Authentication", "unit"))
+            .body(json!([{"id": "first", "value": "value"}]).to_string())
+            .dispatch()
+            .status()
+    }
+
+    #[test]
+    fn cleared() {
+        let config = init_test_config("test/test_reset/cleared");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        assert_eq!(upload(&client), Status::Ok);
+        assert_ne!(upload(&client), Status::Ok);
+
+        let response = client
+            .post(uri!(super::reset_databases))
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/export")
+            .header(Header::new("Authentication", "unit"))
+            .dispatch();
+        let body: Value = response.into_json().unwrap();
+        assert!(body["credentials"].as_array().unwrap().is_empty());
+        assert_eq!(upload(&client), Status::Ok);
+    }
+}
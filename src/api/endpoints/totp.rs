@@ -0,0 +1,404 @@
+use log::{error, info, warn};
+use rocket::{http::Status, response::status, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        catchers::TooManyRequests,
+        db_types::ApiKey,
+        guards::user::AccountAdmin,
+        rate_limit::{user_subject, RateLimiter},
+    },
+    config::parse_config::Config,
+    database::{accounts::check_totp, traits::Databases},
+    util::{
+        error::Error,
+        key::hash_key,
+        totp::{generate_recovery_codes, generate_secret, otpauth_url, secret_base32, TotpCipher},
+        types::GenericResult,
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct TotpSetupResponse {
+    pub status: String,
+    /// Base32 encoded secret to enter into an authenticator app
+    pub secret: Option<String>,
+    /// `otpauth://` URL of the secret
+    pub url: Option<String>,
+    /// Single-use codes that stand in for a TOTP code, only ever returned here
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+/// Start setting up TOTP for the user
+///
+/// The secret is stored encrypted and only enforced once confirmed with a code. Starting
+/// over before that replaces the secret and recovery codes.
+#[post("/user/totp")]
+pub fn setup_totp(
+    admin: AccountAdmin,
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+) -> status::Custom<Json<TotpSetupResponse>> {
+    let AccountAdmin(key) = admin;
+    let response = |status: Status, message: &str| {
+        status::Custom(
+            status,
+            Json(TotpSetupResponse {
+                status: message.into(),
+                ..Default::default()
+            }),
+        )
+    };
+    if !cipher.is_available() {
+        warn!(
+            "User {} tried to set up TOTP without a configured TOTP key",
+            &key.alias
+        );
+        return response(Status::ServiceUnavailable, "unavailable");
+    }
+    match setup_aux(db, cipher, &key.alias) {
+        Ok(setup) => {
+            info!("Started TOTP setup of user {}", &key.alias);
+            status::Custom(Status::Ok, Json(setup))
+        }
+        Err(Error::ExistingTotp(_)) => {
+            warn!("User {} already has TOTP set up", &key.alias);
+            response(Status::Conflict, "existing")
+        }
+        Err(e) => {
+            error!("Failed to set up TOTP of user {}: {:?}", &key.alias, e);
+            response(Status::InternalServerError, "failed")
+        }
+    }
+}
+
+fn setup_aux(
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+    alias: &str,
+) -> GenericResult<TotpSetupResponse> {
+    let secret = generate_secret();
+    let recovery_codes = generate_recovery_codes();
+    let hashes = recovery_codes
+        .iter()
+        .map(|code| hash_key(code).map_err(Error::Server))
+        .collect::<GenericResult<Vec<_>>>()?;
+    db.user
+        .begin_totp(alias, &cipher.encrypt(alias, &secret)?, &hashes)?;
+    Ok(TotpSetupResponse {
+        status: "success".into(),
+        secret: Some(secret_base32(&secret)?),
+        url: Some(otpauth_url(alias, &secret)?),
+        recovery_codes: Some(recovery_codes),
+    })
+}
+
+/// Enforce TOTP for the user after checking a first code
+///
+/// The device that confirms counts as enrolled.
+#[post("/user/totp/confirm", data = "<data>")]
+pub fn confirm_totp(
+    admin: AccountAdmin,
+    config: &State<Config>,
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+    data: Json<TotpCodeRequest>,
+) -> Result<status::Custom<Json<TotpResponse>>, TooManyRequests> {
+    let AccountAdmin(key) = admin;
+    let result = code_aux(config, db, cipher, &key, &data.code, false).and_then(|check| {
+        if let CodeCheck::Checked(true) = check {
+            db.user.enable_totp(&key.alias)?;
+            db.user.verify_device(&key.id)?;
+        }
+        Ok(check)
+    });
+    totp_status(&key, "Enabled", result)
+}
+
+/// Stop enforcing TOTP for the user, with a current code or a recovery code
+#[delete("/user/totp", data = "<data>")]
+pub fn disable_totp(
+    admin: AccountAdmin,
+    config: &State<Config>,
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+    data: Json<TotpCodeRequest>,
+) -> Result<status::Custom<Json<TotpResponse>>, TooManyRequests> {
+    let AccountAdmin(key) = admin;
+    let result = code_aux(config, db, cipher, &key, &data.code, true).and_then(|check| {
+        if let CodeCheck::Checked(true) = check {
+            db.user.remove_totp(&key.alias)?;
+        }
+        Ok(check)
+    });
+    totp_status(&key, "Disabled", result)
+}
+
+/// Result of checking the code of a TOTP change
+enum CodeCheck {
+    Checked(bool),
+    LockedOut(u64),
+}
+
+/// Check `code` against the TOTP setup of the user of `key`, enabled or not
+fn code_aux(
+    config: &State<Config>,
+    db: &State<Databases>,
+    cipher: &State<TotpCipher>,
+    key: &ApiKey,
+    code: &str,
+    enabled: bool,
+) -> GenericResult<CodeCheck> {
+    let totp = match db.user.get_totp(&key.alias)? {
+        Some(totp) if totp.enabled == enabled => totp,
+        Some(_) if !enabled => return Err(Error::ExistingTotp(key.alias.to_string())),
+        _ => return Err(Error::MissingTotp(key.alias.to_string())),
+    };
+    let limiter = RateLimiter::new(&config.rate_limit, db.user.as_ref());
+    let subject = user_subject(&key.alias);
+    if let Some(retry_after) = limiter.locked_out(&subject)? {
+        return Ok(CodeCheck::LockedOut(retry_after));
+    }
+    let checked = check_totp(db.user.as_ref(), cipher, &key.alias, &totp, code.trim())?;
+    if checked {
+        limiter.succeed(&subject)?;
+    } else {
+        limiter.fail(&subject)?;
+    }
+    Ok(CodeCheck::Checked(checked))
+}
+
+fn totp_status(
+    key: &ApiKey,
+    action: &str,
+    result: GenericResult<CodeCheck>,
+) -> Result<status::Custom<Json<TotpResponse>>, TooManyRequests> {
+    let (status, message) = match result {
+        Ok(CodeCheck::Checked(true)) => {
+            info!("{} TOTP of user {}", action, &key.alias);
+            (Status::Ok, "success")
+        }
+        Ok(CodeCheck::Checked(false)) => {
+            warn!("Device {} sent a wrong TOTP code", &key.id);
+            (Status::Forbidden, "invalid")
+        }
+        Ok(CodeCheck::LockedOut(retry_after)) => return Err(TooManyRequests::new(retry_after)),
+        Err(Error::MissingTotp(_)) => (Status::NotFound, "missing"),
+        Err(Error::ExistingTotp(_)) => (Status::Conflict, "existing"),
+        Err(e) => {
+            error!("Failed to change TOTP of user {}: {:?}", &key.alias, e);
+            (Status::InternalServerError, "failed")
+        }
+    };
+    Ok(status::Custom(
+        status,
+        Json(TotpResponse {
+            status: message.into(),
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+    use serde_json::json;
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    use crate::{
+        api::{endpoints::devices::RotateKeyResponse, server::build_server},
+        config::parse_config::{Config, User},
+        util::{key::hash_key, session::now_secs},
+    };
+
+    use super::TotpSetupResponse;
+
+    fn init_test_config(dir: &str) -> Config {
+        let mut config = Config {
+            users: vec![User {
+                alias: "unit".into(),
+                keys: ["phone", "laptop", "tablet", "watch"]
+                    .iter()
+                    .map(|key| hash_key(key).unwrap())
+                    .collect(),
+            }],
+            ..Config::in_test_directory(dir)
+        };
+        config.auth.totp_key = Some(base64::encode([7; 32]));
+        config
+    }
+
+    fn auth_header(key: &str) -> Header<'static> {
+        Header::new("Authentication", key.to_string())
+    }
+
+    /// Code of `secret` valid `offset` seconds from now
+    fn code(secret: &str, offset: u64) -> String {
+        let secret = Secret::Encoded(secret.to_string()).to_bytes().unwrap();
+        TOTP::new(Algorithm::SHA1, 6, 1, 30, secret)
+            .unwrap()
+            .generate(now_secs() + offset)
+    }
+
+    fn set_up(client: &Client) -> TotpSetupResponse {
+        let _init = client
+            .post("/user/init")
+            .header(auth_header("phone"))
+            .body(json!({"salt": "salt", "hash": "hash"}).to_string())
+            .dispatch();
+        let response = client
+            .post(uri!(super::setup_totp))
+            .header(auth_header("phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let setup: TotpSetupResponse = response.into_json().unwrap();
+        assert!(setup.url.as_ref().unwrap().starts_with("otpauth://totp/"));
+        assert_eq!(setup.recovery_codes.as_ref().unwrap().len(), 8);
+
+        let response = client
+            .post(uri!(super::confirm_totp))
+            .header(auth_header("phone"))
+            .body(json!({"code": "000000x"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post(uri!(super::confirm_totp))
+            .header(auth_header("phone"))
+            .body(json!({"code": code(setup.secret.as_ref().unwrap(), 0)}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        setup
+    }
+
+    fn import(client: &Client, key: &str, code: Option<&str>) -> Status {
+        let mut request = client.get("/user/import").header(auth_header(key));
+        if let Some(code) = code {
+            request = request.header(Header::new("TOTP-Code", code.to_string()));
+        }
+        request.dispatch().status()
+    }
+
+    #[test]
+    fn key_required() {
+        let mut config = init_test_config("test/totp/key_required");
+        config.auth.totp_key = None;
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let response = client
+            .post(uri!(super::setup_totp))
+            .header(auth_header("phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+    }
+
+    #[test]
+    fn enrollment() {
+        let config = init_test_config("test/totp/enrollment");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let setup = set_up(&client);
+        let secret = setup.secret.unwrap();
+        let recovery = &setup.recovery_codes.unwrap()[0];
+
+        // The device that confirmed is enrolled
+        assert_eq!(import(&client, "phone", None), Status::Ok);
+        assert_eq!(import(&client, "laptop", None), Status::Forbidden);
+        let response = client
+            .post("/auth/challenge")
+            .header(auth_header("laptop"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        // Other routes are not affected
+        let response = client
+            .get("/export")
+            .header(auth_header("laptop"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let next = code(&secret, 30);
+        assert_eq!(import(&client, "laptop", Some(&next)), Status::Ok);
+        assert_eq!(import(&client, "laptop", None), Status::Ok);
+        // Codes cannot be used twice
+        assert_eq!(import(&client, "tablet", Some(&next)), Status::Forbidden);
+
+        assert_eq!(import(&client, "tablet", Some(recovery)), Status::Ok);
+        assert_eq!(import(&client, "watch", Some(recovery)), Status::Forbidden);
+    }
+
+    #[test]
+    fn disable() {
+        let config = init_test_config("test/totp/disable");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let setup = set_up(&client);
+        let response = client
+            .post(uri!(super::setup_totp))
+            .header(auth_header("phone"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = client
+            .delete(uri!(super::disable_totp))
+            .header(auth_header("phone"))
+            .body(json!({"code": "guess"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .delete(uri!(super::disable_totp))
+            .header(auth_header("phone"))
+            .body(json!({"code": &setup.recovery_codes.unwrap()[3]}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(import(&client, "laptop", None), Status::Ok);
+    }
+
+    #[test]
+    fn rotated() {
+        let config = init_test_config("test/totp/rotated");
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        set_up(&client);
+        let rotate = |key: &str| -> String {
+            let response = client
+                .post("/devices/rotate")
+                .header(auth_header(key))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let body: RotateKeyResponse = response.into_json().unwrap();
+            body.key.unwrap()
+        };
+
+        // The new key of an enrolled device stays enrolled, others still need a code
+        assert_eq!(import(&client, &rotate("phone"), None), Status::Ok);
+        assert_eq!(import(&client, &rotate("laptop"), None), Status::Forbidden);
+    }
+
+    #[test]
+    fn renamed() {
+        let mut config = init_test_config("test/totp/renamed");
+        config.admin_keys = vec![hash_key("admin").unwrap()];
+        let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+        let setup = set_up(&client);
+        let response = client
+            .post("/admin/users/unit/rename")
+            .header(auth_header("admin"))
+            .body(json!({"alias": "renamed"}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let next = code(&setup.secret.unwrap(), 30);
+        assert_eq!(import(&client, "laptop", None), Status::Forbidden);
+        assert_eq!(import(&client, "laptop", Some(&next)), Status::Ok);
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::Display;
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Request,
+};
+
+use crate::{
+    api::{
+        db_types::AuditAction,
+        rate_limit::{client_subject, RateLimiter, RetryAfter},
+    },
+    config::parse_config::Config,
+    database::traits::Databases,
+    util::key::verify_key,
+};
+
+use super::{
+    audit::{self, anonymous_entry},
+    user::request_credential,
+};
+
+/// Server operator holding one of the config's `admin_keys`
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = AdminError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(key) = request_credential(req) {
+            let config = req
+                .rocket()
+                .state::<Config>()
+                .expect("Rocket instance contains managed state for server config");
+            let db = req
+                .rocket()
+                .state::<Databases>()
+                .expect("Rocket instance contains managed state for databases");
+            let limiter = RateLimiter::new(&config.rate_limit, db.user.as_ref());
+            let subject = client_subject(req);
+            match limiter.locked_out(&subject) {
+                Ok(Some(retry_after)) => {
+                    req.local_cache(|| RetryAfter(retry_after));
+                    return request::Outcome::Failure((
+                        Status::TooManyRequests,
+                        AdminError::RateLimited,
+                    ));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to check rate limit: {:?}", e);
+                    return request::Outcome::Failure((
+                        Status::InternalServerError,
+                        AdminError::Server,
+                    ));
+                }
+            }
+            if config.admin_keys.iter().any(|hash| verify_key(key, hash)) {
+                request::Outcome::Success(Self)
+            } else {
+                if let Err(e) = limiter.fail(&subject) {
+                    error!("Failed to record authentication failure: {:?}", e);
+                }
+                audit::record(db, &anonymous_entry(req, AuditAction::AuthFailure));
+                request::Outcome::Failure((Status::Unauthorized, AdminError::InvalidKey))
+            }
+        } else {
+            request::Outcome::Failure((Status::Unauthorized, AdminError::MissingHeader))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    MissingHeader,
+    InvalidKey,
+    RateLimited,
+    Server,
+}
+
+impl Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::MissingHeader => write!(f, "Missing admin key in authorization header"),
+            AdminError::InvalidKey => write!(f, "Admin key does not exist"),
+            AdminError::RateLimited => write!(f, "Too many failed authentication attempts"),
+            AdminError::Server => write!(f, "Failed to check rate limit"),
+        }
+    }
+}
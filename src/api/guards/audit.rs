@@ -0,0 +1,81 @@
+use std::convert::Infallible;
+
+use rocket::{
+    request::{self, FromRequest},
+    Request,
+};
+
+use crate::{
+    api::db_types::{ApiKey, AuditAction, AuditEntry},
+    database::traits::Databases,
+};
+
+/// Audit log of the request, recording events with its route and client address
+pub struct Audit<'r> {
+    db: &'r Databases,
+    route: String,
+    ip: Option<String>,
+}
+
+impl<'r> Audit<'r> {
+    /// Entry of `action` by the device of `key`
+    pub fn entry(&self, action: AuditAction, key: &ApiKey) -> AuditEntry {
+        AuditEntry {
+            action,
+            alias: Some(key.alias.to_owned()),
+            device: Some(key.id.to_owned()),
+            route: self.route.to_owned(),
+            mutations: None,
+            state_id: None,
+            ip: self.ip.to_owned(),
+        }
+    }
+
+    /// Append `entry` to the log, which does not fail the request if it goes wrong
+    pub fn record(&self, entry: AuditEntry) {
+        record(self.db, &entry);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit<'r> {
+    type Error = Infallible;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let db = req
+            .rocket()
+            .state::<Databases>()
+            .expect("Rocket instance contains managed state for databases");
+        request::Outcome::Success(Self {
+            db,
+            route: request_route(req),
+            ip: req.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+/// Path of the request, without its query
+fn request_route(req: &Request<'_>) -> String {
+    req.uri().path().to_string()
+}
+
+/// Entry of `action` for a request that is not authenticated, such as an unknown key
+pub fn anonymous_entry(req: &Request<'_>, action: AuditAction) -> AuditEntry {
+    AuditEntry {
+        action,
+        alias: None,
+        device: None,
+        route: request_route(req),
+        mutations: None,
+        state_id: None,
+        ip: req.client_ip().map(|ip| ip.to_string()),
+    }
+}
+
+pub fn record(db: &Databases, entry: &AuditEntry) {
+    if let Err(e) = db.user.append_audit(entry) {
+        error!(
+            "Failed to record {} in the audit log: {:?}",
+            entry.action, e
+        );
+    }
+}
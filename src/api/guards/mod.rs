@@ -1 +1,3 @@
+pub mod admin;
+pub mod audit;
 pub mod user;
@@ -2,48 +2,483 @@ use std::fmt::Display;
 
 use rocket::{
     http::Status,
+    mtls::Certificate,
+    outcome::try_outcome,
     request::{self, FromRequest},
     Request,
 };
 
-use crate::config::parse_config::Config;
+use crate::{
+    api::{
+        db_types::{ApiKey, AuditAction, AuditEntry, Scope},
+        guards::audit::{self, anonymous_entry},
+        rate_limit::{client_subject, user_keys_subject, user_subject, RateLimiter, RetryAfter},
+    },
+    config::parse_config::Config,
+    database::{accounts::check_totp, traits::Databases},
+    util::{
+        certificate::{certificate_key_hash, CertificateIdentity},
+        error::Error,
+        key::key_id,
+        session::{now_secs, Session, SessionSigner},
+        totp::TotpCipher,
+        types::GenericResult,
+    },
+};
 
+/// User of the request, allowed to read its vault
 pub struct User(pub String);
 
+/// Device of the key that authenticated the request, allowed to read its vault
+pub struct Device(pub ApiKey);
+
+/// Device allowed to write to the vault, with the `sync:write` scope
+pub struct Writer(pub ApiKey);
+
+/// Device allowed to manage the account, with the `account:admin` scope
+pub struct AccountAdmin(pub ApiKey);
+
+/// Device of the request, without the proof of the master password that vault access
+/// may require
+pub struct DeviceKey(pub ApiKey);
+
+/// Device authenticated by its key itself rather than a session token or client
+/// certificate
+pub struct LoginKey(pub ApiKey);
+
+/// Device enrolled with a TOTP code, if its user set up TOTP
+///
+/// A device passes once, with a code or recovery code in the [`TOTP_HEADER`] header.
+pub struct Enrolled;
+
+/// Header carrying a TOTP or recovery code
+pub const TOTP_HEADER: &str = "TOTP-Code";
+
+/// Credential sent with a request
+///
+/// Read from `Authorization: Bearer`, or from the legacy `Authentication` header if
+/// the config allows it
+pub fn request_credential<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    let bearer = req
+        .headers()
+        .get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    bearer.or_else(|| {
+        let config = req
+            .rocket()
+            .state::<Config>()
+            .expect("Rocket instance contains managed state for server config");
+        config
+            .auth
+            .legacy_header
+            .then(|| req.headers().get_one("Authentication"))
+            .flatten()
+    })
+}
+
+/// Key or client certificate that authenticates the request
+enum Credential<'r> {
+    Key(&'r str),
+    Certificate(CertificateIdentity),
+}
+
+/// Credential of the request, falling back to its client certificate without a key
+async fn credential<'r>(req: &'r Request<'_>) -> Option<Credential<'r>> {
+    if let Some(key) = request_credential(req) {
+        return Some(Credential::Key(key));
+    }
+    match req.guard::<Certificate<'_>>().await {
+        request::Outcome::Success(certificate) => Some(Credential::Certificate(
+            CertificateIdentity::new(&certificate),
+        )),
+        _ => None,
+    }
+}
+
+/// Key that authenticated the request and how it was presented
+struct Authenticated {
+    key: ApiKey,
+    method: AuthMethod,
+}
+
+enum AuthMethod {
+    Key,
+    /// A session token used in place of the key
+    Session(Session),
+    /// The client certificate the key belongs to
+    Certificate,
+}
+
+/// Result of authenticating the request, looked up at most once per request
+enum RequestKey {
+    Checked(GenericResult<Option<Authenticated>>),
+    /// The client is locked out for this many more seconds
+    LockedOut(u64),
+}
+
+fn authenticate(
+    req: &Request<'_>,
+    credential: &Credential<'_>,
+) -> GenericResult<Option<Authenticated>> {
+    let credential = match credential {
+        Credential::Key(key) => key,
+        Credential::Certificate(identity) => return authenticate_certificate(req, identity),
+    };
+    let db = req
+        .rocket()
+        .state::<Databases>()
+        .expect("Rocket instance contains managed state for databases");
+    let signer = req
+        .rocket()
+        .state::<SessionSigner>()
+        .expect("Rocket instance contains managed state for session signer");
+    let authenticated = match signer.verify(credential) {
+        // Revoking a key ends its sessions
+        Some(session) => match db.user.get_key(&session.key_id) {
+            Ok(key) => Some(Authenticated {
+                key,
+                method: AuthMethod::Session(session),
+            }),
+            Err(Error::MissingKey(_)) => None,
+            Err(e) => return Err(e),
+        },
+        None => db.user.find_key(credential)?.map(|key| Authenticated {
+            key,
+            method: AuthMethod::Key,
+        }),
+    };
+    Ok(authenticated.filter(|authenticated| !authenticated.key.is_expired(now_secs())))
+}
+
+/// Authenticate as the device of the config's client certificate matching `identity`
+fn authenticate_certificate(
+    req: &Request<'_>,
+    identity: &CertificateIdentity,
+) -> GenericResult<Option<Authenticated>> {
+    let db = req
+        .rocket()
+        .state::<Databases>()
+        .expect("Rocket instance contains managed state for databases");
+    let config = req
+        .rocket()
+        .state::<Config>()
+        .expect("Rocket instance contains managed state for server config");
+    let client = config
+        .tls
+        .iter()
+        .flat_map(|tls| &tls.clients)
+        .find(|client| identity.matches(client));
+    let client = match client {
+        Some(client) => client,
+        None => {
+            warn!(
+                "Unknown client certificate {} with fingerprint {}",
+                &identity.subject, &identity.fingerprint
+            );
+            return Ok(None);
+        }
+    };
+    let key = db.user.find_key_by_hash(&certificate_key_hash(client))?;
+    Ok(key
+        .filter(|key| key.alias == client.alias && !key.is_expired(now_secs()))
+        .map(|key| Authenticated {
+            key,
+            method: AuthMethod::Certificate,
+        }))
+}
+
+/// Subject of the failures of the user whose key id `credential` carries, if any
+fn credential_user_subject(
+    db: &Databases,
+    credential: &Credential<'_>,
+) -> GenericResult<Option<String>> {
+    let id = match credential {
+        Credential::Key(key) => key_id(key),
+        Credential::Certificate(_) => None,
+    };
+    match id.map(|id| db.user.get_key(id)) {
+        Some(Ok(key)) => Ok(Some(user_keys_subject(&key.alias))),
+        Some(Err(Error::MissingKey(_))) | None => Ok(None),
+        Some(Err(e)) => Err(e),
+    }
+}
+
+/// Authenticate unless the client or the user of the key is locked out, counting
+/// failures against both
+///
+/// Counting failures per user stops the secret of a known key id from being guessed
+/// from many addresses.
+fn rate_limited_authenticate(req: &Request<'_>, credential: &Credential<'_>) -> RequestKey {
+    let db = req
+        .rocket()
+        .state::<Databases>()
+        .expect("Rocket instance contains managed state for databases");
+    let config = req
+        .rocket()
+        .state::<Config>()
+        .expect("Rocket instance contains managed state for server config");
+    let limiter = RateLimiter::new(&config.rate_limit, db.user.as_ref());
+    let mut subjects = vec![client_subject(req)];
+    match credential_user_subject(db, credential) {
+        Ok(subject) => subjects.extend(subject),
+        Err(e) => return RequestKey::Checked(Err(e)),
+    }
+    for subject in &subjects {
+        match limiter.locked_out(subject) {
+            Ok(Some(retry_after)) => return RequestKey::LockedOut(retry_after),
+            Ok(None) => {}
+            Err(e) => return RequestKey::Checked(Err(e)),
+        }
+    }
+    let result = authenticate(req, credential);
+    if let Ok(None) = result {
+        for subject in &subjects {
+            if let Err(e) = limiter.fail(subject) {
+                error!("Failed to record authentication failure: {:?}", e);
+            }
+        }
+        audit::record(db, &anonymous_entry(req, AuditAction::AuthFailure));
+    }
+    RequestKey::Checked(result)
+}
+
+async fn request_key<'r>(req: &'r Request<'_>) -> request::Outcome<&'r Authenticated, UserError> {
+    let checked = req
+        .local_cache_async(async {
+            let credential = credential(req).await?;
+            Some(rate_limited_authenticate(req, &credential))
+        })
+        .await;
+    let checked = match checked {
+        Some(checked) => checked,
+        None => return request::Outcome::Failure((Status::Unauthorized, UserError::MissingHeader)),
+    };
+    match checked {
+        RequestKey::Checked(Ok(Some(authenticated))) => request::Outcome::Success(authenticated),
+        RequestKey::Checked(Ok(None)) => {
+            request::Outcome::Failure((Status::Unauthorized, UserError::MissingUser))
+        }
+        RequestKey::LockedOut(retry_after) => {
+            req.local_cache(|| RetryAfter(*retry_after));
+            request::Outcome::Failure((Status::TooManyRequests, UserError::RateLimited))
+        }
+        RequestKey::Checked(Err(e)) => {
+            error!("Failed to look up user key: {:?}", e);
+            request::Outcome::Failure((Status::InternalServerError, UserError::Server))
+        }
+    }
+}
+
+/// Authentication of a request allowed to access the vault with `scope`
+///
+/// If the config requires it, the request must use a session that proved the master
+/// password, unless the user has not set one yet.
+async fn vault_key<'r>(
+    req: &'r Request<'_>,
+    scope: Scope,
+) -> request::Outcome<&'r Authenticated, UserError> {
+    let authenticated = try_outcome!(request_key(req).await);
+    if !authenticated.key.has_scope(scope) {
+        return request::Outcome::Failure((Status::Forbidden, UserError::MissingScope(scope)));
+    }
+    let config = req
+        .rocket()
+        .state::<Config>()
+        .expect("Rocket instance contains managed state for server config");
+    let proven = matches!(&authenticated.method, AuthMethod::Session(session) if session.proven);
+    if !config.auth.require_proof || proven {
+        return request::Outcome::Success(authenticated);
+    }
+    let db = req
+        .rocket()
+        .state::<Databases>()
+        .expect("Rocket instance contains managed state for databases");
+    match db.user.get_user(&authenticated.key.alias) {
+        Ok(_) => request::Outcome::Failure((Status::Forbidden, UserError::ProofRequired)),
+        Err(e) => match e.downcast_ref::<Error>() {
+            Some(Error::UninitializedUser(_)) => request::Outcome::Success(authenticated),
+            _ => {
+                error!("Failed to look up user: {:?}", e);
+                request::Outcome::Failure((Status::InternalServerError, UserError::Server))
+            }
+        },
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for User {
     type Error = UserError;
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        if let Some(key) = req.headers().get_one("Authentication") {
-            let config = req
-                .rocket()
-                .state::<Config>()
-                .expect("Rocket instance contains managed state for server config");
-            match config
-                .users
-                .iter()
-                .find(|i| i.keys.contains(&key.to_owned()))
-            {
-                Some(user) => request::Outcome::Success(Self(user.alias.to_owned())),
-                None => request::Outcome::Failure((Status::NotFound, UserError::MissingUser)),
+        let authenticated = try_outcome!(vault_key(req, Scope::SyncRead).await);
+        request::Outcome::Success(Self(authenticated.key.alias.to_owned()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Device {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(vault_key(req, Scope::SyncRead).await);
+        request::Outcome::Success(Self(authenticated.key.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Writer {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(vault_key(req, Scope::SyncWrite).await);
+        request::Outcome::Success(Self(authenticated.key.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AccountAdmin {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(vault_key(req, Scope::AccountAdmin).await);
+        request::Outcome::Success(Self(authenticated.key.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DeviceKey {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(request_key(req).await);
+        request::Outcome::Success(Self(authenticated.key.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoginKey {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(request_key(req).await);
+        match authenticated.method {
+            AuthMethod::Key => {}
+            AuthMethod::Session(_) => {
+                return request::Outcome::Failure((Status::Unauthorized, UserError::SessionToken))
+            }
+            AuthMethod::Certificate => {
+                return request::Outcome::Failure((Status::Unauthorized, UserError::Certificate))
+            }
+        }
+        request::Outcome::Success(Self(authenticated.key.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Enrolled {
+    type Error = UserError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let authenticated = try_outcome!(request_key(req).await);
+        let key = &authenticated.key;
+        if key.totp_verified.is_some() {
+            return request::Outcome::Success(Self);
+        }
+        match enroll(req, key) {
+            Ok(Enrollment::Enrolled) => request::Outcome::Success(Self),
+            Ok(Enrollment::Refused) => {
+                request::Outcome::Failure((Status::Forbidden, UserError::TotpRequired))
+            }
+            Ok(Enrollment::LockedOut(retry_after)) => {
+                req.local_cache(|| RetryAfter(retry_after));
+                request::Outcome::Failure((Status::TooManyRequests, UserError::RateLimited))
+            }
+            Err(e) => {
+                error!("Failed to check TOTP of key {}: {:?}", &key.id, e);
+                request::Outcome::Failure((Status::InternalServerError, UserError::Server))
             }
-        } else {
-            request::Outcome::Failure((Status::BadRequest, UserError::MissingHeader))
         }
     }
 }
 
+enum Enrollment {
+    Enrolled,
+    /// The TOTP code is missing or wrong
+    Refused,
+    /// The user is locked out for this many more seconds
+    LockedOut(u64),
+}
+
+/// Enroll the device of `key` if its user has TOTP enabled
+fn enroll(req: &Request<'_>, key: &ApiKey) -> GenericResult<Enrollment> {
+    let db = req
+        .rocket()
+        .state::<Databases>()
+        .expect("Rocket instance contains managed state for databases");
+    let config = req
+        .rocket()
+        .state::<Config>()
+        .expect("Rocket instance contains managed state for server config");
+    let cipher = req
+        .rocket()
+        .state::<TotpCipher>()
+        .expect("Rocket instance contains managed state for TOTP cipher");
+    let totp = match db.user.get_totp(&key.alias)? {
+        Some(totp) if totp.enabled => totp,
+        _ => return Ok(Enrollment::Enrolled),
+    };
+    let code = match req.headers().get_one(TOTP_HEADER) {
+        Some(code) => code.trim(),
+        None => return Ok(Enrollment::Refused),
+    };
+    let limiter = RateLimiter::new(&config.rate_limit, db.user.as_ref());
+    let subject = user_subject(&key.alias);
+    if let Some(retry_after) = limiter.locked_out(&subject)? {
+        return Ok(Enrollment::LockedOut(retry_after));
+    }
+    if !check_totp(db.user.as_ref(), cipher, &key.alias, &totp, code)? {
+        warn!("Device {} sent a wrong TOTP code", &key.id);
+        limiter.fail(&subject)?;
+        audit::record(
+            db,
+            &AuditEntry {
+                alias: Some(key.alias.to_owned()),
+                device: Some(key.id.to_owned()),
+                ..anonymous_entry(req, AuditAction::TotpFailure)
+            },
+        );
+        return Ok(Enrollment::Refused);
+    }
+    limiter.succeed(&subject)?;
+    db.user.verify_device(&key.id)?;
+    info!(
+        "Enrolled device {} of user {} with TOTP",
+        &key.id, &key.alias
+    );
+    Ok(Enrollment::Enrolled)
+}
+
 #[derive(Debug)]
 pub enum UserError {
     MissingHeader,
     MissingUser,
+    SessionToken,
+    Certificate,
+    ProofRequired,
+    MissingScope(Scope),
+    TotpRequired,
+    RateLimited,
+    Server,
 }
 
 impl Display for UserError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            UserError::MissingHeader => write!(f, "Missing user key in authentication header"),
+            UserError::MissingHeader => write!(f, "Missing user key in authorization header"),
             UserError::MissingUser => write!(f, "User key does not exist"),
+            UserError::SessionToken => write!(f, "Expected a device key, not a session token"),
+            UserError::Certificate => write!(f, "Expected a device key, not a client certificate"),
+            UserError::ProofRequired => {
+                write!(f, "Vault access requires proof of the master password")
+            }
+            UserError::MissingScope(scope) => write!(f, "Key lacks the {} scope", scope),
+            UserError::TotpRequired => write!(f, "Enrolling the device requires a TOTP code"),
+            UserError::RateLimited => write!(f, "Too many failed authentication attempts"),
+            UserError::Server => write!(f, "Failed to look up user key"),
         }
     }
 }
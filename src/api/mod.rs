@@ -1,4 +1,13 @@
+pub mod catchers;
+pub mod compression;
 pub mod db_types;
+// Route attributes re-export a `uri!` macro per route, which only tests use
+#[allow(unused_imports)]
 pub mod endpoints;
 pub mod guards;
+pub mod rate_limit;
 pub mod server;
+pub mod wire;
+
+#[cfg(test)]
+mod simulation;
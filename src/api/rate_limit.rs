@@ -0,0 +1,91 @@
+use rocket::Request;
+
+use crate::{
+    api::db_types::AuthFailures,
+    config::parse_config::RateLimitConfig,
+    database::traits::UserDatabase,
+    util::{session::now_secs, types::GenericResult},
+};
+
+/// Seconds a rate limited client has to wait, for the `Retry-After` header
+pub struct RetryAfter(pub u64);
+
+/// Subject of the failures of the client of a request
+pub fn client_subject(req: &Request<'_>) -> String {
+    match req.client_ip() {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".into(),
+    }
+}
+
+/// Subject of the failures to prove the master password of a user
+pub fn user_subject(alias: &str) -> String {
+    format!("user:{}", alias)
+}
+
+/// Subject of the failures to guess the secret of a key of a user
+///
+/// Kept apart from [`user_subject`], so that wrong proofs do not lock out the keys.
+pub fn user_keys_subject(alias: &str) -> String {
+    format!("keys:{}", alias)
+}
+
+/// Locks out clients and users after repeated authentication failures
+///
+/// Failures are stored in the database so that lockouts survive restarts.
+pub struct RateLimiter<'a> {
+    config: &'a RateLimitConfig,
+    db: &'a dyn UserDatabase,
+}
+
+impl<'a> RateLimiter<'a> {
+    pub fn new(config: &'a RateLimitConfig, db: &'a dyn UserDatabase) -> Self {
+        Self { config, db }
+    }
+
+    /// Seconds until `subject` may try again, if it is locked out
+    pub fn locked_out(&self, subject: &str) -> GenericResult<Option<u64>> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let now = now_secs();
+        let failures = self.db.auth_failures(subject)?;
+        Ok((failures.locked_until > now).then(|| failures.locked_until - now))
+    }
+
+    /// Count a failure of `subject`, locking it out once it has too many
+    pub fn fail(&self, subject: &str) -> GenericResult<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let now = now_secs();
+        let mut failures = self.db.auth_failures(subject)?;
+        if failures.last_failure + self.config.max_lockout < now {
+            failures = AuthFailures::default();
+        }
+        failures.failures += 1;
+        failures.last_failure = now;
+        if failures.failures >= self.config.max_failures {
+            let doublings = (failures.failures - self.config.max_failures).min(32);
+            let lockout = self
+                .config
+                .lockout
+                .saturating_mul(1 << doublings)
+                .min(self.config.max_lockout);
+            failures.locked_until = now + lockout;
+            warn!(
+                "Locked out {} for {} seconds after {} failed attempts",
+                subject, lockout, failures.failures
+            );
+        }
+        self.db.set_auth_failures(subject, &failures)
+    }
+
+    /// Forget the failures of `subject` after it authenticated
+    pub fn succeed(&self, subject: &str) -> GenericResult<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        self.db.clear_auth_failures(subject)
+    }
+}
@@ -1,44 +1,283 @@
-use rocket::{Build, Rocket};
+use std::{sync::Arc, time::Duration};
+
+#[cfg(unix)]
+use rocket::tokio::signal::unix::{signal, SignalKind};
+use rocket::{
+    config::{MutualTls, TlsConfig},
+    fairing::AdHoc,
+    figment::Figment,
+    tokio::{
+        self,
+        sync::oneshot::{self, Sender},
+    },
+    Build, Rocket, Shutdown,
+};
 
 use crate::{
     config::parse_config::Config,
-    database::{sqlite::SqliteDatabase, traits::Databases},
+    database::{
+        accounts::{import_client_certificates, import_config_users},
+        notify::{NotificationHub, NotifyingCache},
+        sqlite::SqliteDatabase,
+        traits::Databases,
+    },
+    util::{
+        proof::Challenges,
+        session::{now_secs, SessionSigner},
+        totp::TotpCipher,
+    },
 };
 
+use super::catchers::{forbidden, too_many_requests, unauthorized};
+use super::compression::Compression;
 use super::endpoints::{
-    init::initialize_user, init_import::get_user, init_upload::user_initial_upload,
-    sync::sync_user, test_reset::reset_databases,
+    account::{cancel_deletion, delete_account, delete_due_accounts},
+    admin::{
+        admin_add_user, admin_backup, admin_force_resync, admin_issue_key, admin_list_keys,
+        admin_list_users, admin_prune_cache, admin_remove_user, admin_rename_user,
+        admin_revoke_key,
+    },
+    audit::user_audit,
+    devices::{list_devices, revoke_device, rotate_device_key},
+    events::{sync_events, wait_for_state},
+    export::export_store,
+    init::initialize_user,
+    init_import::get_user,
+    init_upload::{
+        begin_replacement_upload, begin_upload, expire_stale_uploads, finish_upload, upload_chunk,
+        upload_progress, user_initial_upload,
+    },
+    rekey::rekey_user,
+    reset::reset_vault,
+    session::{challenge, login, prove},
+    sync::sync_user,
+    test_reset::reset_databases,
+    totp::{confirm_totp, disable_totp, setup_totp},
 };
 
+/// Rocket's config from `Rocket.toml` and the environment, with the listen address, port
+/// and TLS settings of `config` merged in
+fn figment(config: &Config) -> Figment {
+    let mut figment = rocket::Config::figment();
+    if let Some(address) = config.address {
+        figment = figment.merge(("address", address));
+    }
+    if let Some(port) = config.port {
+        figment = figment.merge(("port", port));
+    }
+    if let Some(tls) = &config.tls {
+        let mut tls_config = TlsConfig::from_paths(&tls.certs, &tls.key);
+        if let Some(client_ca) = &tls.client_ca {
+            tls_config =
+                tls_config.with_mutual(MutualTls::from_path(client_ca).mandatory(tls.mandatory));
+        }
+        figment = figment.merge(("tls", tls_config));
+    }
+    figment
+}
+
+/// State that outlives a relaunch on `SIGHUP`, so that sessions, pending challenges and
+/// subscribers to state changes are kept
+#[derive(Clone)]
+pub struct ServerState {
+    hub: Arc<NotificationHub>,
+    signer: SessionSigner,
+    challenges: Challenges,
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        Self {
+            hub: Arc::new(NotificationHub::new()),
+            signer: SessionSigner::new(),
+            challenges: Challenges::new(),
+        }
+    }
+}
+
+/// Server with fresh `ServerState`
+#[cfg(test)]
 pub fn build_server(config: Config) -> Rocket<Build> {
+    build_server_with_state(config, ServerState::new())
+}
+
+pub fn build_server_with_state(config: Config, state: ServerState) -> Rocket<Build> {
     let enable_test_routes = config.enable_test_routes.to_owned();
+    let compression = config
+        .compression
+        .enabled
+        .then(|| Compression::new(&config.compression));
     let sqlite_store = SqliteDatabase::new(&config.db_directory);
     let sqlite_cache = SqliteDatabase::new(&config.db_directory);
     let sqlite_user = SqliteDatabase::new(&config.db_directory);
-    rocket::build()
+    let ServerState {
+        hub,
+        signer,
+        challenges,
+    } = state;
+    let totp_cipher = TotpCipher::new(config.auth.totp_key.as_deref())
+        .expect("TOTP key is validated with the config");
+    let rocket = rocket::custom(figment(&config))
         .manage(Databases::new(
             Box::new(sqlite_store),
-            Box::new(sqlite_cache),
+            Box::new(NotifyingCache::new(Box::new(sqlite_cache), hub.clone())),
             Box::new(sqlite_user),
         ))
+        .manage(hub)
+        .manage(signer)
+        .manage(challenges)
+        .manage(totp_cipher)
         .manage(config)
         .mount(
             "/",
-            if enable_test_routes {
-                routes![
-                    initialize_user,
-                    user_initial_upload,
-                    sync_user,
-                    get_user,
-                    reset_databases
-                ]
-            } else {
-                routes![initialize_user, user_initial_upload, sync_user, get_user]
-            },
+            routes![
+                initialize_user,
+                user_initial_upload,
+                begin_upload,
+                begin_replacement_upload,
+                upload_chunk,
+                upload_progress,
+                finish_upload,
+                sync_user,
+                sync_events,
+                wait_for_state,
+                export_store,
+                get_user,
+                rekey_user,
+                reset_vault,
+                login,
+                challenge,
+                prove,
+                list_devices,
+                revoke_device,
+                rotate_device_key,
+                user_audit,
+                delete_account,
+                cancel_deletion,
+                setup_totp,
+                confirm_totp,
+                disable_totp,
+                admin_list_users,
+                admin_add_user,
+                admin_remove_user,
+                admin_rename_user,
+                admin_list_keys,
+                admin_issue_key,
+                admin_revoke_key,
+                admin_force_resync,
+                admin_prune_cache,
+                admin_backup
+            ],
         )
+        .register("/", catchers![unauthorized, forbidden, too_many_requests])
+        .attach(AdHoc::try_on_ignite(
+            "Import config users",
+            |rocket| async {
+                let db = rocket.state::<Databases>().expect("Managed databases");
+                let config = rocket.state::<Config>().expect("Managed server config");
+                let clients = config.tls.as_ref().map_or(&[][..], |tls| &tls.clients);
+                let imported = import_config_users(db.user.as_ref(), &config.users)
+                    .and_then(|_| import_client_certificates(db.user.as_ref(), clients));
+                match imported {
+                    Ok(_) => Ok(rocket),
+                    Err(e) => {
+                        error!("Failed to import users from config: {:?}", e);
+                        Err(rocket)
+                    }
+                }
+            },
+        ));
+    let rocket = if enable_test_routes {
+        rocket.mount("/", routes![reset_databases])
+    } else {
+        rocket
+    };
+    match compression {
+        Some(compression) => rocket.attach(compression),
+        None => rocket,
+    }
+}
+
+/// Seconds between checks for accounts whose deletion is due and stale uploads
+const MAINTENANCE_INTERVAL: u64 = 60;
+
+/// Serve until shut down, relaunching with a newly read config on `SIGHUP`
+///
+/// Rocket only loads TLS certificates on launch, so reloading restarts the server,
+/// keeping the `ServerState`. If the new config cannot be read, the running server is
+/// kept.
+pub async fn launch_server<F>(read_config: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Fn() -> anyhow::Result<Config> + Send + Sync + 'static,
+{
+    let read_config = Arc::new(read_config);
+    let mut config = read_config()?;
+    let state = ServerState::new();
+    loop {
+        let maintenance = tokio::spawn(run_maintenance(SqliteDatabase::new(&config.db_directory)));
+        let rocket = build_server_with_state(config, state.clone())
+            .ignite()
+            .await?;
+        let (sender, mut receiver) = oneshot::channel();
+        let reload = tokio::spawn(reload_on_hangup(
+            read_config.clone(),
+            rocket.shutdown(),
+            sender,
+        ));
+        let _rocket = rocket.launch().await?;
+        reload.abort();
+        maintenance.abort();
+        match receiver.try_recv() {
+            Ok(reloaded) => {
+                info!("Relaunching with the reloaded config");
+                config = reloaded;
+            }
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// Delete accounts once their grace period is over and forget abandoned uploads
+async fn run_maintenance(db: SqliteDatabase) {
+    let mut interval = tokio::time::interval(Duration::from_secs(MAINTENANCE_INTERVAL));
+    loop {
+        interval.tick().await;
+        delete_due_accounts(&db, now_secs());
+        expire_stale_uploads(&db, &db, now_secs());
+    }
+}
+
+/// Read the config again on `SIGHUP` and shut the server down to relaunch with it
+#[cfg(unix)]
+async fn reload_on_hangup<F>(read_config: Arc<F>, shutdown: Shutdown, sender: Sender<Config>)
+where
+    F: Fn() -> anyhow::Result<Config>,
+{
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(
+                "Failed to listen for SIGHUP, reloading is disabled: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config and certificates");
+        match read_config() {
+            Ok(config) => {
+                if sender.send(config).is_ok() {
+                    shutdown.notify();
+                }
+                return;
+            }
+            Err(e) => error!("Failed to reload config, keeping the current one: {:?}", e),
+        }
+    }
 }
 
-pub async fn launch_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let _rocket = build_server(config).launch().await?;
-    Ok(())
+#[cfg(not(unix))]
+async fn reload_on_hangup<F>(_read_config: Arc<F>, _shutdown: Shutdown, _sender: Sender<Config>) {
+    std::future::pending::<()>().await
 }
@@ -0,0 +1,337 @@
+//! Deterministic simulation of several devices syncing against one server.
+//!
+//! Every device edits its own copy of the vault offline and syncs at random points,
+//! all driven by a seeded rng so that failures can be replayed. The server is only
+//! reached through its public endpoints.
+
+use std::collections::{BTreeMap, HashMap};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rocket::{
+    http::{Accept, ContentType, Header},
+    local::blocking::Client,
+};
+use serde_json::json;
+
+use crate::{
+    api::{
+        db_types::{Credential, Mutation},
+        endpoints::{export::ExportResponse, init_upload::InitUploadResponse, sync::SyncResponse},
+        server::build_server,
+        wire::Format,
+    },
+    config::parse_config::{Config, User},
+    util::key::hash_key,
+};
+
+/// Small id space so that devices regularly create credentials with clashing ids
+const ID_POOL: usize = 24;
+
+type Store = BTreeMap<String, String>;
+
+struct Device {
+    name: String,
+    format: Format,
+    state_id: String,
+    store: Store,
+    pending: Vec<Mutation>,
+}
+
+impl Device {
+    fn new(name: String, format: Format) -> Self {
+        Self {
+            name,
+            format,
+            state_id: String::new(),
+            store: Store::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, rng: &mut StdRng, step: usize) {
+        let free: Vec<String> = (0..ID_POOL)
+            .map(|i| format!("cred-{i}"))
+            .filter(|id| !self.store.contains_key(id))
+            .collect();
+        if free.is_empty() {
+            return;
+        }
+        let id = free[rng.gen_range(0..free.len())].to_owned();
+        let value = format!("{}-add-{}", self.name, step);
+        self.store.insert(id.to_owned(), value.to_owned());
+        self.pending.push(Mutation::Add {
+            credential: Credential { id, value },
+        });
+    }
+
+    fn modify(&mut self, rng: &mut StdRng, step: usize) {
+        if let Some(id) = self.pick(rng) {
+            let value = format!("{}-modify-{}", self.name, step);
+            self.store.insert(id.to_owned(), value.to_owned());
+            self.pending.push(Mutation::Modify {
+                credential: Credential { id, value },
+            });
+        }
+    }
+
+    fn delete(&mut self, rng: &mut StdRng) {
+        if let Some(id) = self.pick(rng) {
+            let value = self.store.remove(&id).unwrap_or_default();
+            self.pending.push(Mutation::Delete {
+                credential: Credential { id, value },
+            });
+        }
+    }
+
+    fn pick(&self, rng: &mut StdRng) -> Option<String> {
+        if self.store.is_empty() {
+            return None;
+        }
+        let index = rng.gen_range(0..self.store.len());
+        self.store.keys().nth(index).cloned()
+    }
+
+    /// Sends pending mutations and applies the response the way a client is expected to
+    fn sync(&mut self, client: &Client, oracle: &mut Oracle) {
+        let request = json!({
+            "state_id": &self.state_id,
+            "mutations": &self.pending,
+        });
+        let response = client
+            .post("/sync")
+            .header(auth_header())
+            .header(ContentType(self.format.media_type()))
+            .header(Accept::from(self.format.media_type()))
+            .body(self.format.encode(&request).expect("Encoded sync request"))
+            .dispatch();
+        let body: SyncResponse = self
+            .format
+            .decode(&response.into_bytes().expect("Sync response body"))
+            .expect("Valid sync response");
+        assert_eq!(body.status, "success", "{} failed to sync", self.name);
+
+        let id_changes = body.id_changes.unwrap_or_default();
+        oracle.acknowledge(&self.pending, &id_changes);
+        self.pending.clear();
+
+        for (id, new_id) in id_changes {
+            if let Some(value) = self.store.remove(&id) {
+                self.store.insert(new_id, value);
+            }
+        }
+        if let Some(store) = body.store {
+            self.store = store.into_iter().map(|c| (c.id, c.value)).collect();
+        }
+        if let Some(export) = body.export {
+            self.store = paged_export(client, &export, self.format);
+        }
+        // Like real clients, reject additions of known and changes of unknown credentials
+        for mutation in body.mutations.unwrap_or_default() {
+            match mutation {
+                Mutation::Add { credential } => {
+                    let existing = self.store.insert(credential.id.clone(), credential.value);
+                    assert!(
+                        existing.is_none(),
+                        "{} got an add of known credential {}",
+                        self.name,
+                        credential.id
+                    );
+                }
+                Mutation::Modify { credential } => {
+                    let existing = self.store.insert(credential.id.clone(), credential.value);
+                    assert!(
+                        existing.is_some(),
+                        "{} got a modification of unknown credential {}",
+                        self.name,
+                        credential.id
+                    );
+                }
+                // The client may have deleted the credential itself in the meantime
+                Mutation::Delete { credential } => {
+                    self.store.remove(&credential.id);
+                }
+            }
+        }
+        self.state_id = body.state_id.expect("Sync response state id");
+    }
+}
+
+/// Expected server store, built by replaying every acknowledged mutation
+/// under the documented override rules
+#[derive(Default)]
+struct Oracle {
+    store: Store,
+    acknowledged: usize,
+}
+
+impl Oracle {
+    fn acknowledge(&mut self, mutations: &[Mutation], id_changes: &[(String, String)]) {
+        let mut id_changes = id_changes.iter();
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        for mutation in mutations {
+            let id = mutation.credential().id.to_owned();
+            let id = renamed.get(&id).cloned().unwrap_or(id);
+            match mutation {
+                Mutation::Add { credential } => {
+                    let id = if self.store.contains_key(&id) {
+                        let (old_id, new_id) = id_changes
+                            .next()
+                            .expect("Clashing add is reported as an id change");
+                        assert_eq!(old_id, &id);
+                        assert!(!self.store.contains_key(new_id));
+                        renamed.insert(id, new_id.to_owned());
+                        new_id.to_owned()
+                    } else {
+                        id
+                    };
+                    self.store.insert(id, credential.value.to_owned());
+                }
+                // Modifications of credentials deleted elsewhere are dropped
+                Mutation::Modify { credential } => {
+                    if let Some(value) = self.store.get_mut(&id) {
+                        *value = credential.value.to_owned();
+                    }
+                }
+                Mutation::Delete { .. } => {
+                    self.store.remove(&id);
+                }
+            }
+        }
+        assert!(id_changes.next().is_none(), "Unexpected id change");
+        self.acknowledged += mutations.len();
+    }
+}
+
+fn auth_header() -> Header<'static> {
+    Header::new("Authentication", "sim")
+}
+
+fn init_test_config(dir: &str, export_page_size: u32) -> Config {
+    Config {
+        users: vec![User {
+            alias: "sim".into(),
+            keys: vec![hash_key("sim").unwrap()],
+        }],
+        export_page_size,
+        ..Config::in_test_directory(dir)
+    }
+}
+
+/// Downloads every page of the export starting at `uri`
+fn paged_export(client: &Client, uri: &str, format: Format) -> Store {
+    let mut store = Store::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let page_uri = match &cursor {
+            Some(cursor) => format!("{uri}?cursor={cursor}"),
+            None => uri.to_string(),
+        };
+        let response = client
+            .get(page_uri)
+            .header(auth_header())
+            .header(Accept::from(format.media_type()))
+            .dispatch();
+        let body: ExportResponse = format
+            .decode(&response.into_bytes().expect("Export response body"))
+            .expect("Valid export response");
+        assert_eq!(body.status, "success");
+        for credential in body.credentials.unwrap_or_default() {
+            store.insert(credential.id, credential.value);
+        }
+        cursor = body.next_cursor;
+        if cursor.is_none() {
+            return store;
+        }
+    }
+}
+
+/// Fetches the entire server store through a sync with an unknown state
+fn server_store(client: &Client) -> Store {
+    let response = client
+        .post("/sync")
+        .header(auth_header())
+        .body(json!({"state_id": "unknown", "mutations": []}).to_string())
+        .dispatch();
+    let body: SyncResponse = response.into_json().expect("Valid sync response");
+    match (body.store, body.export) {
+        (Some(store), _) => store.into_iter().map(|c| (c.id, c.value)).collect(),
+        (None, Some(export)) => paged_export(client, &export, Format::Json),
+        _ => panic!("Missing store for unknown state"),
+    }
+}
+
+fn simulate(seed: u64, device_count: usize, steps: usize, export_page_size: u32) {
+    let config = init_test_config(&format!("test/simulation/seed_{seed}"), export_page_size);
+    let client = Client::tracked(build_server(config)).expect("Valid rocket instance");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut oracle = Oracle::default();
+
+    let init = client
+        .post("/init/upload")
+        .header(auth_header())
+        .body(json!([]).to_string())
+        .dispatch();
+    let init: InitUploadResponse = init.into_json().expect("Valid init response");
+
+    let mut devices: Vec<Device> = (0..device_count)
+        .map(|i| {
+            // Half of the devices speak CBOR, which must not change the outcome
+            let format = match i % 2 {
+                0 => Format::Json,
+                _ => Format::Cbor,
+            };
+            Device::new(format!("device{i}"), format)
+        })
+        .collect();
+    devices[0].state_id = init.state_id.expect("Init state id");
+
+    for step in 0..steps {
+        let device = &mut devices[rng.gen_range(0..device_count)];
+        match rng.gen_range(0..100) {
+            0..=29 => device.add(&mut rng, step),
+            30..=54 => device.modify(&mut rng, step),
+            55..=69 => device.delete(&mut rng),
+            _ => device.sync(&client, &mut oracle),
+        }
+    }
+
+    // Push everything that is left, then let every device catch up
+    for device in devices.iter_mut() {
+        device.sync(&client, &mut oracle);
+    }
+    for device in devices.iter_mut() {
+        device.sync(&client, &mut oracle);
+    }
+
+    let server = server_store(&client);
+    assert!(oracle.acknowledged > 0, "Seed {seed} synced no mutations");
+    assert_eq!(server, oracle.store, "Seed {seed}: server lost mutations");
+    for device in &devices {
+        assert_eq!(
+            device.store, server,
+            "Seed {seed}: {} did not converge",
+            device.name
+        );
+    }
+}
+
+#[test]
+fn two_devices_converge() {
+    for seed in 0..8 {
+        simulate(seed, 2, 120, 500);
+    }
+}
+
+#[test]
+fn many_devices_converge() {
+    for seed in 100..106 {
+        simulate(seed, 5, 250, 500);
+    }
+}
+
+#[test]
+fn paged_export_converges() {
+    for seed in 200..204 {
+        simulate(seed, 3, 200, 3);
+    }
+}
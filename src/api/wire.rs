@@ -0,0 +1,223 @@
+use std::{
+    cell::Cell,
+    io::{self, Cursor},
+    ops::{Deref, DerefMut},
+};
+
+use rocket::{
+    data::{self, Data, FromData, Limits},
+    http::{ContentType, MediaType, Status},
+    response::{self, Responder},
+    Request, Response,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use super::compression::Encoding;
+
+/// Media type parameter of `Accept` by which CBOR clients ask for credential values that
+/// are base64 as byte strings
+const VALUE_BYTES_PARAM: (&str, &str) = ("values", "bytes");
+
+thread_local! {
+    /// Set while a response is encoded for a client that asked for byte string values
+    static VALUE_BYTES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the response being encoded carries credential values as byte strings
+pub fn value_bytes() -> bool {
+    VALUE_BYTES.with(Cell::get)
+}
+
+/// Encodings understood by endpoints using [`Wire`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    pub fn media_type(&self) -> MediaType {
+        match self {
+            Format::Json => MediaType::JSON,
+            Format::Cbor => MediaType::new("application", "cbor"),
+        }
+    }
+
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        if media_type.top() != "application" {
+            return None;
+        }
+        if media_type.sub() == "cbor" {
+            Some(Format::Cbor)
+        } else if media_type.sub() == "json" {
+            Some(Format::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Format of a request body, from its `Content-Type`
+    pub fn of_request(req: &Request<'_>) -> Self {
+        req.content_type()
+            .and_then(|content_type| Self::from_media_type(content_type.media_type()))
+            .unwrap_or(Format::Json)
+    }
+
+    /// Preferred format of a response, from the request's `Accept`
+    pub fn accepted_by(req: &Request<'_>) -> Self {
+        Self::accepted_media_type(req).map_or(Format::Json, |(format, _)| format)
+    }
+
+    /// Preferred format of a response with the media type it was accepted as
+    fn accepted_media_type<'r>(req: &'r Request<'_>) -> Option<(Self, &'r MediaType)> {
+        let mut media_types: Vec<_> = req.accept()?.iter().collect();
+        // Stable sort keeps the client's order between equal weights
+        media_types.sort_by(|a, b| b.weight_or(1.0).total_cmp(&a.weight_or(1.0)));
+        media_types.into_iter().find_map(|media_type| {
+            let media_type = media_type.media_type();
+            Self::from_media_type(media_type).map(|format| (format, media_type))
+        })
+    }
+
+    /// Whether the response to `req` is CBOR with credential values as byte strings
+    fn value_bytes_accepted(req: &Request<'_>) -> bool {
+        let (name, value) = VALUE_BYTES_PARAM;
+        match Self::accepted_media_type(req) {
+            Some((Format::Cbor, media_type)) => media_type
+                .params()
+                .any(|(key, param)| key == name && param == value),
+            _ => false,
+        }
+    }
+
+    fn limit_name(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Cbor => "cbor",
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(bytes)?),
+            Format::Cbor => {
+                ciborium::de::from_reader(bytes).map_err(|e| WireError::Cbor(format!("{:?}", e)))
+            }
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes)
+                    .map_err(|e| WireError::Cbor(format!("{:?}", e)))?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error("Failed to read request body")]
+    Io(#[from] io::Error),
+    #[error("Request body exceeds the data limit")]
+    TooLarge,
+    #[error("Unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid CBOR: {0}")]
+    Cbor(String),
+}
+
+/// Request body or response in JSON or CBOR
+///
+/// Bodies are read according to `Content-Type` and responses are written according to
+/// `Accept`, defaulting to JSON for both. Bodies may be compressed as given by
+/// `Content-Encoding`. Credential values are strings unless a CBOR client accepts
+/// `values=bytes`.
+#[derive(Debug)]
+pub struct Wire<T>(pub T);
+
+impl<T> Deref for Wire<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Wire<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for Wire<T> {
+    type Error = WireError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let format = Format::of_request(req);
+        let limit = req
+            .limits()
+            .get(format.limit_name())
+            .unwrap_or(Limits::JSON);
+        let encoding = match Encoding::of_request(req) {
+            Ok(encoding) => encoding,
+            Err(name) => {
+                return data::Outcome::Failure((
+                    Status::UnsupportedMediaType,
+                    WireError::UnsupportedEncoding(name),
+                ))
+            }
+        };
+        let too_large = || data::Outcome::Failure((Status::PayloadTooLarge, WireError::TooLarge));
+        let bytes = match data.open(limit).into_bytes().await {
+            Ok(bytes) if bytes.is_complete() => bytes.into_inner(),
+            Ok(_) => return too_large(),
+            Err(e) => return data::Outcome::Failure((Status::BadRequest, e.into())),
+        };
+        let bytes = match encoding.map(|encoding| encoding.decompress(&bytes, limit.as_u64())) {
+            None => bytes,
+            Some(Ok(bytes)) if bytes.len() as u64 > limit.as_u64() => return too_large(),
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => return data::Outcome::Failure((Status::BadRequest, e.into())),
+        };
+        match format.decode(&bytes) {
+            Ok(value) => data::Outcome::Success(Wire(value)),
+            Err(WireError::Json(e)) if e.classify() != serde_json::error::Category::Data => {
+                data::Outcome::Failure((Status::BadRequest, e.into()))
+            }
+            Err(e) => data::Outcome::Failure((Status::UnprocessableEntity, e)),
+        }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Wire<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let format = Format::accepted_by(req);
+        let value_bytes = Format::value_bytes_accepted(req);
+        VALUE_BYTES.with(|flag| flag.set(value_bytes));
+        let encoded = format.encode(&self.0);
+        VALUE_BYTES.with(|flag| flag.set(false));
+        let bytes = encoded.map_err(|e| {
+            error!("Failed to serialize response: {:?}", e);
+            Status::InternalServerError
+        })?;
+        let media_type = match value_bytes {
+            true => format.media_type().with_params(VALUE_BYTES_PARAM),
+            false => format.media_type(),
+        };
+        // Caches must not hand a response in one format to a client asking for another
+        Response::build()
+            .header(ContentType(media_type))
+            .raw_header_adjoin("Vary", "Accept")
+            .sized_body(bytes.len(), Cursor::new(bytes))
+            .ok()
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
 
+use crate::api::db_types::Scope;
+
 #[derive(Debug, Parser)]
 #[clap(author, version)]
 /// Personal sync server for Vult
@@ -25,6 +27,101 @@ pub enum Commands {
         test: bool,
     },
 
-    /// Run test functions
-    Test,
+    /// Generate a new API key and print it along with the hash to put in the config
+    GenerateKey,
+
+    /// Manage users
+    #[clap(alias = "users")]
+    User {
+        #[clap(subcommand)]
+        command: UserCommands,
+    },
+
+    /// Manage API keys of users
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommands,
+    },
+
+    /// Export or verify the audit log
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommands,
+    },
+
+    /// Remove all but the `cache_count` most recent states of every vault
+    Prune,
+
+    /// Rebuild every database to give the space of removed data back
+    Vacuum,
+
+    /// Print the encrypted store of a user as JSON, as devices upload it
+    Export { alias: String },
+
+    /// Import a store printed by `export` into the empty vault of a user
+    Import { alias: String, file: String },
+
+    /// Check the config and print it with key hashes redacted
+    CheckConfig,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommands {
+    /// Add a user that keys can be issued to
+    Add { alias: String },
+
+    /// List users with their number of keys and credentials and their last sync
+    List,
+
+    /// Show the vault size, last activity and keys of a user
+    Show { alias: String },
+
+    /// Remove a user and revoke all of its keys, keeping its vault
+    Remove { alias: String },
+
+    /// Give a user a new alias, keeping its keys and vault
+    Rename { alias: String, new_alias: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommands {
+    /// Issue a new key to a user and print it
+    Issue {
+        alias: String,
+
+        /// Name to tell the key apart, such as the device it is for
+        #[clap(short, long, default_value_t = String::new())]
+        name: String,
+
+        /// Scope granted to the key, such as `sync:read`, all scopes if none are given
+        #[clap(short, long = "scope")]
+        scopes: Vec<Scope>,
+
+        /// Days until the key expires, never if not given
+        #[clap(long)]
+        expires_in_days: Option<u64>,
+    },
+
+    /// List the keys of a user
+    List { alias: String },
+
+    /// Revoke a key by its id
+    Revoke { id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommands {
+    /// Print the audit log as JSON lines, oldest first
+    Export {
+        /// Only print the events of this user
+        #[clap(short, long)]
+        alias: Option<String>,
+
+        /// Only print events after this sequence number
+        #[clap(long, default_value_t = 0)]
+        after: u64,
+    },
+
+    /// Check that no event of the audit log was changed or deleted
+    Verify,
 }
@@ -1,31 +1,111 @@
-use std::{fmt::Display, fs::File, io::Read, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::File,
+    io::Read,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::util::error::Error;
+use crate::{
+    database::accounts::alias_problem,
+    util::{
+        certificate::certificate_key_hash,
+        error::Error,
+        key::{hash_plaintext_key, is_key_hash, key_hash_id},
+        totp::TotpCipher,
+    },
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    /// Users imported into the database on startup
+    #[serde(default)]
     pub users: Vec<User>,
+    /// Hashes of the keys accepted by the admin API
+    #[serde(default)]
+    pub admin_keys: Vec<String>,
+    /// Number of most recent states of each vault kept when the cache is pruned
     #[serde(default = "default_cache_count")]
     pub cache_count: u32,
     #[serde(default = "default_db_directory")]
     pub db_directory: String,
+    /// Directory backups are written to, `backups` in `db_directory` if not set
+    #[serde(default)]
+    pub backup_directory: Option<String>,
+    #[serde(default = "default_export_page_size")]
+    pub export_page_size: u32,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Address to listen on, overriding `Rocket.toml`
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    /// Port to listen on, overriding `Rocket.toml`
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Serve over TLS instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Longest time in seconds a `/sync/wait` request is held open
+    #[serde(default = "default_long_poll_timeout")]
+    pub long_poll_timeout: u64,
     #[serde(skip)]
     pub enable_test_routes: bool,
 }
 
 impl Display for Config {
+    /// Pretty printed config with key hashes redacted
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut value = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        if let Some(keys) = value["admin_keys"].as_array_mut() {
+            keys.fill("<redacted>".into());
+        }
+        if value["auth"]["totp_key"].is_string() {
+            value["auth"]["totp_key"] = "<redacted>".into();
+        }
+        if let Some(users) = value["users"].as_array_mut() {
+            for user in users {
+                if let Some(keys) = user["keys"].as_array_mut() {
+                    keys.fill("<redacted>".into());
+                }
+            }
+        }
         write!(
             f,
             "{}",
-            serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?
+            serde_json::to_string_pretty(&value).map_err(|_| std::fmt::Error)?
         )
     }
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            admin_keys: Vec::new(),
+            cache_count: default_cache_count(),
+            db_directory: default_db_directory(),
+            backup_directory: None,
+            export_page_size: default_export_page_size(),
+            compression: Default::default(),
+            auth: Default::default(),
+            rate_limit: Default::default(),
+            address: None,
+            port: None,
+            tls: None,
+            long_poll_timeout: default_long_poll_timeout(),
+            enable_test_routes: false,
+        }
+    }
+}
+
 fn default_cache_count() -> u32 {
     100
 }
@@ -34,13 +114,321 @@ fn default_db_directory() -> String {
     String::from("./data")
 }
 
+fn default_export_page_size() -> u32 {
+    500
+}
+
+fn default_long_poll_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_enabled")]
+    pub enabled: bool,
+    /// Responses smaller than this many bytes are sent uncompressed
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_compression_enabled(),
+            min_size: default_compression_min_size(),
+        }
+    }
+}
+
+fn default_compression_enabled() -> bool {
+    true
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthConfig {
+    /// Accept keys in the nonstandard `Authentication` header of older clients
+    #[serde(default = "default_legacy_header")]
+    pub legacy_header: bool,
+    /// Seconds a session token from `/auth/login` stays valid
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: u64,
+    /// Only give access to a vault to sessions that proved the master password
+    #[serde(default)]
+    pub require_proof: bool,
+    /// Seconds a key stays valid after it is rotated
+    #[serde(default = "default_rotation_grace")]
+    pub rotation_grace: u64,
+    /// Seconds before its expiry that syncs with a key warn about it
+    #[serde(default = "default_expiry_warning")]
+    pub expiry_warning: u64,
+    /// Base64 encoded 32 byte key that TOTP secrets are encrypted with
+    #[serde(default)]
+    pub totp_key: Option<String>,
+    /// Seconds a deleted account can be restored for, deleting it at once if zero
+    #[serde(default)]
+    pub deletion_grace: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            legacy_header: default_legacy_header(),
+            session_lifetime: default_session_lifetime(),
+            require_proof: false,
+            rotation_grace: default_rotation_grace(),
+            expiry_warning: default_expiry_warning(),
+            totp_key: None,
+            deletion_grace: 0,
+        }
+    }
+}
+
+fn default_legacy_header() -> bool {
+    true
+}
+
+fn default_session_lifetime() -> u64 {
+    900
+}
+
+fn default_rotation_grace() -> u64 {
+    600
+}
+
+fn default_expiry_warning() -> u64 {
+    7 * 24 * 3600
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_rate_limit_enabled")]
+    pub enabled: bool,
+    /// Failed attempts allowed before a client or user is locked out
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    /// Seconds of the first lockout, doubled with every further failure
+    #[serde(default = "default_lockout")]
+    pub lockout: u64,
+    /// Longest lockout in seconds, after which old failures are also forgotten
+    #[serde(default = "default_max_lockout")]
+    pub max_lockout: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_rate_limit_enabled(),
+            max_failures: default_max_failures(),
+            lockout: default_lockout(),
+            max_lockout: default_max_lockout(),
+        }
+    }
+}
+
+fn default_rate_limit_enabled() -> bool {
+    true
+}
+
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_lockout() -> u64 {
+    30
+}
+
+fn default_max_lockout() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// Path of the PEM encoded certificate chain of the server
+    pub certs: String,
+    /// Path of the PEM encoded private key of the server
+    pub key: String,
+    /// Path of the PEM encoded CA certificates that client certificates are verified
+    /// against, enabling client certificate authentication
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// Refuse connections without a valid client certificate
+    #[serde(default)]
+    pub mandatory: bool,
+    /// Client certificates accepted in place of a key
+    #[serde(default)]
+    pub clients: Vec<ClientCertificate>,
+}
+
+/// Client certificate of a device of user `alias`, identified by the SHA-256 fingerprint
+/// of its public key or by its subject
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientCertificate {
+    pub alias: String,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct User {
     pub alias: String,
+    /// Hashes of the user's API keys
     pub keys: Vec<String>,
 }
 
 impl Config {
+    /// Default config on the emptied data directory `dir`
+    #[cfg(test)]
+    pub fn in_test_directory(dir: &str) -> Self {
+        if Path::new(dir).exists() {
+            std::fs::remove_dir_all(dir).expect("Remove test data directory");
+        }
+        std::fs::create_dir_all(dir).expect("Create test data directory");
+        Self {
+            db_directory: dir.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Directory backups are written to
+    pub fn backup_path(&self) -> PathBuf {
+        match &self.backup_directory {
+            Some(directory) => directory.into(),
+            None => Path::new(&self.db_directory).join("backups"),
+        }
+    }
+
+    /// Replace the keys given in plain text by their hash, warning with the hash to use
+    ///
+    /// Configs used to list keys in plain text, which are still accepted.
+    pub fn hash_plaintext_keys(&mut self) -> Result<()> {
+        for user in &mut self.users {
+            for (position, key) in user.keys.iter_mut().enumerate() {
+                if !is_key_hash(key) {
+                    *key = hash_plaintext_key(key)?;
+                    warn!(
+                        "Key {} of user {} is not hashed, replace it in the config with \"{}\"",
+                        position + 1,
+                        &user.alias,
+                        key
+                    );
+                }
+            }
+        }
+        for (position, key) in self.admin_keys.iter_mut().enumerate() {
+            if !is_key_hash(key) {
+                *key = hash_plaintext_key(key)?;
+                warn!(
+                    "Admin key {} is not hashed, replace it in the config with \"{}\"",
+                    position + 1,
+                    key
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the config for problems that would otherwise surface as silently ignored
+    /// users or keys, reporting all of them at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        let mut aliases = HashSet::new();
+        // Keys are told apart by their id. Hashes of keys without one are salted, so such
+        // a key is only found twice if the same hash is given twice.
+        let mut key_owners: HashMap<&str, String> = HashMap::new();
+        for (index, user) in self.users.iter().enumerate() {
+            if let Some(problem) = alias_problem(&user.alias) {
+                problems.push(format!(
+                    "User {} has the alias {:?}, which cannot be used because {}",
+                    index + 1,
+                    &user.alias,
+                    problem
+                ));
+            }
+            if !aliases.insert(user.alias.as_str()) {
+                problems.push(format!(
+                    "User {} is configured more than once, merge its keys into one entry",
+                    &user.alias
+                ));
+            }
+            for (position, key) in user.keys.iter().enumerate() {
+                let owner = format!("key {} of user {}", position + 1, &user.alias);
+                if !is_key_hash(key) {
+                    problems.push(format!(
+                        "Key {} of user {} is not a key hash, generate one with `vult-server generate-key`",
+                        position + 1,
+                        &user.alias
+                    ));
+                } else if let Some(first) = key_owners.get(key_hash_id(key).unwrap_or(key)) {
+                    problems.push(format!("The same key is given as {} and {}", first, owner));
+                } else {
+                    key_owners.insert(key_hash_id(key).unwrap_or(key), owner);
+                }
+            }
+        }
+
+        for (position, key) in self.admin_keys.iter().enumerate() {
+            let owner = format!("admin key {}", position + 1);
+            if !is_key_hash(key) {
+                problems.push(format!(
+                    "Admin key {} is not a key hash, generate one with `vult-server generate-key`",
+                    position + 1
+                ));
+            } else if let Some(first) = key_owners.get(key_hash_id(key).unwrap_or(key)) {
+                problems.push(format!("The same key is given as {} and {}", first, owner));
+            } else {
+                key_owners.insert(key_hash_id(key).unwrap_or(key), owner);
+            }
+        }
+
+        if let Err(e) = TotpCipher::new(self.auth.totp_key.as_deref()) {
+            problems.push(format!("Invalid TOTP key: {:#}", e));
+        }
+
+        if let Some(tls) = &self.tls {
+            if !tls.clients.is_empty() && tls.client_ca.is_none() {
+                problems.push("Client certificates require a client CA in tls.client_ca".into());
+            }
+            let mut identities: HashMap<String, &str> = HashMap::new();
+            for client in &tls.clients {
+                if let Some(problem) = alias_problem(&client.alias) {
+                    problems.push(format!(
+                        "Client certificate of user {:?} cannot be used because {}",
+                        &client.alias, problem
+                    ));
+                }
+                if client.fingerprint.is_none() && client.subject.is_none() {
+                    problems.push(format!(
+                        "Client certificate of user {} needs a fingerprint or a subject",
+                        &client.alias
+                    ));
+                    continue;
+                }
+                let identity = certificate_key_hash(client);
+                if let Some(first) = identities.get(&identity) {
+                    problems.push(format!(
+                        "Client certificate of user {} is also given for user {}",
+                        first, &client.alias
+                    ));
+                } else {
+                    identities.insert(identity, &client.alias);
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(anyhow!("{}", problems.join("\n"))).into())
+        }
+    }
+
     pub fn read_config<T: AsRef<Path> + Display>(path: T) -> Result<Config> {
         let mut config_file = File::open(&path)
             .map_err(|e| Error::Config(e.into()))
@@ -51,8 +439,112 @@ impl Config {
             .map_err(|_e| Error::Config(_e.into()))
             .context("Failed to read config file")?;
 
-        let parsed = toml::from_str(&contents).context("Failed to parse config file")?;
+        let mut parsed: Config =
+            toml::from_str(&contents).context("Failed to parse config file")?;
 
+        parsed.hash_plaintext_keys()?;
+        parsed.validate()?;
         Ok(parsed)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::util::key::{generate_key, hash_key, verify_key};
+
+    use super::Config;
+
+    fn problems(contents: &str) -> Vec<String> {
+        let config: Config = toml::from_str(contents).expect("Parsable config");
+        match config.validate() {
+            Ok(_) => vec![],
+            Err(e) => format!("{:#}", e)
+                .trim_start_matches("Invalid server configuration: ")
+                .lines()
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let contents = format!(
+            "admin_keys = [\"{}\"]\n[[users]]\nalias = \"unit\"\nkeys = [\"{}\"]\n[[users]]\nalias = \"unit.test\"\nkeys = []",
+            hash_key("admin").unwrap(),
+            hash_key("unit").unwrap()
+        );
+        assert!(problems(&contents).is_empty());
+    }
+
+    #[test]
+    fn invalid_aliases() {
+        let contents = "[[users]]\nalias = \"../unit\"\nkeys = []\n[[users]]\nalias = \"\"\nkeys = []\n[[users]]\nalias = \"unit\"\nkeys = []\n[[users]]\nalias = \"unit\"\nkeys = []";
+        assert_eq!(
+            problems(contents),
+            vec![
+                "User 2 has the alias \"\", which cannot be used because it is empty",
+                "User unit is configured more than once, merge its keys into one entry",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_keys() {
+        let hash = hash_key("unit").unwrap();
+        let contents = format!(
+            "admin_keys = [\"{hash}\"]\n[[users]]\nalias = \"unit\"\nkeys = [\"{hash}\"]\n[[users]]\nalias = \"other\"\nkeys = [\"{}\", \"{hash}\"]",
+            hash_key("other").unwrap()
+        );
+        assert_eq!(
+            problems(&contents),
+            vec![
+                "The same key is given as key 1 of user unit and key 2 of user other",
+                "The same key is given as key 1 of user unit and admin key 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_keys_with_ids() {
+        let key = generate_key();
+        let contents = format!(
+            "admin_keys = [\"{}\"]\n[[users]]\nalias = \"unit\"\nkeys = [\"{}\", \"{}\"]",
+            hash_key(&key).unwrap(),
+            hash_key(&generate_key()).unwrap(),
+            hash_key(&key).unwrap()
+        );
+        assert_eq!(
+            problems(&contents),
+            vec!["The same key is given as key 2 of user unit and admin key 1"]
+        );
+    }
+
+    #[test]
+    fn shared_client_certificates() {
+        let contents = "[tls]\ncerts = \"certs.pem\"\nkey = \"key.pem\"\nclient_ca = \"ca.pem\"\n[[tls.clients]]\nalias = \"unit\"\nfingerprint = \"AB:CD\"\n[[tls.clients]]\nalias = \"other\"\nfingerprint = \"abcd\"\n[[tls.clients]]\nalias = \"\"\nsubject = \"CN=unit\"";
+        assert_eq!(
+            problems(contents),
+            vec![
+                "Client certificate of user unit is also given for user other",
+                "Client certificate of user \"\" cannot be used because it is empty",
+            ]
+        );
+    }
+
+    #[test]
+    fn plaintext_keys() {
+        let contents = "admin_keys = [\"admin\"]\n[[users]]\nalias = \"unit\"\nkeys = [\"unit\"]\n[[users]]\nalias = \"other\"\nkeys = [\"unit\"]";
+        let mut config: Config = toml::from_str(contents).unwrap();
+        config.hash_plaintext_keys().unwrap();
+        assert!(verify_key("admin", &config.admin_keys[0]));
+        assert!(verify_key("unit", &config.users[0].keys[0]));
+        // The same key always gets the same hash, so it is imported once and found twice
+        assert_eq!(config.users[0].keys, config.users[1].keys);
+        let hashed = config.users[0].keys.clone();
+        config.hash_plaintext_keys().unwrap();
+        assert_eq!(config.users[0].keys, hashed);
+        let problems = format!("{:#}", config.validate().unwrap_err());
+        assert!(problems
+            .contains("The same key is given as key 1 of user unit and key 1 of user other"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    api::db_types::{ApiKey, Scope, TotpState},
+    config::parse_config::{ClientCertificate, User},
+    util::{
+        certificate::certificate_key_hash,
+        error::Error,
+        key::{generate_key, hash_key},
+        session::now_secs,
+        totp::{verify_code, TotpCipher},
+        types::GenericResult,
+    },
+};
+
+use super::traits::UserDatabase;
+
+/// Reason `alias` cannot be given to a user, if any
+pub fn alias_problem(alias: &str) -> Option<&'static str> {
+    alias.is_empty().then_some("it is empty")
+}
+
+/// Name given to keys imported from the config
+pub const CONFIG_KEY_NAME: &str = "config";
+
+/// Add the user `alias` of the config unless it was imported before
+fn import_config_account(db: &dyn UserDatabase, alias: &str) -> GenericResult<()> {
+    let entry = format!("user {}", alias);
+    if db.config_imported(&entry)? {
+        return Ok(());
+    }
+    match db.add_account(alias) {
+        Ok(_) => info!("Imported user {} from config", alias),
+        Err(Error::ExistingUser(_)) => {}
+        Err(e) => return Err(e),
+    }
+    db.mark_config_imported(&entry)
+}
+
+/// Register the key `hash` of the config for `alias` unless it was imported before
+///
+/// Returns the id of the key if it was registered now.
+fn import_config_key(
+    db: &dyn UserDatabase,
+    alias: &str,
+    name: &str,
+    hash: &str,
+) -> GenericResult<Option<String>> {
+    let entry = format!("{} {} {}", name, hash, alias);
+    if db.config_imported(&entry)? {
+        return Ok(None);
+    }
+    let id = match db.add_key(alias, name, hash, &Scope::ALL, None) {
+        Ok(id) => Some(id),
+        Err(Error::ExistingKey) => match db.find_key_by_hash(hash)? {
+            // The config moved the key to another user
+            Some(key) if key.alias != alias => {
+                return Err(Error::Config(anyhow::anyhow!(
+                    "The {} key {} of user {} is registered to user {}, revoke it first",
+                    name,
+                    &key.id,
+                    alias,
+                    &key.alias
+                )))
+            }
+            _ => None,
+        },
+        // The user was removed after it was imported, so its new keys are left out
+        Err(Error::MissingUser(_)) => {
+            warn!("Not importing a {} key of removed user {}", name, alias);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    db.mark_config_imported(&entry)?;
+    Ok(id)
+}
+
+/// Add the users and key hashes of the config that were not imported before
+///
+/// Each user and key is imported once, so those removed from the database stay removed.
+pub fn import_config_users(db: &dyn UserDatabase, users: &[User]) -> GenericResult<()> {
+    for user in users {
+        import_config_account(db, &user.alias)?;
+        for hash in &user.keys {
+            if let Some(id) = import_config_key(db, &user.alias, CONFIG_KEY_NAME, hash)? {
+                info!("Imported key {} of user {} from config", &id, &user.alias);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name given to the keys of client certificates
+pub const CERTIFICATE_KEY_NAME: &str = "certificate";
+
+/// Register a device for each client certificate of the config that was not imported
+/// before, adding its user if needed
+pub fn import_client_certificates(
+    db: &dyn UserDatabase,
+    clients: &[ClientCertificate],
+) -> GenericResult<()> {
+    for client in clients {
+        import_config_account(db, &client.alias)?;
+        let hash = certificate_key_hash(client);
+        if let Some(id) = import_config_key(db, &client.alias, CERTIFICATE_KEY_NAME, &hash)? {
+            info!(
+                "Registered client certificate of user {} as key {}",
+                &client.alias, &id
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Generate a new key with `scopes` for the user `alias` and register its hash, valid
+/// until `expires` if given
+///
+/// Returns the id of the key and the key itself, which is not stored anywhere
+pub fn issue_key(
+    db: &dyn UserDatabase,
+    alias: &str,
+    name: &str,
+    scopes: &[Scope],
+    expires: Option<u64>,
+) -> GenericResult<(String, String)> {
+    let key = generate_key();
+    let hash = hash_key(&key).map_err(Error::Server)?;
+    let id = db.add_key(alias, name, &hash, scopes, expires)?;
+    Ok((id, key))
+}
+
+/// Key issued by [`rotate_key`]
+pub struct RotatedKey {
+    pub id: String,
+    pub key: String,
+    pub expires: Option<u64>,
+    /// New expiry of the replaced key
+    pub old_expires: u64,
+}
+
+/// Replace `old` with a new key that keeps its lifetime, leaving `old` valid for `grace`
+/// more seconds at most
+pub fn rotate_key(db: &dyn UserDatabase, old: &ApiKey, grace: u64) -> GenericResult<RotatedKey> {
+    let now = now_secs();
+    let expires = old
+        .expires
+        .map(|expires| now + expires.saturating_sub(old.created));
+    let old_expires = old.expires.map_or(now + grace, |old| old.min(now + grace));
+    let key = generate_key();
+    let hash = hash_key(&key).map_err(Error::Server)?;
+    let id = db.rotate_key(&old.id, &hash, expires, old_expires)?;
+    Ok(RotatedKey {
+        id,
+        key,
+        expires,
+        old_expires,
+    })
+}
+
+/// Check a TOTP code, or else a recovery code, of the user `alias` with setup `totp`
+///
+/// Valid codes are used up.
+pub fn check_totp(
+    db: &dyn UserDatabase,
+    cipher: &TotpCipher,
+    alias: &str,
+    totp: &TotpState,
+    code: &str,
+) -> GenericResult<bool> {
+    let secret = cipher.decrypt(alias, &totp.secret)?;
+    if let Some(step) = verify_code(&secret, code, totp.last_step)? {
+        return db.use_totp_step(alias, step);
+    }
+    db.use_recovery_code(alias, code)
+}
@@ -1,2 +1,4 @@
+pub mod accounts;
+pub mod notify;
 pub mod sqlite;
 pub mod traits;
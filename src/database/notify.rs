@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    api::db_types::{Mutation, NewStore},
+    util::types::GenericResult,
+};
+
+use super::traits::CacheDatabase;
+
+/// Number of state ids a slow subscriber can fall behind before missing some
+const CHANNEL_CAPACITY: usize = 16;
+
+type Channels = HashMap<String, broadcast::Sender<String>>;
+
+/// In-process publisher of new state ids per user
+#[derive(Default)]
+pub struct NotificationHub {
+    channels: Arc<Mutex<Channels>>,
+}
+
+impl NotificationHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every state id recorded for `alias` from now on
+    pub fn subscribe(&self, alias: &str) -> Subscription {
+        let mut channels = self.channels.lock().expect("Notification hub lock");
+        let receiver = channels
+            .entry(alias.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe();
+        Subscription {
+            receiver,
+            alias: alias.to_string(),
+            channels: self.channels.clone(),
+        }
+    }
+
+    pub fn publish(&self, alias: &str, state_id: &str) {
+        let mut channels = self.channels.lock().expect("Notification hub lock");
+        if let Some(sender) = channels.get(alias) {
+            if sender.send(state_id.to_string()).is_err() {
+                // Every subscriber is gone
+                channels.remove(alias);
+            }
+        }
+    }
+}
+
+/// State ids of one user from a [`NotificationHub`]
+///
+/// The channel of the user is removed when its last subscription is dropped.
+pub struct Subscription {
+    receiver: broadcast::Receiver<String>,
+    alias: String,
+    channels: Arc<Mutex<Channels>>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<String, RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock().expect("Notification hub lock");
+        // The receiver of this subscription is only dropped after this
+        if channels
+            .get(&self.alias)
+            .is_some_and(|sender| sender.receiver_count() <= 1)
+        {
+            channels.remove(&self.alias);
+        }
+    }
+}
+
+/// Cache that publishes every newly recorded state to a [`NotificationHub`]
+pub struct NotifyingCache {
+    inner: Box<dyn CacheDatabase + Send + Sync>,
+    hub: Arc<NotificationHub>,
+}
+
+impl NotifyingCache {
+    pub fn new(inner: Box<dyn CacheDatabase + Send + Sync>, hub: Arc<NotificationHub>) -> Self {
+        Self { inner, hub }
+    }
+}
+
+impl CacheDatabase for NotifyingCache {
+    fn add_mutations(&self, alias: &str, mutations: &[Mutation]) -> Result<String> {
+        let state_id = self.inner.add_mutations(alias, mutations)?;
+        self.hub.publish(alias, &state_id);
+        Ok(state_id)
+    }
+
+    fn has_state(&self, alias: &str, state: &str) -> GenericResult<bool> {
+        self.inner.has_state(alias, state)
+    }
+
+    fn latest_state(&self, alias: &str) -> GenericResult<Option<String>> {
+        self.inner.latest_state(alias)
+    }
+
+    fn get_next_mutations(&self, alias: &str, id: &str) -> GenericResult<Vec<Mutation>> {
+        self.inner.get_next_mutations(alias, id)
+    }
+
+    fn is_empty(&self, alias: &str) -> GenericResult<bool> {
+        self.inner.is_empty(alias)
+    }
+
+    fn replace_vault(
+        &self,
+        alias: &str,
+        state_id: &str,
+        salt: &str,
+        hash: &str,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)> {
+        let (state_id, count) = self
+            .inner
+            .replace_vault(alias, state_id, salt, hash, store)?;
+        self.hub.publish(alias, &state_id);
+        Ok((state_id, count))
+    }
+
+    fn reset_vault(
+        &self,
+        alias: &str,
+        state_id: Option<&str>,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)> {
+        let (state_id, count) = self.inner.reset_vault(alias, state_id, store)?;
+        self.hub.publish(alias, &state_id);
+        Ok((state_id, count))
+    }
+
+    fn finish_upload(&self, alias: &str, upload_id: &str) -> GenericResult<(String, u64)> {
+        let (state_id, count) = self.inner.finish_upload(alias, upload_id)?;
+        self.hub.publish(alias, &state_id);
+        Ok((state_id, count))
+    }
+
+    fn force_resync(&self, alias: &str) -> GenericResult<Option<String>> {
+        let state_id = self.inner.force_resync(alias)?;
+        if let Some(state_id) = &state_id {
+            self.hub.publish(alias, state_id);
+        }
+        Ok(state_id)
+    }
+
+    fn prune_cache(&self, alias: &str, keep: u32) -> GenericResult<u64> {
+        self.inner.prune_cache(alias, keep)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NotificationHub;
+
+    #[test]
+    fn channels_removed() {
+        let hub = NotificationHub::new();
+        let first = hub.subscribe("unit");
+        let second = hub.subscribe("unit");
+        let _other = hub.subscribe("other");
+        drop(first);
+        assert!(hub.channels.lock().unwrap().contains_key("unit"));
+        drop(second);
+        assert!(!hub.channels.lock().unwrap().contains_key("unit"));
+        assert!(hub.channels.lock().unwrap().contains_key("other"));
+    }
+}
@@ -1,22 +1,44 @@
 use std::time::SystemTime;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
 
-use crate::api::db_types::{Credential, DbMutation, Mutation};
+use crate::api::db_types::{
+    Account, ApiKey, AuditEntry, AuditEvent, AuthFailures, Credential, DbMutation, Mutation,
+    NewStore, Scope, TotpState, VaultStats,
+};
+use crate::util::audit::{event_hash, GENESIS_HASH};
 use crate::util::error::Error;
-use crate::util::id::random_b64;
+use crate::util::id::{random_b64, random_b64_url, random_hex};
+use crate::util::key::{key_hash_id, key_id, verify_key};
+use crate::util::totp::TotpCipher;
 use crate::util::types::GenericResult;
 
-use super::traits::{CacheDatabase, StoreDatabase, UserDatabase};
+use super::{
+    accounts::alias_problem,
+    traits::{CacheDatabase, StoreDatabase, UserDatabase},
+};
 
 pub struct SqliteDatabase {
     directory: PathBuf,
 }
 
-fn get_db_path(alias: &str) -> String {
-    format!("{}.sqlite", alias)
+fn get_db_path(name: &str) -> String {
+    format!("{}.sqlite", name)
+}
+
+const INTERNAL_DB: &str = "vult.internal";
+
+/// Id of the vault of `alias`, if it has one
+fn vault_id(db: &rusqlite::Connection, alias: &str) -> rusqlite::Result<Option<String>> {
+    db.query_row("select id from Vault where alias = ?", [alias], |row| {
+        row.get(0)
+    })
+    .optional()
 }
 
 impl SqliteDatabase {
@@ -26,18 +48,123 @@ impl SqliteDatabase {
         }
     }
 
-    fn open_db(&self, alias: &str) -> GenericResult<rusqlite::Connection> {
+    fn open_db(&self, name: &str) -> GenericResult<rusqlite::Connection> {
         let mut path: PathBuf = self.directory.clone();
         if !path.exists() {
             fs::create_dir_all(&path)?;
         }
-        path.push(get_db_path(alias));
+        path.push(get_db_path(name));
         let db = rusqlite::Connection::open(&path)?;
         Ok(db)
     }
 
+    fn open_vaults(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists Vault (alias text primary key, id text unique)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    /// Path of the vault of `alias`, assigning it an id if `create` is set
+    ///
+    /// Vault files are named by an opaque id rather than the alias, so that any alias is
+    /// safe and renaming a user leaves its vault in place. A vault from before ids were
+    /// assigned, named after its alias, is renamed to its id here.
+    fn lookup_vault(&self, alias: &str, create: bool) -> GenericResult<Option<PathBuf>> {
+        let mut db = self.open_vaults()?;
+        if let Some(id) = vault_id(&db, alias)? {
+            return Ok(Some(self.directory.join(get_db_path(&id))));
+        }
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another connection may have assigned an id in the meantime
+        if let Some(id) = vault_id(&transaction, alias)? {
+            return Ok(Some(self.directory.join(get_db_path(&id))));
+        }
+        let legacy = self.legacy_vault(&transaction, alias)?;
+        if legacy.is_none() && !create {
+            return Ok(None);
+        }
+        let id = random_hex(16);
+        transaction.execute("insert into Vault values (?, ?)", [alias, &id])?;
+        let path = self.directory.join(get_db_path(&id));
+        if let Some(legacy) = legacy {
+            for suffix in ["-journal", "-wal", "-shm", ""] {
+                let mut file = legacy.clone().into_os_string();
+                file.push(suffix);
+                let mut target = path.clone().into_os_string();
+                target.push(suffix);
+                if Path::new(&file).exists() {
+                    fs::rename(&file, &target)?;
+                }
+            }
+            info!("Moved the vault of user {} to {}", alias, path.display());
+        }
+        transaction.commit()?;
+        Ok(Some(path))
+    }
+
+    /// File of the vault of `alias` named after the alias, if there is one
+    fn legacy_vault(
+        &self,
+        db: &rusqlite::Connection,
+        alias: &str,
+    ) -> GenericResult<Option<PathBuf>> {
+        // Other aliases could not be used as file names
+        if alias.is_empty()
+            || alias.contains(['/', '\\'])
+            || alias.eq_ignore_ascii_case(INTERNAL_DB)
+        {
+            return Ok(None);
+        }
+        // The alias may equal the id of another vault
+        if db
+            .prepare("select id from Vault where id = ?")?
+            .exists([alias])?
+        {
+            return Ok(None);
+        }
+        let path = self.directory.join(get_db_path(alias));
+        Ok(path.is_file().then_some(path))
+    }
+
+    /// Path of the vault of `alias`, which need not exist yet
+    fn vault_path(&self, alias: &str) -> GenericResult<PathBuf> {
+        Ok(self
+            .lookup_vault(alias, true)?
+            .expect("Vaults are assigned an id"))
+    }
+
+    fn open_vault(&self, alias: &str) -> GenericResult<rusqlite::Connection> {
+        Ok(rusqlite::Connection::open(self.vault_path(alias)?)?)
+    }
+
+    /// Names and paths of every database in the directory, sorted by name
+    fn database_files(&self) -> GenericResult<Vec<(String, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension() != Some("sqlite".as_ref()) {
+                continue;
+            }
+            if let Some(name) = path.file_name() {
+                files.push((name.to_string_lossy().into_owned(), path.to_owned()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Whether the vault of `alias` was ever opened, without creating it
+    fn vault_exists(&self, alias: &str) -> GenericResult<bool> {
+        Ok(self
+            .lookup_vault(alias, false)?
+            .is_some_and(|path| path.exists()))
+    }
+
     fn open_store(&self, alias: &str) -> GenericResult<rusqlite::Connection> {
-        let db = self.open_db(alias)?;
+        let db = self.open_vault(alias)?;
         db.execute(
             "create table if not exists Store (id text primary key, value text)",
             [],
@@ -45,8 +172,21 @@ impl SqliteDatabase {
         Ok(db)
     }
 
+    fn open_upload(&self, alias: &str) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_store(alias)?;
+        db.execute(
+            "create table if not exists UploadSession (id text primary key, time integer)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists Upload (upload_id text, id text, value text, primary key (upload_id, id))",
+            [],
+        )?;
+        Ok(db)
+    }
+
     fn open_cache(&self, alias: &str) -> GenericResult<rusqlite::Connection> {
-        let db = self.open_db(alias)?;
+        let db = self.open_vault(alias)?;
         db.execute(
             "create table if not exists Cache (id text primary key, time integer, mutation blob)",
             [],
@@ -55,13 +195,279 @@ impl SqliteDatabase {
     }
 
     fn open_user(&self) -> GenericResult<rusqlite::Connection> {
-        let db = self.open_db("vult.internal")?;
+        let db = self.open_db(INTERNAL_DB)?;
         db.execute(
             "create table if not exists User (alias text primary key, salt text, hash text)",
             [],
         )?;
         Ok(db)
     }
+
+    fn open_auth_failures(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists AuthFailure (subject text primary key, failures integer, last_failure integer, locked_until integer)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    fn open_audit(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists AuditLog (seq integer primary key, time integer, action text, alias text, device text, route text, mutations integer, state_id text, ip text, hash text)",
+            [],
+        )?;
+        db.execute(
+            "create index if not exists AuditLogAlias on AuditLog (alias, seq)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    fn open_accounts(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists Account (alias text primary key, created integer)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists ApiKey (id text primary key, alias text, name text, hash text unique, created integer)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists KeySync (key_id text primary key, time integer, state_id text)",
+            [],
+        )?;
+        // Keys without a row predate scopes and keep all of them
+        db.execute(
+            "create table if not exists KeyScope (key_id text primary key, scopes text)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists KeyExpiry (key_id text primary key, expires integer)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists KeyTotp (key_id text primary key, verified integer)",
+            [],
+        )?;
+        // Keys with a row carry their id and are looked up by it, the others predate ids
+        // in keys and are found by trying their hashes
+        db.execute(
+            "create table if not exists KeyPrefix (key_id text primary key)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    fn open_config_imports(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists ConfigImport (entry text primary key)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    fn open_deletions(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_db(INTERNAL_DB)?;
+        db.execute(
+            "create table if not exists Deletion (alias text primary key, requested integer, due integer)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    fn open_totp(&self) -> GenericResult<rusqlite::Connection> {
+        let db = self.open_accounts()?;
+        db.execute(
+            "create table if not exists Totp (alias text primary key, secret text, enabled integer, last_step integer)",
+            [],
+        )?;
+        db.execute(
+            "create table if not exists RecoveryCode (alias text, hash text)",
+            [],
+        )?;
+        Ok(db)
+    }
+
+    /// Replace the store, and the salt and hash if given, forgetting every previous state
+    ///
+    /// Fails with `StaleState` if `state_id` is given but is not the most recent state.
+    /// Returns the `id` of the new state and the number of credentials in the store
+    fn replace_store(
+        &self,
+        alias: &str,
+        state_id: Option<&str>,
+        master_password: Option<(&str, &str)>,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        drop(self.open_user()?);
+        drop(self.open_cache(alias)?);
+        let mut db = self.open_upload(alias)?;
+        // The user database is attached so that everything changes in one transaction
+        db.execute(
+            "attach database ? as internal",
+            [self
+                .directory
+                .join(get_db_path(INTERNAL_DB))
+                .to_string_lossy()],
+        )?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let latest: Option<String> = transaction
+            .query_row(
+                "select id from Cache order by time desc limit 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(state_id) = state_id {
+            if latest.as_deref() != Some(state_id) {
+                return Err(Error::StaleState(state_id.to_string()));
+            }
+        }
+        if let Some((salt, hash)) = master_password {
+            if transaction.execute(
+                "update internal.User set salt = ?, hash = ? where alias = ?",
+                [salt, hash, alias],
+            )? == 0
+            {
+                return Err(Error::UninitializedUser(alias.to_string()));
+            }
+        }
+
+        transaction.execute("delete from Store", [])?;
+        let count = match store {
+            NewStore::Credentials(credentials) => {
+                let mut statement = transaction.prepare("insert into Store values (?, ?)")?;
+                for credential in credentials {
+                    statement.execute([&credential.id, &credential.value])?;
+                }
+                credentials.len() as u64
+            }
+            NewStore::Upload(upload_id) => {
+                if !upload_exists(&transaction, upload_id)? {
+                    return Err(Error::MissingUpload(upload_id.to_string()));
+                }
+                transaction.execute(
+                    "insert into Store select id, value from Upload where upload_id = ?",
+                    [upload_id],
+                )?;
+                count_upload(&transaction, upload_id)?
+            }
+        };
+        // Other uploads in progress were meant for the old store
+        transaction.execute("delete from Upload", [])?;
+        transaction.execute("delete from UploadSession", [])?;
+        let new_state_id = restart_cache(&transaction, time)?;
+        transaction.commit()?;
+        Ok((new_state_id, count))
+    }
+}
+
+fn now_secs() -> GenericResult<u64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs())
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation)
+}
+
+fn account_exists(db: &rusqlite::Connection, alias: &str) -> GenericResult<bool> {
+    let mut statement = db.prepare("select alias from Account where alias = ?")?;
+    Ok(statement.exists([alias])?)
+}
+
+/// Columns read by [`key_from_row`] from [`KEY_TABLES`]
+const KEY_COLUMNS: &str = "ApiKey.id, alias, name, created, KeySync.time, KeySync.state_id, KeyScope.scopes, KeyExpiry.expires, KeyTotp.verified";
+const KEY_TABLES: &str = "ApiKey left join KeySync on KeySync.key_id = ApiKey.id left join KeyScope on KeyScope.key_id = ApiKey.id left join KeyExpiry on KeyExpiry.key_id = ApiKey.id left join KeyTotp on KeyTotp.key_id = ApiKey.id";
+/// Tables with details of keys, by `key_id`
+const KEY_DETAIL_TABLES: [&str; 5] = ["KeySync", "KeyScope", "KeyExpiry", "KeyTotp", "KeyPrefix"];
+
+/// Space separated scopes as stored in `KeyScope`
+fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn key_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        alias: row.get(1)?,
+        name: row.get(2)?,
+        created: row.get(3)?,
+        last_sync: row.get(4)?,
+        last_state: row.get(5)?,
+        scopes: match row.get::<_, Option<String>>(6)? {
+            Some(scopes) => scopes
+                .split_whitespace()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            None => Scope::ALL.to_vec(),
+        },
+        expires: row.get(7)?,
+        totp_verified: row.get(8)?,
+    })
+}
+
+const AUDIT_COLUMNS: &str =
+    "seq, time, action, alias, device, route, mutations, state_id, ip, hash";
+
+fn audit_event_from_row(row: &rusqlite::Row) -> rusqlite::Result<AuditEvent> {
+    Ok(AuditEvent {
+        seq: row.get(0)?,
+        time: row.get(1)?,
+        action: row.get(2)?,
+        alias: row.get(3)?,
+        device: row.get(4)?,
+        route: row.get(5)?,
+        mutations: row.get(6)?,
+        state_id: row.get(7)?,
+        ip: row.get(8)?,
+        hash: row.get(9)?,
+    })
+}
+
+/// Insert a new key and its details, returning its id
+fn insert_key(
+    transaction: &rusqlite::Transaction,
+    alias: &str,
+    name: &str,
+    hash: &str,
+    scopes: &[Scope],
+    expires: Option<u64>,
+) -> GenericResult<String> {
+    // Hashes of keys with an id are stored under that id
+    let prefix = key_hash_id(hash);
+    let id = prefix.map_or_else(|| random_b64_url(12), str::to_string);
+    match transaction.execute(
+        "insert into ApiKey values (?, ?, ?, ?, ?)",
+        params![id, alias, name, hash, now_secs()?],
+    ) {
+        Ok(_) => {}
+        Err(e) if is_constraint_violation(&e) => return Err(Error::ExistingKey),
+        Err(e) => return Err(e.into()),
+    }
+    transaction.execute(
+        "insert into KeyScope values (?, ?)",
+        params![id, format_scopes(scopes)],
+    )?;
+    if prefix.is_some() {
+        transaction.execute("insert into KeyPrefix values (?)", [&id])?;
+    }
+    if let Some(expires) = expires {
+        transaction.execute("insert into KeyExpiry values (?, ?)", params![id, expires])?;
+    }
+    Ok(id)
 }
 
 impl StoreDatabase for SqliteDatabase {
@@ -166,6 +572,33 @@ impl StoreDatabase for SqliteDatabase {
         Ok(credentials)
     }
 
+    fn export_page(
+        &self,
+        alias: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<Vec<Credential>> {
+        let db = self.open_store(alias)?;
+        let mut statement = match cursor {
+            Some(_) => db.prepare("select * from Store where id > ?1 order by id limit ?2")?,
+            None => db.prepare("select * from Store order by id limit ?2")?,
+        };
+
+        let iter = statement.query_map(params![cursor, limit], |row| {
+            Ok(Credential {
+                id: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?;
+
+        let mut credentials: Vec<Credential> = Vec::new();
+        for credential in iter {
+            credentials.push(credential?);
+        }
+
+        Ok(credentials)
+    }
+
     fn import_all(&self, alias: &str, credentials: &[Credential]) -> GenericResult<()> {
         let db = self.open_store(alias)?;
 
@@ -191,6 +624,145 @@ impl StoreDatabase for SqliteDatabase {
         })?;
         Ok(iter.next().is_none())
     }
+
+    fn begin_upload(&self, alias: &str) -> GenericResult<String> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let db = self.open_upload(alias)?;
+        let id = random_b64_url(24);
+        db.execute("insert into UploadSession values (?, ?)", params![id, time])?;
+        Ok(id)
+    }
+
+    fn stage_upload(
+        &self,
+        alias: &str,
+        upload_id: &str,
+        credentials: &[Credential],
+    ) -> GenericResult<u64> {
+        let mut db = self.open_upload(alias)?;
+        let transaction = db.transaction()?;
+        if !upload_exists(&transaction, upload_id)? {
+            return Err(Error::MissingUpload(upload_id.to_string()));
+        }
+        {
+            let mut statement =
+                transaction.prepare("insert or replace into Upload values (?, ?, ?)")?;
+            for credential in credentials {
+                statement.execute([upload_id, &credential.id, &credential.value])?;
+            }
+        }
+        let count = count_upload(&transaction, upload_id)?;
+        transaction.commit()?;
+        Ok(count)
+    }
+
+    fn upload_size(&self, alias: &str, upload_id: &str) -> GenericResult<u64> {
+        let db = self.open_upload(alias)?;
+        if !upload_exists(&db, upload_id)? {
+            return Err(Error::MissingUpload(upload_id.to_string()));
+        }
+        count_upload(&db, upload_id)
+    }
+
+    fn expire_uploads(&self, alias: &str, before: u64) -> GenericResult<u64> {
+        if !self.vault_exists(alias)? {
+            return Ok(0);
+        }
+        let mut db = self.open_upload(alias)?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        transaction.execute(
+            "delete from Upload where upload_id in (select id from UploadSession where time < ?)",
+            [before],
+        )?;
+        let expired = transaction.execute("delete from UploadSession where time < ?", [before])?;
+        transaction.commit()?;
+        Ok(expired as u64)
+    }
+
+    fn vault_stats(&self, alias: &str) -> GenericResult<VaultStats> {
+        if !self.vault_exists(alias)? {
+            return Ok(VaultStats::default());
+        }
+        drop(self.open_cache(alias)?);
+        let db = self.open_store(alias)?;
+        let count = |table: &str| -> GenericResult<u64> {
+            let count: i64 = db.query_row(&format!("select count(*) from {table}"), [], |row| {
+                row.get(0)
+            })?;
+            Ok(count as u64)
+        };
+        Ok(VaultStats {
+            credentials: count("Store")?,
+            states: count("Cache")?,
+            size: fs::metadata(self.vault_path(alias)?)?.len(),
+        })
+    }
+
+    fn backup(&self, directory: &Path) -> GenericResult<Vec<String>> {
+        if let Some(parent) = directory.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::create_dir(directory)?;
+        let files = self.database_files()?;
+        for (name, path) in &files {
+            let db = rusqlite::Connection::open(path)?;
+            db.execute("vacuum into ?", [directory.join(name).to_string_lossy()])?;
+        }
+        Ok(files.into_iter().map(|(name, _)| name).collect())
+    }
+
+    fn clear_vault(&self, alias: &str) -> GenericResult<()> {
+        if !self.vault_exists(alias)? {
+            return Ok(());
+        }
+        drop(self.open_cache(alias)?);
+        let mut db = self.open_upload(alias)?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for table in ["Store", "Cache", "Upload", "UploadSession"] {
+            transaction.execute(&format!("delete from {table}"), [])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn vacuum(&self) -> GenericResult<Vec<String>> {
+        let files = self.database_files()?;
+        for (_, path) in &files {
+            let db = rusqlite::Connection::open(path)?;
+            db.execute("vacuum", [])?;
+        }
+        Ok(files.into_iter().map(|(name, _)| name).collect())
+    }
+}
+
+/// Replace every state of the cache with a single new one without mutations at `time`
+///
+/// Returns the `id` of the new state
+fn restart_cache(db: &rusqlite::Connection, time: u128) -> GenericResult<String> {
+    db.execute("delete from Cache", [])?;
+    let state_id = random_b64(24);
+    let mutation_blob = bincode::serialize(&Vec::<DbMutation>::new())?;
+    db.execute(
+        "insert into Cache values (?, ?, ?)",
+        params![state_id, time as u64, mutation_blob],
+    )?;
+    Ok(state_id)
+}
+
+fn upload_exists(db: &rusqlite::Connection, upload_id: &str) -> GenericResult<bool> {
+    let mut statement = db.prepare("select id from UploadSession where id = ?")?;
+    Ok(statement.exists([upload_id])?)
+}
+
+fn count_upload(db: &rusqlite::Connection, upload_id: &str) -> GenericResult<u64> {
+    let count: i64 = db.query_row(
+        "select count(*) from Upload where upload_id = ?",
+        [upload_id],
+        |row| row.get(0),
+    )?;
+    Ok(count as u64)
 }
 
 impl CacheDatabase for SqliteDatabase {
@@ -199,36 +771,24 @@ impl CacheDatabase for SqliteDatabase {
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_nanos();
 
-        let mutations: &[DbMutation] = unsafe {
-            std::slice::from_raw_parts(mutations.as_ptr() as *const DbMutation, mutations.len())
-        };
+        let mutations: Vec<DbMutation> = mutations.iter().cloned().map(DbMutation::from).collect();
         let mutation_blob = bincode::serialize(&mutations)?;
 
         let db = self.open_cache(alias)?;
 
-        let mut id;
-        while {
-            id = random_b64(24);
-            let result = db.execute(
+        let id = loop {
+            let id = random_b64(24);
+            match db.execute(
                 "insert into Cache values (?, ?, ?)",
                 params![id, time as u64, mutation_blob],
-            );
-            match &result {
-                Ok(_) => false,
-                Err(rusqlite::Error::SqliteFailure(e, _)) => {
-                    if e.extended_code == 1555 {
-                        true
-                    } else {
-                        result.context("Failed to add mutations to database")?;
-                        unreachable!();
-                    }
-                }
-                _ => {
-                    result.context("Failed to add mutations to database")?;
-                    unreachable!();
-                }
+            ) {
+                Ok(_) => break id,
+                // The id is taken, draw another one
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {}
+                Err(e) => return Err(e).context("Failed to add mutations to database"),
             }
-        } {}
+        };
 
         Ok(id)
     }
@@ -238,7 +798,7 @@ impl CacheDatabase for SqliteDatabase {
 
         let db = self.open_cache(alias)?;
         let mut statement = db.prepare(
-            "select mutation from Cache where time > (select time from Cache where id = ?) order by time desc",
+            "select mutation from Cache where time > (select time from Cache where id = ?) order by time asc",
         )?;
         let mutation_blob_iter = statement.query_map([id], |row| {
             let mutation: Vec<u8> = row.get(0)?;
@@ -247,11 +807,7 @@ impl CacheDatabase for SqliteDatabase {
 
         for mutation_blob in mutation_blob_iter.flatten() {
             let mutation: Vec<DbMutation> = bincode::deserialize(&mutation_blob)?;
-            let mut ptr = std::mem::ManuallyDrop::new(mutation);
-            let mut mutation: Vec<Mutation> = unsafe {
-                Vec::from_raw_parts(ptr.as_mut_ptr() as *mut Mutation, ptr.len(), ptr.len())
-            };
-            mutations.append(&mut mutation);
+            mutations.extend(mutation.into_iter().map(Mutation::from));
         }
 
         Ok(mutations)
@@ -276,6 +832,102 @@ impl CacheDatabase for SqliteDatabase {
             Err(e) => Err(e.into()),
         }
     }
+
+    fn replace_vault(
+        &self,
+        alias: &str,
+        state_id: &str,
+        salt: &str,
+        hash: &str,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)> {
+        self.replace_store(alias, Some(state_id), Some((salt, hash)), store)
+    }
+
+    fn reset_vault(
+        &self,
+        alias: &str,
+        state_id: Option<&str>,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)> {
+        self.replace_store(alias, state_id, None, store)
+    }
+
+    fn finish_upload(&self, alias: &str, upload_id: &str) -> GenericResult<(String, u64)> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        drop(self.open_cache(alias)?);
+        let mut db = self.open_upload(alias)?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if !upload_exists(&transaction, upload_id)? {
+            return Err(Error::MissingUpload(upload_id.to_string()));
+        }
+        // Checked in the same transaction, so that no other upload or sync can initialize
+        // the vault in between
+        let initialized = transaction
+            .prepare("select id from Store limit 1")?
+            .exists([])?
+            || transaction
+                .prepare("select id from Cache limit 1")?
+                .exists([])?;
+        if initialized {
+            return Err(Error::ExistingUser(alias.to_string()));
+        }
+        transaction.execute(
+            "insert into Store select id, value from Upload where upload_id = ?",
+            [upload_id],
+        )?;
+        let count = count_upload(&transaction, upload_id)?;
+        transaction.execute("delete from Upload", [])?;
+        transaction.execute("delete from UploadSession", [])?;
+        let state_id = restart_cache(&transaction, time)?;
+        transaction.commit()?;
+        Ok((state_id, count))
+    }
+
+    fn latest_state(&self, alias: &str) -> GenericResult<Option<String>> {
+        let db = self.open_cache(alias)?;
+        let mut statement = db.prepare("select id from Cache order by time desc limit 1")?;
+        match statement.query_row([], |row| row.get(0)) {
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Ok(id) => Ok(Some(id)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn force_resync(&self, alias: &str) -> GenericResult<Option<String>> {
+        if !self.vault_exists(alias)? {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let mut db = self.open_cache(alias)?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Without any state the vault can still be initialized
+        if !transaction
+            .prepare("select id from Cache limit 1")?
+            .exists([])?
+        {
+            return Ok(None);
+        }
+        let state_id = restart_cache(&transaction, time)?;
+        transaction.commit()?;
+        Ok(Some(state_id))
+    }
+
+    fn prune_cache(&self, alias: &str, keep: u32) -> GenericResult<u64> {
+        if !self.vault_exists(alias)? {
+            return Ok(0);
+        }
+        let db = self.open_cache(alias)?;
+        let removed = db.execute(
+            "delete from Cache where id not in (select id from Cache order by time desc limit ?)",
+            [keep],
+        )?;
+        Ok(removed as u64)
+    }
 }
 
 impl UserDatabase for SqliteDatabase {
@@ -308,4 +960,612 @@ impl UserDatabase for SqliteDatabase {
             .context("Failed to delete salt")
             .map(|_| ())
     }
+
+    fn add_account(&self, alias: &str) -> GenericResult<()> {
+        if let Some(problem) = alias_problem(alias) {
+            return Err(Error::InvalidAlias(alias.to_string(), problem));
+        }
+        let db = self.open_accounts()?;
+        match db.execute(
+            "insert into Account values (?, ?)",
+            params![alias, now_secs()?],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) if is_constraint_violation(&e) => Err(Error::ExistingUser(alias.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list_accounts(&self) -> GenericResult<Vec<Account>> {
+        let db = self.open_accounts()?;
+        let mut statement = db.prepare(
+            "select alias, created, (select count(*) from ApiKey where ApiKey.alias = Account.alias), (select max(KeySync.time) from KeySync join ApiKey on ApiKey.id = KeySync.key_id where ApiKey.alias = Account.alias) from Account order by alias",
+        )?;
+        let accounts = statement
+            .query_map([], |row| {
+                Ok(Account {
+                    alias: row.get(0)?,
+                    created: row.get(1)?,
+                    keys: row.get(2)?,
+                    last_sync: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(accounts)
+    }
+
+    fn remove_account(&self, alias: &str) -> GenericResult<()> {
+        // A vault still named after the alias is given an id, so that it is kept apart
+        let vault = self.lookup_vault(alias, false)?;
+        drop(self.open_user()?);
+        drop(self.open_totp()?);
+        drop(self.open_deletions()?);
+        let mut db = self.open_vaults()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if transaction.execute("delete from Account where alias = ?", [alias])? == 0 {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        for table in KEY_DETAIL_TABLES {
+            transaction.execute(
+                &format!(
+                    "delete from {table} where key_id in (select id from ApiKey where alias = ?)"
+                ),
+                [alias],
+            )?;
+        }
+        for table in [
+            "ApiKey",
+            "User",
+            "Totp",
+            "RecoveryCode",
+            "Deletion",
+            "Vault",
+        ] {
+            transaction.execute(&format!("delete from {table} where alias = ?"), [alias])?;
+        }
+        transaction.commit()?;
+        if let Some(path) = vault {
+            info!(
+                "Kept the vault of removed user {} in {}",
+                alias,
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn rename_account(
+        &self,
+        alias: &str,
+        new_alias: &str,
+        cipher: &TotpCipher,
+    ) -> GenericResult<()> {
+        if let Some(problem) = alias_problem(new_alias) {
+            return Err(Error::InvalidAlias(new_alias.to_string(), problem));
+        }
+        // Vaults still named after either alias are given ids before aliases change
+        self.lookup_vault(alias, false)?;
+        self.lookup_vault(new_alias, false)?;
+        drop(self.open_user()?);
+        drop(self.open_totp()?);
+        drop(self.open_deletions()?);
+        let mut db = self.open_vaults()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for table in ["Account", "Vault"] {
+            match transaction.execute(
+                &format!("update {table} set alias = ? where alias = ?"),
+                [new_alias, alias],
+            ) {
+                Ok(0) if table == "Account" => return Err(Error::MissingUser(alias.to_string())),
+                Ok(_) => {}
+                // The new alias still has a vault if an earlier version removed its user
+                Err(e) if is_constraint_violation(&e) => {
+                    return Err(Error::ExistingUser(new_alias.to_string()))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for table in ["ApiKey", "User", "Totp", "RecoveryCode", "Deletion"] {
+            transaction.execute(
+                &format!("update {table} set alias = ? where alias = ?"),
+                [new_alias, alias],
+            )?;
+        }
+        let secret: Option<String> = transaction
+            .query_row(
+                "select secret from Totp where alias = ?",
+                [new_alias],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(secret) = secret {
+            let secret = cipher.encrypt(new_alias, &cipher.decrypt(alias, &secret)?)?;
+            transaction.execute(
+                "update Totp set secret = ? where alias = ?",
+                [&secret, new_alias],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn delete_account(&self, alias: &str) -> GenericResult<()> {
+        drop(self.open_user()?);
+        drop(self.open_totp()?);
+        drop(self.open_deletions()?);
+        drop(self.open_cache(alias)?);
+        let path = self.vault_path(alias)?;
+        let mut db = self.open_upload(alias)?;
+        // The user database is attached so that everything is removed in one transaction
+        db.execute(
+            "attach database ? as internal",
+            [self
+                .directory
+                .join(get_db_path(INTERNAL_DB))
+                .to_string_lossy()],
+        )?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if transaction.execute("delete from internal.Account where alias = ?", [alias])? == 0 {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        for table in KEY_DETAIL_TABLES {
+            transaction.execute(
+                &format!(
+                    "delete from internal.{table} where key_id in (select id from internal.ApiKey where alias = ?)"
+                ),
+                [alias],
+            )?;
+        }
+        for table in [
+            "ApiKey",
+            "User",
+            "Totp",
+            "RecoveryCode",
+            "Deletion",
+            "Vault",
+        ] {
+            transaction.execute(
+                &format!("delete from internal.{table} where alias = ?"),
+                [alias],
+            )?;
+        }
+        for table in ["Store", "Cache", "Upload", "UploadSession"] {
+            transaction.execute(&format!("delete from main.{table}"), [])?;
+        }
+        transaction.commit()?;
+        drop(db);
+        // Only the emptied tables are left in the file
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn schedule_deletion(&self, alias: &str, due: u64) -> GenericResult<()> {
+        drop(self.open_accounts()?);
+        let db = self.open_deletions()?;
+        if !account_exists(&db, alias)? {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        match db.execute(
+            "insert into Deletion values (?, ?, ?)",
+            params![alias, now_secs()?, due],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) if is_constraint_violation(&e) => {
+                Err(Error::ExistingDeletion(alias.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn cancel_deletion(&self, alias: &str) -> GenericResult<()> {
+        let db = self.open_deletions()?;
+        if db.execute("delete from Deletion where alias = ?", [alias])? == 0 {
+            return Err(Error::MissingDeletion(alias.to_string()));
+        }
+        Ok(())
+    }
+
+    fn pending_deletion(&self, alias: &str) -> GenericResult<Option<u64>> {
+        let db = self.open_deletions()?;
+        Ok(db
+            .query_row("select due from Deletion where alias = ?", [alias], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn due_deletions(&self, now: u64) -> GenericResult<Vec<String>> {
+        let db = self.open_deletions()?;
+        let mut statement = db.prepare("select alias from Deletion where due <= ? order by due")?;
+        let aliases = statement
+            .query_map([now], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(aliases)
+    }
+
+    fn add_key(
+        &self,
+        alias: &str,
+        name: &str,
+        hash: &str,
+        scopes: &[Scope],
+        expires: Option<u64>,
+    ) -> GenericResult<String> {
+        let mut db = self.open_accounts()?;
+        if !account_exists(&db, alias)? {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        let transaction = db.transaction()?;
+        let id = insert_key(&transaction, alias, name, hash, scopes, expires)?;
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    fn rotate_key(
+        &self,
+        id: &str,
+        hash: &str,
+        expires: Option<u64>,
+        old_expires: u64,
+    ) -> GenericResult<String> {
+        let mut db = self.open_accounts()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let old = match transaction.query_row(
+            &format!("select {KEY_COLUMNS} from {KEY_TABLES} where ApiKey.id = ?"),
+            [id],
+            key_from_row,
+        ) {
+            Ok(key) => key,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(Error::MissingKey(id.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let new_id = insert_key(
+            &transaction,
+            &old.alias,
+            &old.name,
+            hash,
+            &old.scopes,
+            expires,
+        )?;
+        // The new key takes over the place of the old one in the device registry, and its
+        // TOTP enrollment
+        transaction.execute(
+            "insert into KeySync select ?, time, state_id from KeySync where key_id = ?",
+            params![new_id, id],
+        )?;
+        transaction.execute(
+            "insert into KeyTotp select ?, verified from KeyTotp where key_id = ?",
+            params![new_id, id],
+        )?;
+        transaction.execute(
+            "insert or replace into KeyExpiry values (?, ?)",
+            params![id, old_expires],
+        )?;
+        transaction.commit()?;
+        Ok(new_id)
+    }
+
+    fn list_keys(&self, alias: &str) -> GenericResult<Vec<ApiKey>> {
+        let db = self.open_accounts()?;
+        if !account_exists(&db, alias)? {
+            return Err(Error::MissingUser(alias.to_string()));
+        }
+        let mut statement = db.prepare(&format!(
+            "select {KEY_COLUMNS} from {KEY_TABLES} where alias = ? order by created, ApiKey.id"
+        ))?;
+        let keys = statement
+            .query_map([alias], key_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    fn get_key(&self, id: &str) -> GenericResult<ApiKey> {
+        let db = self.open_accounts()?;
+        let mut statement = db.prepare(&format!(
+            "select {KEY_COLUMNS} from {KEY_TABLES} where ApiKey.id = ?"
+        ))?;
+        match statement.query_row([id], key_from_row) {
+            Ok(key) => Ok(key),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(Error::MissingKey(id.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn record_sync(&self, id: &str, state_id: &str) -> GenericResult<()> {
+        let db = self.open_accounts()?;
+        db.execute(
+            "insert or replace into KeySync values (?, ?, ?)",
+            params![id, now_secs()?, state_id],
+        )?;
+        Ok(())
+    }
+
+    fn remove_key(&self, id: &str) -> GenericResult<()> {
+        let mut db = self.open_accounts()?;
+        let transaction = db.transaction()?;
+        if transaction.execute("delete from ApiKey where id = ?", [id])? == 0 {
+            return Err(Error::MissingKey(id.to_string()));
+        }
+        for table in KEY_DETAIL_TABLES {
+            transaction.execute(&format!("delete from {table} where key_id = ?"), [id])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn find_key(&self, key: &str) -> GenericResult<Option<ApiKey>> {
+        let db = self.open_accounts()?;
+        if let Some(id) = key_id(key) {
+            let found = db
+                .query_row(
+                    &format!("select {KEY_COLUMNS}, hash from {KEY_TABLES} where ApiKey.id = ?"),
+                    [id],
+                    |row| Ok((key_from_row(row)?, row.get::<_, String>(9)?)),
+                )
+                .optional()?;
+            return Ok(found
+                .filter(|(_, hash)| verify_key(key, hash))
+                .map(|(key, _)| key));
+        }
+        let mut statement = db.prepare(&format!(
+            "select {KEY_COLUMNS}, hash from {KEY_TABLES} where ApiKey.id not in (select key_id from KeyPrefix)"
+        ))?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let hash: String = row.get(9)?;
+            if verify_key(key, &hash) {
+                return Ok(Some(key_from_row(row)?));
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_key_by_hash(&self, hash: &str) -> GenericResult<Option<ApiKey>> {
+        let db = self.open_accounts()?;
+        let mut statement = db.prepare(&format!(
+            "select {KEY_COLUMNS} from {KEY_TABLES} where hash = ?"
+        ))?;
+        Ok(statement.query_row([hash], key_from_row).optional()?)
+    }
+
+    fn begin_totp(
+        &self,
+        alias: &str,
+        secret: &str,
+        recovery_hashes: &[String],
+    ) -> GenericResult<()> {
+        let mut db = self.open_totp()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let enabled: Option<bool> = transaction
+            .query_row("select enabled from Totp where alias = ?", [alias], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if enabled == Some(true) {
+            return Err(Error::ExistingTotp(alias.to_string()));
+        }
+        transaction.execute(
+            "insert or replace into Totp values (?, ?, 0, null)",
+            params![alias, secret],
+        )?;
+        transaction.execute("delete from RecoveryCode where alias = ?", [alias])?;
+        for hash in recovery_hashes {
+            transaction.execute(
+                "insert into RecoveryCode values (?, ?)",
+                params![alias, hash],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_totp(&self, alias: &str) -> GenericResult<Option<TotpState>> {
+        let db = self.open_totp()?;
+        Ok(db
+            .query_row(
+                "select secret, enabled, last_step from Totp where alias = ?",
+                [alias],
+                |row| {
+                    Ok(TotpState {
+                        secret: row.get(0)?,
+                        enabled: row.get(1)?,
+                        last_step: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn enable_totp(&self, alias: &str) -> GenericResult<()> {
+        let db = self.open_totp()?;
+        if db.execute("update Totp set enabled = 1 where alias = ?", [alias])? == 0 {
+            return Err(Error::MissingTotp(alias.to_string()));
+        }
+        Ok(())
+    }
+
+    fn use_totp_step(&self, alias: &str, step: u64) -> GenericResult<bool> {
+        let db = self.open_totp()?;
+        let updated = db.execute(
+            "update Totp set last_step = ? where alias = ? and (last_step is null or last_step < ?)",
+            params![step, alias, step],
+        )?;
+        Ok(updated == 1)
+    }
+
+    fn use_recovery_code(&self, alias: &str, code: &str) -> GenericResult<bool> {
+        let mut db = self.open_totp()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let hashes = transaction
+            .prepare("select rowid, hash from RecoveryCode where alias = ?")?
+            .query_map([alias], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let used = hashes.iter().find(|(_, hash)| verify_key(code, hash));
+        if let Some((rowid, _)) = used {
+            transaction.execute("delete from RecoveryCode where rowid = ?", [rowid])?;
+            transaction.commit()?;
+        }
+        Ok(used.is_some())
+    }
+
+    fn remove_totp(&self, alias: &str) -> GenericResult<()> {
+        let mut db = self.open_totp()?;
+        let transaction = db.transaction()?;
+        if transaction.execute("delete from Totp where alias = ?", [alias])? == 0 {
+            return Err(Error::MissingTotp(alias.to_string()));
+        }
+        transaction.execute("delete from RecoveryCode where alias = ?", [alias])?;
+        transaction.execute(
+            "delete from KeyTotp where key_id in (select id from ApiKey where alias = ?)",
+            [alias],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn verify_device(&self, id: &str) -> GenericResult<()> {
+        let db = self.open_accounts()?;
+        db.execute(
+            "insert or replace into KeyTotp values (?, ?)",
+            params![id, now_secs()?],
+        )?;
+        Ok(())
+    }
+
+    fn auth_failures(&self, subject: &str) -> GenericResult<AuthFailures> {
+        let db = self.open_auth_failures()?;
+        let failures = db
+            .query_row(
+                "select failures, last_failure, locked_until from AuthFailure where subject = ?",
+                [subject],
+                |row| {
+                    Ok(AuthFailures {
+                        failures: row.get(0)?,
+                        last_failure: row.get(1)?,
+                        locked_until: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(failures.unwrap_or_default())
+    }
+
+    fn set_auth_failures(&self, subject: &str, failures: &AuthFailures) -> GenericResult<()> {
+        let db = self.open_auth_failures()?;
+        db.execute(
+            "insert or replace into AuthFailure values (?, ?, ?, ?)",
+            params![
+                subject,
+                failures.failures,
+                failures.last_failure,
+                failures.locked_until
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn clear_auth_failures(&self, subject: &str) -> GenericResult<()> {
+        let db = self.open_auth_failures()?;
+        db.execute("delete from AuthFailure where subject = ?", [subject])?;
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> GenericResult<AuditEvent> {
+        let mut db = self.open_audit()?;
+        let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (seq, previous) = transaction
+            .query_row(
+                "select seq, hash from AuditLog order by seq desc limit 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .unwrap_or_else(|| (0, GENESIS_HASH.to_string()));
+        let mut event = AuditEvent {
+            seq: seq + 1,
+            time: now_secs()?,
+            action: entry.action.to_string(),
+            alias: entry.alias.to_owned(),
+            device: entry.device.to_owned(),
+            route: entry.route.to_owned(),
+            mutations: entry.mutations,
+            state_id: entry.state_id.to_owned(),
+            ip: entry.ip.to_owned(),
+            hash: String::new(),
+        };
+        event.hash = event_hash(&previous, &event);
+        transaction.execute(
+            &format!(
+                "insert into AuditLog ({AUDIT_COLUMNS}) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            ),
+            params![
+                event.seq,
+                event.time,
+                &event.action,
+                &event.alias,
+                &event.device,
+                &event.route,
+                event.mutations,
+                &event.state_id,
+                &event.ip,
+                &event.hash
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(event)
+    }
+
+    fn recent_audit(&self, alias: &str, limit: u32) -> GenericResult<Vec<AuditEvent>> {
+        // Events of an earlier account with the same alias are left out
+        let created: Option<u64> = self
+            .open_accounts()?
+            .query_row(
+                "select created from Account where alias = ?",
+                [alias],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let db = self.open_audit()?;
+        let mut statement = db.prepare(&format!(
+            "select {AUDIT_COLUMNS} from AuditLog where alias = ? and time >= ? order by seq desc limit ?"
+        ))?;
+        let events = statement
+            .query_map(
+                params![alias, created.unwrap_or(0), limit],
+                audit_event_from_row,
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+
+    fn audit_events(&self, after: u64, limit: u32) -> GenericResult<Vec<AuditEvent>> {
+        let db = self.open_audit()?;
+        let mut statement = db.prepare(&format!(
+            "select {AUDIT_COLUMNS} from AuditLog where seq > ? order by seq limit ?"
+        ))?;
+        let events = statement
+            .query_map(params![after, limit], audit_event_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+
+    fn config_imported(&self, entry: &str) -> GenericResult<bool> {
+        let db = self.open_config_imports()?;
+        let imported = db
+            .prepare("select entry from ConfigImport where entry = ?")?
+            .exists([entry])?;
+        Ok(imported)
+    }
+
+    fn mark_config_imported(&self, entry: &str) -> GenericResult<()> {
+        let db = self.open_config_imports()?;
+        db.execute("insert or ignore into ConfigImport values (?)", [entry])?;
+        Ok(())
+    }
 }
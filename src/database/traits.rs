@@ -1,8 +1,13 @@
+use std::path::Path;
+
 use anyhow::Result;
 
 use crate::{
-    api::db_types::{Credential, Mutation},
-    util::types::GenericResult,
+    api::db_types::{
+        Account, ApiKey, AuditEntry, AuditEvent, AuthFailures, Credential, Mutation, NewStore,
+        Scope, TotpState, VaultStats,
+    },
+    util::{totp::TotpCipher, types::GenericResult},
 };
 
 pub trait StoreDatabase {
@@ -14,11 +19,62 @@ pub trait StoreDatabase {
     /// Export the entire store of the user of `key` as a list of credentials
     fn export_all(&self, alias: &str) -> GenericResult<Vec<Credential>>;
 
+    /// Export up to `limit` credentials ordered by id, starting after the id `cursor`
+    fn export_page(
+        &self,
+        alias: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> GenericResult<Vec<Credential>>;
+
     /// Imports entire list of credentials into what should be an empty store
     fn import_all(&self, alias: &str, credentials: &[Credential]) -> GenericResult<()>;
 
+    /// Start a resumable upload of an entire store
+    ///
+    /// Returns the `id` of the upload
+    fn begin_upload(&self, alias: &str) -> GenericResult<String>;
+
+    /// Add a chunk of credentials to an upload, replacing any with the same id
+    ///
+    /// Returns the number of credentials received so far
+    fn stage_upload(
+        &self,
+        alias: &str,
+        upload_id: &str,
+        credentials: &[Credential],
+    ) -> GenericResult<u64>;
+
+    /// Number of credentials received so far by an upload
+    fn upload_size(&self, alias: &str, upload_id: &str) -> GenericResult<u64>;
+
+    /// Forget the uploads of `alias` begun before `before`, in seconds since the Unix
+    /// epoch, with everything they received
+    ///
+    /// Returns the number of uploads forgotten
+    fn expire_uploads(&self, alias: &str, before: u64) -> GenericResult<u64>;
+
     /// Check if database is empty for user of 'key'
     fn is_empty(&self, alias: &str) -> GenericResult<bool>;
+
+    /// Number of credentials and states and the size of the vault of `alias`
+    fn vault_stats(&self, alias: &str) -> GenericResult<VaultStats>;
+
+    /// Write a copy of every database to the new directory `directory`
+    ///
+    /// Each copy is consistent on its own, but the databases are copied one after the
+    /// other, so a change made during the backup may be in some copies and not others.
+    /// Returns the names of the copied files
+    fn backup(&self, directory: &Path) -> GenericResult<Vec<String>>;
+
+    /// Rebuild every database to give the space of removed data back to the file system
+    ///
+    /// Returns the names of the rebuilt files
+    fn vacuum(&self) -> GenericResult<Vec<String>>;
+
+    /// Empty the store, cache and uploads of the vault of `alias`, so it can be
+    /// initialized again
+    fn clear_vault(&self, alias: &str) -> GenericResult<()>;
 }
 
 pub trait CacheDatabase {
@@ -31,18 +87,189 @@ pub trait CacheDatabase {
     /// Check if cache contains state id
     fn has_state(&self, alias: &str, state: &str) -> GenericResult<bool>;
 
+    /// Get the id of the most recent state, if any
+    fn latest_state(&self, alias: &str) -> GenericResult<Option<String>>;
+
     /// Get all mutations necessary to get to most up-to-date state from state `id`
     ///
+    /// Mutations are returned in the order they were recorded.
     /// If `id` refers to the most current state, result is an empty list.
     fn get_next_mutations(&self, alias: &str, id: &str) -> GenericResult<Vec<Mutation>>;
 
     /// Check if database is empty for user of 'key'
     fn is_empty(&self, alias: &str) -> GenericResult<bool>;
+
+    /// Replace the salt, hash and entire store of the user, as when the master password
+    /// changes, and forget every previous state
+    ///
+    /// Fails with `StaleState` unless `state_id` is the most recent state. Returns the
+    /// `id` of the new state and the number of credentials in the store
+    fn replace_vault(
+        &self,
+        alias: &str,
+        state_id: &str,
+        salt: &str,
+        hash: &str,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)>;
+
+    /// Replace the entire store of the user, keeping the salt and hash, and forget every
+    /// previous state
+    ///
+    /// Fails with `StaleState` if `state_id` is given but is not the most recent state.
+    /// Returns the `id` of the new state and the number of credentials in the store
+    fn reset_vault(
+        &self,
+        alias: &str,
+        state_id: Option<&str>,
+        store: NewStore<'_>,
+    ) -> GenericResult<(String, u64)>;
+
+    /// Move all credentials of an upload into the store and record the first state, as
+    /// one transaction
+    ///
+    /// Fails with `ExistingUser` if the vault has a store or a state already. Returns the
+    /// `id` of the new state and the number of credentials in the store
+    fn finish_upload(&self, alias: &str, upload_id: &str) -> GenericResult<(String, u64)>;
+
+    /// Forget every state, so all devices get the entire store on their next sync
+    ///
+    /// Returns the `id` of the new state, if the vault had any state
+    fn force_resync(&self, alias: &str) -> GenericResult<Option<String>>;
+
+    /// Remove all but the `keep` most recent states, returning the number removed
+    fn prune_cache(&self, alias: &str, keep: u32) -> GenericResult<u64>;
 }
 pub trait UserDatabase {
     fn add_user(&self, alias: &str, salt: &str, hash: &str) -> Result<()>;
     fn get_user(&self, alias: &str) -> Result<(String, String)>;
     fn remove_salt(&self, alias: &str) -> Result<()>;
+
+    /// Create a user that keys can be issued to
+    fn add_account(&self, alias: &str) -> GenericResult<()>;
+
+    fn list_accounts(&self) -> GenericResult<Vec<Account>>;
+
+    /// Remove a user with its keys, master password and TOTP setup
+    ///
+    /// The vault file is left in place as an archive, but is no longer assigned to the
+    /// alias, so a user added with the same alias starts with an empty vault.
+    fn remove_account(&self, alias: &str) -> GenericResult<()>;
+
+    /// Give the user `alias` the alias `new_alias`, keeping its keys and vault
+    ///
+    /// The TOTP secret is bound to the alias, so it is encrypted again with `cipher`.
+    fn rename_account(
+        &self,
+        alias: &str,
+        new_alias: &str,
+        cipher: &TotpCipher,
+    ) -> GenericResult<()>;
+
+    /// Remove the vault, keys and TOTP setup of a user in one transaction
+    fn delete_account(&self, alias: &str) -> GenericResult<()>;
+
+    /// Schedule the deletion of `alias` at `due`, in seconds since the Unix epoch
+    fn schedule_deletion(&self, alias: &str, due: u64) -> GenericResult<()>;
+
+    fn cancel_deletion(&self, alias: &str) -> GenericResult<()>;
+
+    /// Time the scheduled deletion of `alias` is due, if any
+    fn pending_deletion(&self, alias: &str) -> GenericResult<Option<u64>>;
+
+    /// Users whose deletion is due at `now`
+    fn due_deletions(&self, now: u64) -> GenericResult<Vec<String>>;
+
+    /// Register the hash of a new key of the user `alias` with the given scopes, valid
+    /// until `expires` if given
+    ///
+    /// Returns the `id` of the key
+    fn add_key(
+        &self,
+        alias: &str,
+        name: &str,
+        hash: &str,
+        scopes: &[Scope],
+        expires: Option<u64>,
+    ) -> GenericResult<String>;
+
+    /// Replace key `id` with a new key of the same user, name and scopes
+    ///
+    /// The old key stays valid until `old_expires`. Returns the `id` of the new key
+    fn rotate_key(
+        &self,
+        id: &str,
+        hash: &str,
+        expires: Option<u64>,
+        old_expires: u64,
+    ) -> GenericResult<String>;
+
+    fn list_keys(&self, alias: &str) -> GenericResult<Vec<ApiKey>>;
+
+    fn get_key(&self, id: &str) -> GenericResult<ApiKey>;
+
+    /// Record that the device of key `id` synced to state `state_id`
+    fn record_sync(&self, id: &str, state_id: &str) -> GenericResult<()>;
+
+    fn remove_key(&self, id: &str) -> GenericResult<()>;
+
+    /// Find the registered key whose hash matches the plaintext `key`
+    ///
+    /// Only the hash of the key with the id of `key` is verified. Keys without an id are
+    /// checked against the hashes of the keys that predate ids.
+    fn find_key(&self, key: &str) -> GenericResult<Option<ApiKey>>;
+
+    /// Find the registered key with the stored `hash`
+    fn find_key_by_hash(&self, hash: &str) -> GenericResult<Option<ApiKey>>;
+
+    /// Store a new encrypted TOTP secret of `alias` and replace its recovery code hashes
+    ///
+    /// The secret is not enforced until it is enabled
+    fn begin_totp(
+        &self,
+        alias: &str,
+        secret: &str,
+        recovery_hashes: &[String],
+    ) -> GenericResult<()>;
+
+    fn get_totp(&self, alias: &str) -> GenericResult<Option<TotpState>>;
+
+    fn enable_totp(&self, alias: &str) -> GenericResult<()>;
+
+    /// Record the use of a code of time `step`, false if a code of the same or a later
+    /// step was used before
+    fn use_totp_step(&self, alias: &str, step: u64) -> GenericResult<bool>;
+
+    /// Use up the recovery code of `alias` that matches `code`, false if none does
+    fn use_recovery_code(&self, alias: &str, code: &str) -> GenericResult<bool>;
+
+    /// Remove the TOTP setup of `alias` and forget which devices passed it
+    fn remove_totp(&self, alias: &str) -> GenericResult<()>;
+
+    /// Record that the device of key `id` passed a TOTP check
+    fn verify_device(&self, id: &str) -> GenericResult<()>;
+
+    /// Failed authentication attempts of `subject`, such as a client address
+    fn auth_failures(&self, subject: &str) -> GenericResult<AuthFailures>;
+
+    fn set_auth_failures(&self, subject: &str, failures: &AuthFailures) -> GenericResult<()>;
+
+    fn clear_auth_failures(&self, subject: &str) -> GenericResult<()>;
+
+    /// Append `entry` to the audit log, chained to the last event
+    fn append_audit(&self, entry: &AuditEntry) -> GenericResult<AuditEvent>;
+
+    /// Most recent audit events of `alias` since its account was created, newest first
+    fn recent_audit(&self, alias: &str, limit: u32) -> GenericResult<Vec<AuditEvent>>;
+
+    /// Up to `limit` audit events after sequence number `after`, oldest first
+    fn audit_events(&self, after: u64, limit: u32) -> GenericResult<Vec<AuditEvent>>;
+
+    /// Whether the config `entry` was imported before
+    fn config_imported(&self, entry: &str) -> GenericResult<bool>;
+
+    /// Record that the config `entry` was imported, so that it is not imported again
+    fn mark_config_imported(&self, entry: &str) -> GenericResult<()>;
 }
 
 pub struct Databases {
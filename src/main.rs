@@ -6,13 +6,36 @@ pub mod util;
 #[macro_use]
 extern crate rocket;
 
-use api::server::launch_server;
+use std::{
+    fs::File,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use api::{
+    db_types::{Account, Credential, Scope},
+    server::launch_server,
+};
 use clap::Parser;
 use config::{
-    cli::{Cli, Commands},
+    cli::{AuditCommands, Cli, Commands, KeyCommands, UserCommands},
     parse_config::Config,
 };
+use database::{
+    accounts::{import_config_users, issue_key},
+    sqlite::SqliteDatabase,
+    traits::{CacheDatabase, StoreDatabase, UserDatabase},
+};
 use log::info;
+use util::{
+    audit::{verify_chain, GENESIS_HASH},
+    error::Error,
+    key::{generate_key, hash_key},
+    session::now_secs,
+    totp::TotpCipher,
+};
+
+/// Audit events read from the database at once
+const AUDIT_PAGE_SIZE: u32 = 1000;
 
 #[rocket::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -28,18 +51,226 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .init();
 
-    let mut config = Config::read_config(&cli_config.config)?;
-    info!("Parsed config:\n{}", &config);
-
     match cli_config.command {
         Commands::Run { test } => {
-            config.enable_test_routes = test;
-            launch_server(config).await?;
+            let path = cli_config.config.clone();
+            launch_server(move || {
+                let mut config = read_config(&path)?;
+                config.enable_test_routes = test;
+                Ok(config)
+            })
+            .await?;
+        }
+        Commands::GenerateKey => {
+            let key = generate_key();
+            println!("Key:  {}", &key);
+            println!("Hash: {}", hash_key(&key)?);
+        }
+        Commands::User { command } => {
+            let db = open_user_database(&cli_config.config)?;
+            match command {
+                UserCommands::Add { alias } => {
+                    db.add_account(&alias)?;
+                    println!("Added user {}", &alias);
+                }
+                UserCommands::List => {
+                    for account in db.list_accounts()? {
+                        let vault = db.vault_stats(&account.alias)?;
+                        println!(
+                            "{}\t{} keys\t{} credentials\t{}",
+                            &account.alias,
+                            account.keys,
+                            vault.credentials,
+                            format_time(account.last_sync, "never synced")
+                        );
+                    }
+                }
+                UserCommands::Show { alias } => {
+                    let account = find_account(&db, &alias)?;
+                    let vault = db.vault_stats(&alias)?;
+                    let last_activity = db.recent_audit(&alias, 1)?.pop();
+                    println!("User:          {}", &account.alias);
+                    println!("Created:       {}", rfc3339(account.created));
+                    println!("Credentials:   {}", vault.credentials);
+                    println!("Cached states: {}", vault.states);
+                    println!("Size:          {} bytes", vault.size);
+                    println!("Last sync:     {}", format_time(account.last_sync, "never"));
+                    println!(
+                        "Last activity: {}",
+                        last_activity.map_or_else(
+                            || "none".into(),
+                            |event| format!("{} {}", rfc3339(event.time), event.action)
+                        )
+                    );
+                    if let Some(due) = db.pending_deletion(&alias)? {
+                        println!("Deletion due:  {}", rfc3339(due));
+                    }
+                    println!("Keys:          {}", account.keys);
+                    print_keys(&db, &alias)?;
+                }
+                UserCommands::Remove { alias } => {
+                    db.remove_account(&alias)?;
+                    println!("Removed user {}, keeping its vault file", &alias);
+                }
+                UserCommands::Rename { alias, new_alias } => {
+                    let config = read_config(&cli_config.config)?;
+                    let cipher = TotpCipher::new(config.auth.totp_key.as_deref())?;
+                    db.rename_account(&alias, &new_alias, &cipher)?;
+                    println!("Renamed user {} to {}", &alias, &new_alias);
+                }
+            }
+        }
+        Commands::Key { command } => {
+            let db = open_user_database(&cli_config.config)?;
+            match command {
+                KeyCommands::Issue {
+                    alias,
+                    name,
+                    mut scopes,
+                    expires_in_days,
+                } => {
+                    if scopes.is_empty() {
+                        scopes = Scope::ALL.to_vec();
+                    }
+                    let expires = expires_in_days.map(|days| now_secs() + days * 24 * 3600);
+                    let (id, key) = issue_key(&db, &alias, &name, &scopes, expires)?;
+                    println!("Id:  {}", &id);
+                    println!("Key: {}", &key);
+                }
+                KeyCommands::List { alias } => print_keys(&db, &alias)?,
+                KeyCommands::Revoke { id } => {
+                    db.remove_key(&id)?;
+                    println!("Revoked key {}", &id);
+                }
+            }
+        }
+        Commands::Audit { command } => {
+            let config = read_config(&cli_config.config)?;
+            let db = SqliteDatabase::new(&config.db_directory);
+            match command {
+                AuditCommands::Export { alias, mut after } => loop {
+                    let events = db.audit_events(after, AUDIT_PAGE_SIZE)?;
+                    let Some(last) = events.last() else {
+                        break;
+                    };
+                    after = last.seq;
+                    for event in events.iter().filter(|event| {
+                        alias.is_none() || event.alias.as_deref() == alias.as_deref()
+                    }) {
+                        println!("{}", serde_json::to_string(event)?);
+                    }
+                },
+                AuditCommands::Verify => {
+                    let (mut seq, mut hash) = (0, GENESIS_HASH.to_string());
+                    loop {
+                        let events = db.audit_events(seq, AUDIT_PAGE_SIZE)?;
+                        if events.is_empty() {
+                            break;
+                        }
+                        (seq, hash) = verify_chain(seq, &hash, &events).map_err(|seq| {
+                            anyhow::anyhow!("Audit log was tampered with at event {}", seq)
+                        })?;
+                    }
+                    println!("Verified {} events", seq);
+                    println!("Last hash: {}", hash);
+                }
+            }
+        }
+        Commands::Prune => {
+            let config = read_config(&cli_config.config)?;
+            let db = SqliteDatabase::new(&config.db_directory);
+            let mut pruned = 0;
+            for account in db.list_accounts()? {
+                pruned += db.prune_cache(&account.alias, config.cache_count)?;
+            }
+            println!("Pruned {} states", pruned);
+        }
+        Commands::Vacuum => {
+            let config = read_config(&cli_config.config)?;
+            let db = SqliteDatabase::new(&config.db_directory);
+            for name in db.vacuum()? {
+                println!("Vacuumed {}", name);
+            }
+        }
+        Commands::Export { alias } => {
+            let config = read_config(&cli_config.config)?;
+            let db = SqliteDatabase::new(&config.db_directory);
+            find_account(&db, &alias)?;
+            println!("{}", serde_json::to_string(&db.export_all(&alias)?)?);
+        }
+        Commands::Import { alias, file } => {
+            let db = open_user_database(&cli_config.config)?;
+            find_account(&db, &alias)?;
+            let credentials: Vec<Credential> = serde_json::from_reader(File::open(&file)?)?;
+            if !StoreDatabase::is_empty(&db, &alias)? || !CacheDatabase::is_empty(&db, &alias)? {
+                return Err(Error::ExistingUser(alias).into());
+            }
+            db.import_all(&alias, &credentials)?;
+            let state_id = db.add_mutations(&alias, &[])?;
+            println!(
+                "Imported {} credentials for user {}",
+                credentials.len(),
+                &alias
+            );
+            println!("State: {}", state_id);
         }
-        Commands::Test => {
-            info!("Testing stuff");
+        Commands::CheckConfig => {
+            let config = Config::read_config(&cli_config.config)?;
+            println!("{}", config);
+            println!("Config at {} is valid", &cli_config.config);
         }
     }
 
     Ok(())
 }
+
+fn read_config(path: &str) -> anyhow::Result<Config> {
+    let config = Config::read_config(path)?;
+    info!("Parsed config:\n{}", &config);
+    Ok(config)
+}
+
+fn find_account(db: &SqliteDatabase, alias: &str) -> anyhow::Result<Account> {
+    Ok(db
+        .list_accounts()?
+        .into_iter()
+        .find(|account| account.alias == alias)
+        .ok_or_else(|| Error::MissingUser(alias.to_string()))?)
+}
+
+/// Print a line for each key of a user
+fn print_keys(db: &SqliteDatabase, alias: &str) -> anyhow::Result<()> {
+    for key in db.list_keys(alias)? {
+        let last_sync = format_time(key.last_sync, "never synced");
+        let scopes = key
+            .scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let expires = format_time(key.expires, "never expires");
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            &key.id, &key.name, last_sync, expires, scopes
+        );
+    }
+    Ok(())
+}
+
+/// RFC 3339 form of a time in seconds since the Unix epoch
+fn rfc3339(time: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time)).to_string()
+}
+
+/// RFC 3339 form of a time in seconds since the Unix epoch, or `missing` if there is none
+fn format_time(time: Option<u64>, missing: &str) -> String {
+    time.map_or_else(|| missing.into(), rfc3339)
+}
+
+/// User database of the configured data directory, with the config's users imported
+fn open_user_database(path: &str) -> anyhow::Result<SqliteDatabase> {
+    let config = read_config(path)?;
+    let db = SqliteDatabase::new(&config.db_directory);
+    import_config_users(&db, &config.users)?;
+    Ok(db)
+}
@@ -0,0 +1,48 @@
+use sha2::{Digest, Sha256};
+
+use crate::api::db_types::AuditEvent;
+
+/// Hash of the event before the first one
+pub const GENESIS_HASH: &str = "";
+
+/// Hash chaining `event` to the event with hash `previous`, ignoring the event's own hash
+pub fn event_hash(previous: &str, event: &AuditEvent) -> String {
+    let fields = (
+        previous,
+        event.seq,
+        event.time,
+        &event.action,
+        &event.alias,
+        &event.device,
+        &event.route,
+        event.mutations,
+        &event.state_id,
+        &event.ip,
+    );
+    let encoded = serde_json::to_vec(&fields).expect("Audit fields serialize to JSON");
+    Sha256::digest(encoded)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Check that `events` continue the chain after the event with sequence number `seq` and
+/// hash `previous`
+///
+/// Returns the sequence number and hash of the last event, or the sequence number of
+/// the first event that does not fit, such as one after a deleted or modified event.
+pub fn verify_chain(
+    mut seq: u64,
+    previous: &str,
+    events: &[AuditEvent],
+) -> Result<(u64, String), u64> {
+    let mut previous = previous.to_string();
+    for event in events {
+        if event.seq != seq + 1 || event.hash != event_hash(&previous, event) {
+            return Err(event.seq);
+        }
+        seq = event.seq;
+        previous = event.hash.clone();
+    }
+    Ok((seq, previous))
+}
@@ -0,0 +1,60 @@
+use rocket::mtls::Certificate;
+use sha2::{Digest, Sha256};
+
+use crate::config::parse_config::ClientCertificate;
+
+/// Identity of a verified client certificate
+pub struct CertificateIdentity {
+    /// Hex encoded SHA-256 of the certificate's public key
+    pub fingerprint: String,
+    pub subject: String,
+}
+
+impl CertificateIdentity {
+    pub fn new(certificate: &Certificate<'_>) -> Self {
+        Self {
+            fingerprint: fingerprint(certificate.subject_pki.raw),
+            subject: certificate.subject().to_string(),
+        }
+    }
+
+    /// Whether the certificate matches the fingerprint and subject of `client`, as far
+    /// as they are given
+    pub fn matches(&self, client: &ClientCertificate) -> bool {
+        (client.fingerprint.is_some() || client.subject.is_some())
+            && client
+                .fingerprint
+                .as_ref()
+                .is_none_or(|fingerprint| normalize_fingerprint(fingerprint) == self.fingerprint)
+            && client
+                .subject
+                .as_ref()
+                .is_none_or(|subject| subject == &self.subject)
+    }
+}
+
+/// Hex encoded SHA-256 of a DER encoded public key
+pub fn fingerprint(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Fingerprint in lowercase hex without the colons `openssl` separates bytes with
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_lowercase()
+}
+
+/// Value stored in place of a key hash for the device of a client certificate
+///
+/// It is never a valid key hash, so no key can authenticate as the device.
+pub fn certificate_key_hash(client: &ClientCertificate) -> String {
+    match (&client.fingerprint, &client.subject) {
+        (Some(fingerprint), _) => {
+            format!("certificate:sha256:{}", normalize_fingerprint(fingerprint))
+        }
+        (None, Some(subject)) => format!("certificate:subject:{}", subject),
+        (None, None) => unreachable!("Client certificates are validated with the config"),
+    }
+}
@@ -7,8 +7,32 @@ pub enum Error {
     MissingId(String),
     #[error("User with alias {0} already exists")]
     ExistingUser(String),
+    #[error("User with alias {0} does not exist")]
+    MissingUser(String),
+    #[error("Alias {0:?} cannot be used because {1}")]
+    InvalidAlias(String, &'static str),
+    #[error("Missing key with id: {0}")]
+    MissingKey(String),
+    #[error("Key is already registered")]
+    ExistingKey,
+    #[error("Unknown key scope: {0}")]
+    InvalidScope(String),
     #[error("User with alias {0} has not been initialized")]
     UninitializedUser(String),
+    #[error("State {0} is not the most recent state")]
+    StaleState(String),
+    #[error("Missing upload with id: {0}")]
+    MissingUpload(String),
+    #[error("User with alias {0} already has TOTP set up")]
+    ExistingTotp(String),
+    #[error("User with alias {0} has not set up TOTP")]
+    MissingTotp(String),
+    #[error("Deletion of user with alias {0} is already scheduled")]
+    ExistingDeletion(String),
+    #[error("No deletion of user with alias {0} is scheduled")]
+    MissingDeletion(String),
+    #[error("No key to encrypt TOTP secrets is configured")]
+    MissingTotpKey,
     #[error("Internal server error")]
     Server(#[source] anyhow::Error),
     #[error("Invalid server configuration")]
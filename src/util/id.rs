@@ -5,3 +5,20 @@ pub fn random_b64(bytes: usize) -> String {
     rand::thread_rng().fill_bytes(&mut id_array);
     base64::encode(&id_array)
 }
+
+/// Random id that can be used in paths and queries
+pub fn random_b64_url(bytes: usize) -> String {
+    let mut id_array: Vec<u8> = vec![0; bytes];
+    rand::thread_rng().fill_bytes(&mut id_array);
+    base64::encode_config(&id_array, base64::URL_SAFE_NO_PAD)
+}
+
+/// Random id of lowercase hex digits, a valid file name on any file system
+pub fn random_hex(bytes: usize) -> String {
+    let mut id_array: Vec<u8> = vec![0; bytes];
+    rand::thread_rng().fill_bytes(&mut id_array);
+    id_array
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+use sha2::{Digest, Sha256};
+
+use super::id::random_b64_url;
+
+/// Memory cost of new key hashes in KiB
+///
+/// Generated keys carry 256 bits of entropy so, unlike passwords, they do not need an
+/// expensive hash to resist guessing. A light cost keeps authentication cheap.
+const KEY_HASH_MEMORY: u32 = 1024;
+
+fn hasher() -> Argon2<'static> {
+    let params = Params::new(KEY_HASH_MEMORY, 1, 1, None).expect("Valid key hash parameters");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Separates the id of a key from its secret, and the same id from the hash of the key
+const ID_SEPARATOR: char = '.';
+
+/// Generate a new random API key of the form `<id>.<secret>`
+///
+/// The id lets the key be looked up without trying every stored hash.
+pub fn generate_key() -> String {
+    format!(
+        "{}{}{}",
+        random_b64_url(12),
+        ID_SEPARATOR,
+        random_b64_url(32)
+    )
+}
+
+/// Id of `key`, which keys generated before ids were added to them do not have
+pub fn key_id(key: &str) -> Option<&str> {
+    key.split_once(ID_SEPARATOR)
+        .map(|(id, _)| id)
+        .filter(|id| !id.is_empty())
+}
+
+/// Split a key hash into the id it is prefixed with, if any, and the hash itself
+fn split_hash(hash: &str) -> (Option<&str>, &str) {
+    match hash.split_once(ID_SEPARATOR) {
+        Some((id, rest)) if !id.is_empty() && PasswordHash::new(rest).is_ok() => (Some(id), rest),
+        _ => (None, hash),
+    }
+}
+
+/// Id of the key that `hash` was made from, if the key has one
+pub fn key_hash_id(hash: &str) -> Option<&str> {
+    split_hash(hash).0
+}
+
+/// Hash an API key with a random salt for storage in the config, prefixed with the id
+/// of the key
+pub fn hash_key(key: &str) -> Result<String> {
+    hash_key_with_salt(key, &SaltString::generate(&mut OsRng))
+}
+
+/// Hash a key that the config gives in plain text, with a salt derived from the key
+///
+/// The key gets the same hash on every startup, so it is only imported once.
+pub fn hash_plaintext_key(key: &str) -> Result<String> {
+    let digest = Sha256::digest(key.as_bytes());
+    let salt = SaltString::encode_b64(&digest[..16])
+        .map_err(|e| anyhow!("Failed to derive salt: {}", e))?;
+    hash_key_with_salt(key, &salt)
+}
+
+fn hash_key_with_salt(key: &str, salt: &SaltString) -> Result<String> {
+    let hash = hasher()
+        .hash_password(key.as_bytes(), salt)
+        .map_err(|e| anyhow!("Failed to hash key: {}", e))?;
+    Ok(match key_id(key) {
+        Some(id) => format!("{}{}{}", id, ID_SEPARATOR, hash),
+        None => hash.to_string(),
+    })
+}
+
+/// Check that `hash` is a key hash that can be verified
+pub fn is_key_hash(hash: &str) -> bool {
+    PasswordHash::new(split_hash(hash).1).is_ok()
+}
+
+/// Verify `key` against a stored hash, comparing in constant time
+///
+/// The cost parameters are read from the hash itself. A key with another id than the
+/// hash is rejected without hashing it.
+pub fn verify_key(key: &str, hash: &str) -> bool {
+    let (id, hash) = split_hash(hash);
+    if id.is_some() && id != key_id(key) {
+        return false;
+    }
+    match PasswordHash::new(hash) {
+        Ok(hash) => hasher().verify_password(key.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
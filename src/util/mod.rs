@@ -1,3 +1,9 @@
+pub mod audit;
+pub mod certificate;
 pub mod error;
 pub mod id;
+pub mod key;
+pub mod proof;
+pub mod session;
+pub mod totp;
 pub mod types;
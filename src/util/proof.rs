@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{id::random_b64_url, session::now_secs};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a challenge can be answered for
+const CHALLENGE_LIFETIME: u64 = 60;
+
+/// Single-use challenges handed out to devices, with the key and expiry of each
+///
+/// Clones share the pending challenges.
+#[derive(Default, Clone)]
+pub struct Challenges {
+    pending: Arc<Mutex<HashMap<String, (String, u64)>>>,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a challenge for the device of key `key_id`
+    pub fn issue(&self, key_id: &str) -> String {
+        let now = now_secs();
+        let challenge = random_b64_url(32);
+        let mut pending = self.pending.lock().expect("Challenge lock");
+        pending.retain(|_, (_, expires)| *expires > now);
+        pending.insert(
+            challenge.to_owned(),
+            (key_id.to_string(), now + CHALLENGE_LIFETIME),
+        );
+        challenge
+    }
+
+    /// Use up a challenge, true if it was issued to `key_id` and has not expired
+    pub fn take(&self, challenge: &str, key_id: &str) -> bool {
+        let mut pending = self.pending.lock().expect("Challenge lock");
+        match pending.remove(challenge) {
+            Some((issued_to, expires)) => issued_to == key_id && expires > now_secs(),
+            None => false,
+        }
+    }
+}
+
+fn mac(hash: &str, challenge: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(hash.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(challenge.as_bytes());
+    mac
+}
+
+/// Proof of the master password answering `challenge`
+///
+/// HMAC-SHA256 of the challenge keyed with the hash the client derives from the master
+/// password and stores with `/user/init`, encoded as URL-safe base64 without padding.
+pub fn proof(hash: &str, challenge: &str) -> String {
+    base64::encode_config(
+        mac(hash, challenge).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Check a proof in constant time
+pub fn verify_proof(hash: &str, challenge: &str, proof: &str) -> bool {
+    match base64::decode_config(proof, base64::URL_SAFE_NO_PAD) {
+        Ok(proof) => mac(hash, challenge).verify_slice(&proof).is_ok(),
+        Err(_) => false,
+    }
+}
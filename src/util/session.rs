@@ -0,0 +1,86 @@
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Token kinds, telling sessions started with a key alone from those with a proof
+const KEY_ONLY: &str = "k";
+const PROVEN: &str = "p";
+
+/// Session of a device
+pub struct Session {
+    pub key_id: String,
+    /// The device proved knowledge of the master password when the session started
+    pub proven: bool,
+}
+
+/// Issues and verifies short-lived session tokens bound to a key id
+///
+/// Tokens are signed with a secret generated when the server starts, so all sessions
+/// end when it restarts, though not when it reloads its config.
+#[derive(Clone)]
+pub struct SessionSigner {
+    secret: [u8; 32],
+}
+
+impl SessionSigner {
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Issue a token for the key `key_id` valid for `lifetime` seconds
+    ///
+    /// Returns the token and its expiry in seconds since the Unix epoch
+    pub fn issue(&self, key_id: &str, lifetime: u64, proven: bool) -> (String, u64) {
+        let expires = now_secs() + lifetime;
+        let kind = if proven { PROVEN } else { KEY_ONLY };
+        let payload = format!("{}.{}.{}", key_id, expires, kind);
+        let signature = self.mac(&payload).finalize().into_bytes();
+        let token = format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        );
+        (token, expires)
+    }
+
+    /// Session of a valid, unexpired token
+    pub fn verify(&self, token: &str) -> Option<Session> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+        let mut parts = payload.split('.');
+        let (key_id, expires, kind) = (parts.next()?, parts.next()?, parts.next()?);
+        match expires.parse::<u64>() {
+            Ok(expires) if expires > now_secs() => Some(Session {
+                key_id: key_id.to_string(),
+                proven: kind == PROVEN,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SessionSigner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,128 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::anyhow;
+use rand::RngCore;
+use rocket::http::RawStr;
+use totp_rs::{Algorithm, TOTP};
+
+use super::{error::Error, id::random_b64_url, session::now_secs, types::GenericResult};
+
+/// Length in bytes of generated TOTP secrets
+const SECRET_LENGTH: usize = 20;
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+/// Steps before and after the current one whose codes are also accepted
+const SKEW: u64 = 1;
+const NONCE_LENGTH: usize = 12;
+/// Number of recovery codes handed out when TOTP is set up
+pub const RECOVERY_CODES: usize = 8;
+
+/// Encrypts TOTP secrets at rest with the key from the config
+pub struct TotpCipher {
+    cipher: Option<Aes256Gcm>,
+}
+
+impl TotpCipher {
+    /// Cipher for the base64 encoded 32 byte `key`, unable to encrypt without one
+    pub fn new(key: Option<&str>) -> GenericResult<Self> {
+        let cipher = match key {
+            Some(key) => {
+                let key = base64::decode(key).map_err(|e| Error::Config(e.into()))?;
+                Some(
+                    Aes256Gcm::new_from_slice(&key)
+                        .map_err(|_| Error::Config(anyhow!("TOTP key must be 32 bytes long")))?,
+                )
+            }
+            None => None,
+        };
+        Ok(Self { cipher })
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn cipher(&self) -> GenericResult<&Aes256Gcm> {
+        self.cipher.as_ref().ok_or(Error::MissingTotpKey)
+    }
+
+    /// Encrypt the secret of user `alias`, bound to the alias
+    pub fn encrypt(&self, alias: &str, secret: &[u8]) -> GenericResult<String> {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: secret,
+            aad: alias.as_bytes(),
+        };
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.cipher()?
+                .encrypt(Nonce::from_slice(&nonce), payload)
+                .map_err(|_| Error::Server(anyhow!("Failed to encrypt TOTP secret")))?,
+        );
+        Ok(base64::encode(encrypted))
+    }
+
+    pub fn decrypt(&self, alias: &str, encrypted: &str) -> GenericResult<Vec<u8>> {
+        let encrypted = base64::decode(encrypted).map_err(|e| Error::Server(e.into()))?;
+        if encrypted.len() < NONCE_LENGTH {
+            return Err(Error::Server(anyhow!("Truncated TOTP secret")));
+        }
+        let (nonce, msg) = encrypted.split_at(NONCE_LENGTH);
+        let payload = Payload {
+            msg,
+            aad: alias.as_bytes(),
+        };
+        self.cipher()?
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Server(anyhow!("Failed to decrypt TOTP secret of {}", alias)))
+    }
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES).map(|_| random_b64_url(9)).collect()
+}
+
+fn totp(secret: &[u8]) -> GenericResult<TOTP> {
+    TOTP::new(Algorithm::SHA1, 6, SKEW as u8, STEP, secret.to_vec())
+        .map_err(|e| Error::Server(anyhow!("Invalid TOTP secret: {:?}", e)))
+}
+
+/// Secret as entered into authenticator apps by hand
+pub fn secret_base32(secret: &[u8]) -> GenericResult<String> {
+    Ok(totp(secret)?.get_secret_base32())
+}
+
+/// `otpauth://` URL of the secret, usually shown as a QR code
+pub fn otpauth_url(alias: &str, secret: &[u8]) -> GenericResult<String> {
+    Ok(format!(
+        "otpauth://totp/vult:{}?secret={}&issuer=vult&algorithm=SHA1&digits=6&period={}",
+        RawStr::new(alias).percent_encode(),
+        secret_base32(secret)?,
+        STEP
+    ))
+}
+
+/// Time step of `code` if it is currently valid and newer than `last_step`
+///
+/// Steps only move forward so that a code cannot be used twice.
+pub fn verify_code(
+    secret: &[u8],
+    code: &str,
+    last_step: Option<u64>,
+) -> GenericResult<Option<u64>> {
+    let totp = totp(secret)?;
+    let current = now_secs() / STEP;
+    let steps = current.saturating_sub(SKEW)..=current + SKEW;
+    Ok(steps
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp.generate(step * STEP) == code))
+}